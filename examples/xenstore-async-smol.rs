@@ -1,3 +1,6 @@
+// xeniface is Windows-only, there is nothing to run elsewhere.
+#![cfg_attr(not(windows), allow(dead_code, unused_imports))]
+
 use clap::{Parser, Subcommand};
 use futures::StreamExt;
 use smol::Executor;
@@ -42,6 +45,7 @@ enum Command {
     },
 }
 
+#[cfg(windows)]
 fn main() {
     let cli = Cli::parse();
 
@@ -62,30 +66,35 @@ fn main() {
     drop(executor);
 }
 
-async fn cmd_list(xs: &mut impl AsyncXs, path: &String) {
-    let values = xs.directory(&path).await.expect("path should be readable");
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("xeniface is only available on Windows");
+}
+
+async fn cmd_list(xs: &mut impl AsyncXs, path: &str) {
+    let values = xs.directory(path).await.expect("path should be readable");
     for value in values {
         println!("{}", value);
     }
 }
 
-async fn cmd_read(xs: &mut impl AsyncXs, path: &String) {
-    let value = xs.read(&path).await.expect("path should be readable");
+async fn cmd_read(xs: &mut impl AsyncXs, path: &str) {
+    let value = xs.read(path).await.expect("path should be readable");
     println!("{}", value);
 }
 
-async fn cmd_rm(xs: &mut impl AsyncXs, path: &String) {
-    xs.rm(&path).await.expect("cannot rm xenstore path");
+async fn cmd_rm(xs: &mut impl AsyncXs, path: &str) {
+    xs.rm(path).await.expect("cannot rm xenstore path");
 }
 
-async fn cmd_write(xs: &mut impl AsyncXs, path: &String, data: &String) {
-    xs.write(&path, &data)
+async fn cmd_write(xs: &mut impl AsyncXs, path: &str, data: &str) {
+    xs.write(path, data)
         .await
         .expect("cannot write to xenstore path");
 }
 
-async fn cmd_watch<XS: AsyncXs + AsyncWatch>(xs: &mut XS, path: &String) {
-    let mut stream = xs.watch(&path).await.expect("path should be watchable");
+async fn cmd_watch<XS: AsyncXs + AsyncWatch>(xs: &mut XS, path: &str) {
+    let mut stream = xs.watch(path).await.expect("path should be watchable");
 
     while let Some(entry) = stream.next().await {
         println!("{entry}: {:?}", xs.read(&entry).await);
@@ -1,16 +1,28 @@
+// xeniface is Windows-only, there is nothing to run elsewhere.
+#![cfg_attr(not(windows), allow(dead_code, unused_imports))]
+
+use std::ffi::OsString;
+
 use clap::{Parser, Subcommand};
 use xenstore_rs::Xs;
-use xenstore_win::XsWindows;
+#[cfg(windows)]
+use xenstore_win::XenifaceDevice;
+use xenstore_win::{Transport, XsError, XsWindows, walk::Walk};
 
 /// Demo/test tool for xenstore Rust bindings
 #[derive(Parser)]
 struct Cli {
+    /// Interface path of the xeniface device to use
+    #[arg(long, global = true)]
+    device: Option<OsString>,
     #[command(subcommand)]
     command: Command,
 }
 
 #[derive(Subcommand)]
 enum Command {
+    /// List xeniface devices
+    Devices,
     /// List Xenstore keys in path
     List {
         #[arg()]
@@ -33,38 +45,113 @@ enum Command {
         #[arg()]
         data: String,
     },
+    /// Watch on path on Xenstore.
+    Watch {
+        #[arg()]
+        path: String,
+    },
+    /// Dump the subtree of a Xenstore path
+    Walk {
+        #[arg()]
+        path: String,
+        /// Maximum depth below path
+        #[arg(long)]
+        max_depth: Option<usize>,
+        /// Only print nodes matching this glob
+        #[arg(long)]
+        glob: Option<String>,
+    },
 }
 
+#[cfg(windows)]
 fn main() {
     let cli = Cli::parse();
 
-    let xs = XsWindows::new().expect("xenstore should open");
+    if let Command::Devices = cli.command {
+        return cmd_devices();
+    }
+
+    let xs = match &cli.device {
+        Some(path) => XsWindows::open_path(path),
+        None => XsWindows::new().map_err(XsError::from),
+    }
+    .expect("xenstore should open");
 
     match cli.command {
+        Command::Devices => unreachable!(),
         Command::List { path } => cmd_list(&xs, &path),
         Command::Read { path } => cmd_read(&xs, &path),
         Command::Rm { path } => cmd_rm(&xs, &path),
         Command::Write { path, data } => cmd_write(&xs, &path, &data),
+        Command::Watch { path } => cmd_watch(&xs, &path),
+        Command::Walk {
+            path,
+            max_depth,
+            glob,
+        } => cmd_walk(&xs, &path, max_depth, glob.as_deref()),
     }
 }
 
-fn cmd_list(xs: &impl Xs, path: &String) {
-    let values = xs.directory(&path).expect("path should be readable");
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("xeniface is only available on Windows");
+}
+
+#[cfg(windows)]
+fn cmd_devices() {
+    let devices = XenifaceDevice::enumerate().expect("devices should be listable");
+    for device in devices {
+        println!("{}", device.path().display());
+        if let Some(instance_id) = device.instance_id() {
+            println!("  instance: {}", instance_id.display());
+        }
+        if let Some(version) = device.driver_version() {
+            println!("  driver: {version}");
+        }
+    }
+}
+
+fn cmd_list(xs: &impl Xs, path: &str) {
+    let values = xs.directory(path).expect("path should be readable");
     for value in values {
         println!("{}", value);
     }
 }
 
-fn cmd_read(xs: &impl Xs, path: &String) {
-    let value = xs.read(&path).expect("path should be readable");
+fn cmd_read(xs: &impl Xs, path: &str) {
+    let value = xs.read(path).expect("path should be readable");
     println!("{}", value);
 }
 
-fn cmd_rm(xs: &impl Xs, path: &String) {
-    xs.rm(&path).expect("cannot rm xenstore path");
+fn cmd_rm(xs: &impl Xs, path: &str) {
+    xs.rm(path).expect("cannot rm xenstore path");
 }
 
-fn cmd_write(xs: &impl Xs, path: &String, data: &String) {
-    xs.write(&path, &data)
-        .expect("cannot write to xenstore path");
+fn cmd_write(xs: &impl Xs, path: &str, data: &str) {
+    xs.write(path, data).expect("cannot write to xenstore path");
+}
+
+fn cmd_watch<T: Transport>(xs: &XsWindows<T>, path: &str) {
+    let watch = xs.watch(path).expect("path should be watchable");
+
+    for entry in watch {
+        println!("{entry}: {:?}", xs.read(&entry));
+    }
+}
+
+fn cmd_walk(xs: &impl Xs, path: &str, max_depth: Option<usize>, glob: Option<&str>) {
+    let mut walk = Walk::new(xs, path);
+    if let Some(max_depth) = max_depth {
+        walk = walk.with_max_depth(max_depth);
+    }
+    if let Some(glob) = glob {
+        walk = walk.with_glob(glob);
+    }
+
+    for entry in walk {
+        match entry {
+            Ok((path, value)) => println!("{path} = {value:?}"),
+            Err(e) => eprintln!("{e}"),
+        }
+    }
 }
@@ -1,13 +1,25 @@
 //! Xeniface device discovery utilities.
-//! 
+//!
+use std::{
+    ffi::{OsStr, OsString},
+    io,
+    mem::offset_of,
+    os::windows::ffi::OsStringExt,
+    slice,
+};
+
 use log::{error, warn};
 use windows::{
-    core::{Result, GUID},
-    Win32::Devices::DeviceAndDriverInstallation::{
-        SetupDiDestroyDeviceInfoList, SetupDiEnumDeviceInterfaces, SetupDiGetClassDevsW,
-        SetupDiGetDeviceInterfaceDetailW, DIGCF_DEVICEINTERFACE, DIGCF_PRESENT, HDEVINFO,
-        SP_DEVICE_INTERFACE_DATA, SP_DEVICE_INTERFACE_DETAIL_DATA_W,
+    Win32::Devices::{
+        DeviceAndDriverInstallation::{
+            DIGCF_DEVICEINTERFACE, DIGCF_PRESENT, HDEVINFO, SP_DEVICE_INTERFACE_DATA,
+            SP_DEVICE_INTERFACE_DETAIL_DATA_W, SP_DEVINFO_DATA, SetupDiDestroyDeviceInfoList,
+            SetupDiEnumDeviceInterfaces, SetupDiGetClassDevsW, SetupDiGetDeviceInstanceIdW,
+            SetupDiGetDeviceInterfaceDetailW, SetupDiGetDevicePropertyW,
+        },
+        Properties::{DEVPKEY_Device_DriverVersion, DEVPROPTYPE},
     },
+    core::{GUID, Result},
 };
 
 pub const GUID_INTERFACE_XENIFACE: GUID = GUID::from_values(
@@ -17,31 +29,44 @@ pub const GUID_INTERFACE_XENIFACE: GUID = GUID::from_values(
     [0x8b, 0xf7, 0x97, 0x93, 0xf3, 0x15, 0x45, 0x65],
 );
 
-const MAX_INTERFACE_DETAIL_PATH_LEN: usize = 4094;
+/// Convert a (possibly) NUL-terminated WTF16 string.
+fn wide_string(units: &[u16]) -> OsString {
+    let len = units.iter().position(|&c| c == 0).unwrap_or(units.len());
 
-// Extended SP_DEVICE_INTERFACE_DATA_DETAIL_W (fixed flex array at 4094)
-// Maximum path is 4094 characters.
-// Hopefully, we would have "never" a that large path.
-#[repr(C)]
-struct ExtendedDataDetail {
-    cb_size: u32,
-    path: [u16; MAX_INTERFACE_DETAIL_PATH_LEN],
+    OsString::from_wide(&units[..len])
 }
 
-impl Default for ExtendedDataDetail {
-    fn default() -> Self {
-        Self {
-            cb_size: Default::default(),
-            path: [0; MAX_INTERFACE_DETAIL_PATH_LEN],
-        }
-    }
+/// xeniface device interface.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XenifaceDevice {
+    path: OsString,
+    instance_id: Option<OsString>,
+    driver_version: Option<String>,
 }
 
-impl ExtendedDataDetail {
-    // ExtendedDataDetail is ABI-compatible with SP_DEVICE_INTERFACE_DETAIL_DATA_W.
-    // We just need to ensure that required length < size_of::<ExtendedDataDetail>().
-    fn as_data_detail_ptr(&mut self) -> *mut SP_DEVICE_INTERFACE_DETAIL_DATA_W {
-        &mut *self as *mut _ as *mut SP_DEVICE_INTERFACE_DETAIL_DATA_W
+impl XenifaceDevice {
+    /// List the present xeniface interfaces (GUID = b2cfb085-aa5e-47e1-8bf7-9793f3154565).
+    ///
+    /// Interfaces that can't be queried are skipped (and logged).
+    pub fn enumerate() -> io::Result<Vec<Self>> {
+        Ok(DeviceInfoList::new(GUID_INTERFACE_XENIFACE)?
+            .iter()
+            .collect())
+    }
+
+    /// Interface path, to be opened with [`XsWindows::open_path`](crate::XsWindows::open_path).
+    pub fn path(&self) -> &OsStr {
+        &self.path
+    }
+
+    /// Device instance ID (e.g `XENBUS\VEN_XN0001&DEV_IFACE&REV_09000000\_`), if available.
+    pub fn instance_id(&self) -> Option<&OsStr> {
+        self.instance_id.as_deref()
+    }
+
+    /// Version of the installed driver (e.g `9.1.0.146`), if available.
+    pub fn driver_version(&self) -> Option<&str> {
+        self.driver_version.as_deref()
     }
 }
 
@@ -66,11 +91,122 @@ impl DeviceInfoList {
         })
     }
 
-    pub fn iter(&self) -> DeviceInfoIterator {
+    pub fn iter(&self) -> DeviceInfoIterator<'_> {
         DeviceInfoIterator {
             list: self,
             index: 0,
-            buffer: Box::default(),
+        }
+    }
+
+    /// Get the path of an interface, along with its device.
+    ///
+    /// # Safety
+    /// `data` must be an interface of this list.
+    unsafe fn interface_detail(
+        &self,
+        data: &SP_DEVICE_INTERFACE_DATA,
+    ) -> Result<(OsString, SP_DEVINFO_DATA)> {
+        unsafe {
+            let mut length = 0;
+
+            // Get the length of the interface detail.
+            // It will fail but we only want to know length.
+            SetupDiGetDeviceInterfaceDetailW(self.info, data, None, 0, Some(&mut length), None)
+                .ok();
+
+            // SP_DEVICE_INTERFACE_DETAIL_DATA_W is a DWORD followed by the path (flexible array),
+            // allocate it as DWORDs to keep it aligned.
+            let units = (length as usize)
+                .max(size_of::<SP_DEVICE_INTERFACE_DETAIL_DATA_W>())
+                .div_ceil(size_of::<u32>());
+            let mut buffer = vec![0u32; units];
+            let size = buffer.len() * size_of::<u32>();
+
+            let detail = buffer
+                .as_mut_ptr()
+                .cast::<SP_DEVICE_INTERFACE_DETAIL_DATA_W>();
+            (*detail).cbSize = size_of::<SP_DEVICE_INTERFACE_DETAIL_DATA_W>() as u32;
+
+            let mut device = SP_DEVINFO_DATA {
+                cbSize: size_of::<SP_DEVINFO_DATA>() as u32,
+                ..Default::default()
+            };
+
+            SetupDiGetDeviceInterfaceDetailW(
+                self.info,
+                data,
+                Some(detail),
+                size as u32,
+                None,
+                Some(&mut device),
+            )?;
+
+            let offset = offset_of!(SP_DEVICE_INTERFACE_DETAIL_DATA_W, DevicePath);
+            let path = slice::from_raw_parts(
+                detail.byte_add(offset).cast::<u16>(),
+                (size - offset) / size_of::<u16>(),
+            );
+
+            Ok((wide_string(path), device))
+        }
+    }
+
+    /// Get the instance ID of `device`.
+    ///
+    /// # Safety
+    /// `device` must be a device of this list.
+    unsafe fn instance_id(&self, device: &SP_DEVINFO_DATA) -> Result<OsString> {
+        unsafe {
+            let mut length = 0;
+
+            // Get the length of the instance ID.
+            SetupDiGetDeviceInstanceIdW(self.info, device, None, Some(&mut length)).ok();
+
+            let mut buffer = vec![0u16; length as usize];
+            SetupDiGetDeviceInstanceIdW(self.info, device, Some(&mut buffer), None)?;
+
+            Ok(wide_string(&buffer))
+        }
+    }
+
+    /// Get the driver version of `device`.
+    ///
+    /// # Safety
+    /// `device` must be a device of this list.
+    unsafe fn driver_version(&self, device: &SP_DEVINFO_DATA) -> Result<String> {
+        unsafe {
+            let mut property_type = DEVPROPTYPE::default();
+            let mut size = 0;
+
+            // Get the size of the property (a DEVPROP_TYPE_STRING).
+            SetupDiGetDevicePropertyW(
+                self.info,
+                device,
+                &DEVPKEY_Device_DriverVersion,
+                &mut property_type,
+                None,
+                Some(&mut size),
+                0,
+            )
+            .ok();
+
+            let mut buffer = vec![0u8; size as usize];
+            SetupDiGetDevicePropertyW(
+                self.info,
+                device,
+                &DEVPKEY_Device_DriverVersion,
+                &mut property_type,
+                Some(&mut buffer),
+                None,
+                0,
+            )?;
+
+            let units: Vec<u16> = buffer
+                .chunks_exact(size_of::<u16>())
+                .map(|unit| u16::from_ne_bytes([unit[0], unit[1]]))
+                .collect();
+
+            Ok(wide_string(&units).to_string_lossy().into_owned())
         }
     }
 }
@@ -86,12 +222,11 @@ impl Drop for DeviceInfoList {
 pub struct DeviceInfoIterator<'a> {
     list: &'a DeviceInfoList,
     index: u32,
-    buffer: Box<ExtendedDataDetail>,
 }
 
-/// Iterator of device info paths in WTF16 encoding.
+/// Iterator of the devices of the list.
 impl Iterator for DeviceInfoIterator<'_> {
-    type Item = Box<[u16]>;
+    type Item = XenifaceDevice;
 
     fn next(&mut self) -> Option<Self::Item> {
         unsafe {
@@ -111,46 +246,31 @@ impl Iterator for DeviceInfoIterator<'_> {
             .is_ok()
             {
                 self.index += 1;
-                let mut length = 0;
 
-                // Get the length of the interface detail.
-                SetupDiGetDeviceInterfaceDetailW(
-                    self.list.info,
-                    &mut data,
-                    None,
-                    0,
-                    Some(&mut length),
-                    None,
-                ) // it will fail but we only want to know length
-                .ok();
-
-                if (length as usize) > size_of::<ExtendedDataDetail>() {
-                    warn!(
-                        "interface detail too large ! ({} > {})",
-                        length,
-                        size_of::<ExtendedDataDetail>()
-                    );
-                    continue;
-                }
-
-                self.buffer.cb_size = size_of::<SP_DEVICE_INTERFACE_DETAIL_DATA_W>() as u32;
-
-                if let Err(e) = SetupDiGetDeviceInterfaceDetailW(
-                    self.list.info,
-                    &mut data,
-                    Some(self.buffer.as_data_detail_ptr()),
-                    length,
-                    None,
-                    None,
-                ) {
-                    error!(
-                        "SetupDiGetDeviceInterfaceDetailW(index = {}) failure: {e:?}",
-                        self.index - 1
-                    );
-                    continue;
+                let (path, device) = match self.list.interface_detail(&data) {
+                    Ok(detail) => detail,
+                    Err(e) => {
+                        error!(
+                            "SetupDiGetDeviceInterfaceDetailW(index = {}) failure: {e:?}",
+                            self.index - 1
+                        );
+                        continue;
+                    }
                 };
 
-                return Some(self.buffer.path.into());
+                return Some(XenifaceDevice {
+                    instance_id: self
+                        .list
+                        .instance_id(&device)
+                        .inspect_err(|e| warn!("Unable to get instance ID of {path:?} ({e})"))
+                        .ok(),
+                    driver_version: self
+                        .list
+                        .driver_version(&device)
+                        .inspect_err(|e| warn!("Unable to get driver version of {path:?} ({e})"))
+                        .ok(),
+                    path,
+                });
             }
 
             None
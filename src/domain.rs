@@ -0,0 +1,194 @@
+//! Domain-relative paths.
+//!
+//! Guest tools mostly deal with paths under their home path (`/local/domain/<domid>`).
+//! [`DomainXs`] resolves relative paths against it, and checks paths before they reach
+//! xenstore.
+//!
+use std::io;
+
+use xenstore_rs::{AsyncXs, Xs};
+
+use crate::{Permission, Transport, Watch, XsError, XsWindows};
+
+/// Whether `segment` is a valid xenstore node name.
+///
+/// Made of ASCII alphanumerics and `-_`, and can't be empty.
+pub(crate) fn valid_segment(segment: &str) -> bool {
+    !segment.is_empty()
+        && segment
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"-_".contains(&c))
+}
+
+/// Whether `path` is a special watch path, like `@releaseDomain`.
+pub(crate) fn is_special(path: &str) -> bool {
+    path.starts_with('@')
+}
+
+/// Whether `path` is a valid xenstore path, absolute, relative or special.
+///
+/// See [`valid_segment`] for its segments, special paths only start with `@`.
+pub(crate) fn valid_path(path: &str) -> bool {
+    let relative = path
+        .strip_prefix('/')
+        .or_else(|| path.strip_prefix('@'))
+        .unwrap_or(path);
+
+    path == "/" || relative.split('/').all(valid_segment)
+}
+
+/// Home path of `domid`.
+pub fn domain_path(domid: u16) -> String {
+    format!("/local/domain/{domid}")
+}
+
+fn parse_domid(value: &str) -> io::Result<u16> {
+    value.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid domid {value:?}"),
+        )
+    })
+}
+
+/// Xenstore with paths relative to the home path of the domain.
+///
+/// Absolute and special (e.g `@releaseDomain`) paths are kept as is, relative ones are
+/// resolved against [`DomainXs::home`].
+/// Both are checked (no empty segment, nor `.`/`..`, nor invalid character) beforehand,
+/// failing with [`XsError::InvalidArgument`].
+pub struct DomainXs<X> {
+    inner: X,
+    domid: u16,
+    home: Box<str>,
+}
+
+impl<X> DomainXs<X> {
+    /// Use `inner` as `domid`.
+    pub fn with_domid(inner: X, domid: u16) -> Self {
+        Self {
+            inner,
+            domid,
+            home: domain_path(domid).into(),
+        }
+    }
+
+    /// Domain ID.
+    pub fn domid(&self) -> u16 {
+        self.domid
+    }
+
+    /// Home path of the domain (`/local/domain/<domid>`).
+    pub fn home(&self) -> &str {
+        &self.home
+    }
+
+    /// Home path of another domain.
+    pub fn domain_path(&self, domid: u16) -> String {
+        domain_path(domid)
+    }
+
+    /// Check `path`, and make it absolute.
+    pub fn resolve(&self, path: &str) -> io::Result<String> {
+        if path.is_empty() {
+            return Ok(self.home.to_string());
+        }
+
+        if !valid_path(path) {
+            return Err(XsError::InvalidArgument.into());
+        }
+
+        Ok(if path.starts_with('/') || is_special(path) {
+            path.to_string()
+        } else {
+            format!("{}/{path}", self.home)
+        })
+    }
+
+    /// Underlying xenstore.
+    pub fn inner(&self) -> &X {
+        &self.inner
+    }
+
+    /// Get back the underlying xenstore.
+    pub fn into_inner(self) -> X {
+        self.inner
+    }
+}
+
+impl<X: Xs> DomainXs<X> {
+    /// Use `inner` as the domain it runs in, read from the `domid` node.
+    pub fn new(inner: X) -> io::Result<Self> {
+        let domid = parse_domid(&inner.read("domid")?)?;
+
+        Ok(Self::with_domid(inner, domid))
+    }
+
+    /// Path of the VM of the domain (`/vm/<uuid>`), read from the `vm` node.
+    pub fn vm_path(&self) -> io::Result<Box<str>> {
+        self.read("vm")
+    }
+}
+
+impl<X: AsyncXs + Sync> DomainXs<X> {
+    /// Async counterpart of [`DomainXs::new`].
+    pub async fn new_async(inner: X) -> io::Result<Self> {
+        let domid = parse_domid(&inner.read("domid").await?)?;
+
+        Ok(Self::with_domid(inner, domid))
+    }
+
+    /// Async counterpart of [`DomainXs::vm_path`].
+    pub async fn vm_path_async(&self) -> io::Result<Box<str>> {
+        AsyncXs::read(self, "vm").await
+    }
+}
+
+impl<T: Transport> DomainXs<XsWindows<T>> {
+    /// Watch `path` and its subtree (see [`XsWindows::watch`]).
+    pub fn watch(&self, path: &str) -> io::Result<Watch<T>> {
+        self.inner.watch(&self.resolve(path)?)
+    }
+
+    /// Set the permissions of a node (see [`XsWindows::set_permissions`]).
+    pub fn set_permissions(&self, path: &str, permissions: &[Permission]) -> io::Result<()> {
+        self.inner
+            .set_permissions(&self.resolve(path)?, permissions)
+    }
+}
+
+impl<X: Xs> Xs for DomainXs<X> {
+    fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        self.inner.directory(&self.resolve(path)?)
+    }
+
+    fn read(&self, path: &str) -> io::Result<Box<str>> {
+        self.inner.read(&self.resolve(path)?)
+    }
+
+    fn write(&self, path: &str, data: &str) -> io::Result<()> {
+        self.inner.write(&self.resolve(path)?, data)
+    }
+
+    fn rm(&self, path: &str) -> io::Result<()> {
+        self.inner.rm(&self.resolve(path)?)
+    }
+}
+
+impl<X: AsyncXs + Sync> AsyncXs for DomainXs<X> {
+    async fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        self.inner.directory(&self.resolve(path)?).await
+    }
+
+    async fn read(&self, path: &str) -> io::Result<Box<str>> {
+        self.inner.read(&self.resolve(path)?).await
+    }
+
+    async fn write(&self, path: &str, data: &str) -> io::Result<()> {
+        self.inner.write(&self.resolve(path)?, data).await
+    }
+
+    async fn rm(&self, path: &str) -> io::Result<()> {
+        self.inner.rm(&self.resolve(path)?).await
+    }
+}
@@ -0,0 +1,1268 @@
+//! In-memory xeniface emulator.
+//!
+//! [`Emulator`] is a [`Transport`] that implements the xeniface store ioctls on top of
+//! an in-memory tree, so that [`XsWindows`](crate::XsWindows) can be used without a Xen guest
+//! (e.g in tests). It is only available with the `emulator` feature.
+//!
+//! It tries to reproduce the driver behavior as seen by userland :
+//! - buffers are NUL-terminated, and directory listings end with an extra NUL
+//! - a zero-length output buffer reports the required length through `ERROR_MORE_DATA`,
+//!   a too small one fails with `ERROR_INVALID_PARAMETER`
+//! - writing a node implicitly creates its parents, new nodes inherit the permissions
+//!   of their parent
+//! - watches fire on registration, and then on any change of the watched node or its
+//!   subtree, and when an ancestor is removed
+//!
+//! Event channels are emulated as loopback ones : a port can only be bound to an unbound
+//! port of the same emulator, which is enough to have both ends of a protocol in-process.
+//! Likewise, pages can only be granted to the emulator domain itself, and mapped back.
+//! Resumes only happen when requested with [`Emulator::resume`], and the Xen wallclock is
+//! the system clock unless overridden with [`Emulator::set_time`]. Messages logged to dom0
+//! are kept, see [`Emulator::logs`]. The device can be removed and added back with
+//! [`Emulator::unplug`] and [`Emulator::plug`], handles opened before then stay stale.
+//! Granting and mapping ioctls complete immediately, rather than being pending until the
+//! pages are released.
+//!
+use std::{
+    alloc::{self, Layout},
+    collections::{BTreeMap, HashMap},
+    io, mem,
+    ptr::NonNull,
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    task::{Context, Poll, Waker},
+    time::SystemTime,
+};
+
+use crate::{
+    Access, Completed, Event, Permission, PermissionSet, Transport, XENSTORE_PAYLOAD_MAX,
+    domain::{is_special, valid_path},
+    error::win32_error,
+    error::{
+        ERROR_ACCESS_DENIED, ERROR_DEVICE_REMOVED, ERROR_FILE_NOT_FOUND, ERROR_INVALID_FUNCTION,
+        ERROR_INVALID_HANDLE, ERROR_INVALID_PARAMETER, ERROR_MORE_DATA, ERROR_NOT_ENOUGH_QUOTA,
+    },
+    evtchn::{BIND_INTERDOMAIN_IN_LEN, BIND_UNBOUND_IN_LEN},
+    gnttab::{
+        GNTTAB_READONLY, GNTTAB_USE_NOTIFY_OFFSET, GNTTAB_USE_NOTIFY_PORT, PAGE_SIZE,
+        PAGES_IN_HEADER_LEN,
+    },
+    logger::{LOG_MAX_LENGTH, is_loggable},
+    permission::{SET_PERMISSIONS_IN_HEADER_LEN, STORE_PERMISSION_LEN},
+    time::{self, XenTime},
+    utils::parse_nul_list,
+};
+
+/// In-memory xeniface device.
+///
+/// Clones (and [`Transport::try_clone`]) share the same store.
+#[derive(Clone)]
+pub struct Emulator(Arc<Mutex<Store>>, u64);
+
+struct Store {
+    domid: u16,
+    nodes: BTreeMap<String, Node>,
+    events: HashMap<usize, Weak<EventState>>,
+    watches: HashMap<usize, WatchEntry>,
+    ports: HashMap<u32, Port>,
+    grants: HashMap<u32, Grant>,
+    requests: HashMap<u32, Request>,
+    suspend_count: u32,
+    suspend_events: HashMap<usize, Weak<EventState>>,
+    time: Option<XenTime>,
+    logs: Vec<String>,
+    /// Number of upcoming watch registrations to fail.
+    failed_watches: usize,
+    /// Whether the device is present.
+    present: bool,
+    /// Bumped when the device is removed, handles of older generations are stale.
+    generation: u64,
+    next_id: usize,
+}
+
+struct Node {
+    value: Vec<u8>,
+    permissions: PermissionSet,
+}
+
+struct WatchEntry {
+    path: String,
+    event: Weak<EventState>,
+}
+
+struct Port {
+    event: Weak<EventState>,
+    remote_domid: u16,
+    /// Bound port, `None` if unbound.
+    peer: Option<u32>,
+    /// Mask the port after each notification.
+    auto_mask: bool,
+    masked: bool,
+    /// Notification held while masked.
+    pending: bool,
+}
+
+/// Zeroed pages, shared by grants and mappings.
+struct Pages {
+    address: NonNull<u8>,
+    layout: Layout,
+}
+
+// SAFETY: The pages are only accessed through raw pointers given to userland.
+unsafe impl Send for Pages {}
+unsafe impl Sync for Pages {}
+
+impl Pages {
+    fn new(count: usize) -> io::Result<Self> {
+        let layout = Layout::from_size_align(count * PAGE_SIZE, PAGE_SIZE)
+            .ok()
+            .filter(|layout| layout.size() > 0)
+            .ok_or_else(|| win32_error(ERROR_INVALID_PARAMETER))?;
+        let address = NonNull::new(unsafe { alloc::alloc_zeroed(layout) })
+            .unwrap_or_else(|| alloc::handle_alloc_error(layout));
+
+        Ok(Self { address, layout })
+    }
+}
+
+impl Drop for Pages {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.address.as_ptr(), self.layout) };
+    }
+}
+
+struct Grant {
+    remote_domid: u16,
+    readonly: bool,
+    pages: Arc<Pages>,
+    /// Index of the granted page.
+    index: usize,
+}
+
+/// Pending grant or map request.
+struct Request {
+    pages: Arc<Pages>,
+    /// Offset of the requested pages.
+    offset: usize,
+    /// Grant references, empty for mappings.
+    references: Vec<u32>,
+    notify_offset: Option<usize>,
+    notify_port: Option<u32>,
+}
+
+impl Request {
+    fn address(&self) -> usize {
+        self.pages.address.as_ptr().expose_provenance() + self.offset
+    }
+}
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Emulator {
+    /// Create an emulator with an empty store, as seen from domain 0.
+    pub fn new() -> Self {
+        Self::with_domid(0)
+    }
+
+    /// Create an emulator with an empty store, as seen from `domid`.
+    ///
+    /// Relative paths are resolved against `/local/domain/<domid>`.
+    pub fn with_domid(domid: u16) -> Self {
+        Self(
+            Arc::new(Mutex::new(Store {
+                domid,
+                nodes: BTreeMap::from([(
+                    "/".to_string(),
+                    Node {
+                        value: vec![],
+                        permissions: PermissionSet::new(0, Access::None),
+                    },
+                )]),
+                events: HashMap::new(),
+                watches: HashMap::new(),
+                ports: HashMap::new(),
+                grants: HashMap::new(),
+                requests: HashMap::new(),
+                suspend_count: 0,
+                suspend_events: HashMap::new(),
+                time: None,
+                logs: vec![],
+                failed_watches: 0,
+                present: true,
+                generation: 0,
+                // Zero is never a valid handle/context.
+                next_id: 1,
+            })),
+            0,
+        )
+    }
+
+    fn store(&self) -> MutexGuard<'_, Store> {
+        self.0.lock().unwrap()
+    }
+
+    /// Read a node value, bypassing the ioctl interface.
+    pub fn get(&self, path: &str) -> Option<Vec<u8>> {
+        let store = self.store();
+        let path = store.resolve(path).ok()?;
+
+        store.nodes.get(&path).map(|node| node.value.clone())
+    }
+
+    /// Number of bound event channel ports.
+    pub fn port_count(&self) -> usize {
+        self.store().ports.len()
+    }
+
+    /// Number of pages currently granted.
+    pub fn grant_count(&self) -> usize {
+        self.store().grants.len()
+    }
+
+    /// Number of currently pending grant and map requests.
+    pub fn request_count(&self) -> usize {
+        self.store().requests.len()
+    }
+
+    /// Number of events registered for resumes.
+    pub fn suspend_event_count(&self) -> usize {
+        self.store().suspend_events.len()
+    }
+
+    /// Emulate a suspend and resume of the guest (e.g live migration).
+    ///
+    /// Watches are kept, like xenbus does.
+    pub fn resume(&self) {
+        let mut store = self.store();
+
+        store.suspend_count += 1;
+        store
+            .suspend_events
+            .values()
+            .filter_map(Weak::upgrade)
+            .for_each(|event| event.set());
+    }
+
+    /// Emulate the removal of the device (e.g driver upgrade).
+    ///
+    /// Watches and resume registrations are dropped, and the current handles (including
+    /// this one) fail with `ERROR_DEVICE_REMOVED` from now on, see [`Emulator::reopen`].
+    pub fn unplug(&self) {
+        let mut store = self.store();
+
+        store.present = false;
+        store.generation += 1;
+        store.watches.clear();
+        store.suspend_events.clear();
+    }
+
+    /// Add the device back after [`Emulator::unplug`].
+    pub fn plug(&self) {
+        self.store().present = true;
+    }
+
+    /// Open a new handle to the device, like after enumerating it again.
+    ///
+    /// Fails with `ERROR_FILE_NOT_FOUND` while the device is unplugged.
+    pub fn reopen(&self) -> io::Result<Self> {
+        let store = self.store();
+
+        if !store.present {
+            return Err(win32_error(ERROR_FILE_NOT_FOUND));
+        }
+
+        Ok(Self(self.0.clone(), store.generation))
+    }
+
+    /// Fail if this handle was opened before the device was unplugged.
+    fn check_present(&self) -> io::Result<()> {
+        match self.store().generation == self.1 {
+            true => Ok(()),
+            false => Err(win32_error(ERROR_DEVICE_REMOVED)),
+        }
+    }
+
+    /// Override the reported Xen wallclock, `None` reports the system clock (as UTC).
+    pub fn set_time(&self, time: Option<XenTime>) {
+        self.store().time = time;
+    }
+
+    /// Messages logged to dom0 so far.
+    pub fn logs(&self) -> Vec<String> {
+        self.store().logs.clone()
+    }
+
+    /// Get the permissions of a node.
+    pub fn permissions(&self, path: &str) -> Option<PermissionSet> {
+        let store = self.store();
+        let path = store.resolve(path).ok()?;
+
+        store.nodes.get(&path).map(|node| node.permissions.clone())
+    }
+
+    /// Write a node value (like another domain would), bypassing the ioctl interface.
+    ///
+    /// Unlike ioctl writes, the value is not limited to [`XENSTORE_PAYLOAD_MAX`].
+    pub fn set(&self, path: &str, value: impl AsRef<[u8]>) {
+        let mut store = self.store();
+        let path = store.resolve(path).expect("path should be valid");
+
+        store.write(path, value.as_ref().to_vec());
+    }
+
+    /// Remove a node and its subtree, bypassing the ioctl interface.
+    ///
+    /// Returns `false` if the node doesn't exist.
+    pub fn remove(&self, path: &str) -> bool {
+        let mut store = self.store();
+
+        match store.resolve(path) {
+            Ok(path) => store.remove(&path).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Fail the next `count` watch registrations with `ERROR_NOT_ENOUGH_QUOTA`, like
+    /// xenstored does past the per-domain watch limit.
+    pub fn fail_watches(&self, count: usize) {
+        self.store().failed_watches = count;
+    }
+
+    /// Number of currently registered watches.
+    pub fn watch_count(&self) -> usize {
+        self.store().watches.len()
+    }
+}
+
+fn is_subpath(path: &str, parent: &str) -> bool {
+    parent == "/"
+        || path
+            .strip_prefix(parent)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+fn parent_path(path: &str) -> Option<&str> {
+    match path.rsplit_once('/') {
+        _ if path == "/" => None,
+        Some(("", _)) => Some("/"),
+        Some((parent, _)) => Some(parent),
+        None => None,
+    }
+}
+
+impl Store {
+    fn resolve(&self, path: &str) -> io::Result<String> {
+        if !valid_path(path) {
+            return Err(win32_error(ERROR_INVALID_PARAMETER));
+        }
+
+        Ok(if path.starts_with('/') || is_special(path) {
+            path.to_string()
+        } else {
+            format!("/local/domain/{}/{path}", self.domid)
+        })
+    }
+
+    fn directory(&self, path: &str) -> io::Result<Vec<&str>> {
+        if !self.nodes.contains_key(path) {
+            return Err(win32_error(ERROR_FILE_NOT_FOUND));
+        }
+
+        let prefix = if path == "/" {
+            "/".to_string()
+        } else {
+            format!("{path}/")
+        };
+
+        Ok(self
+            .nodes
+            .range(prefix.clone()..)
+            .map(|(child, _)| child)
+            .take_while(|child| child.starts_with(&prefix))
+            .filter_map(|child| child.strip_prefix(&prefix))
+            .filter(|name| !name.is_empty() && !name.contains('/'))
+            .collect())
+    }
+
+    fn write(&mut self, path: String, value: Vec<u8>) {
+        self.fire(&path, false);
+
+        if let Some(node) = self.nodes.get_mut(&path) {
+            node.value = value;
+            return;
+        }
+
+        // Implicitly create missing parents, from the top-most one.
+        let mut missing = vec![];
+        let mut parent = parent_path(&path);
+        while let Some(p) = parent
+            && !self.nodes.contains_key(p)
+        {
+            missing.push(p.to_string());
+            parent = parent_path(p);
+        }
+
+        for new in missing.into_iter().rev() {
+            self.create(new, vec![]);
+        }
+        self.create(path, value);
+    }
+
+    fn create(&mut self, path: String, value: Vec<u8>) {
+        let parent = &self.nodes[parent_path(&path).unwrap()];
+        let mut permissions = parent.permissions.clone();
+
+        // Nodes created by unprivileged domains belong to them.
+        if self.domid != 0 {
+            permissions = permissions.with_owner(self.domid);
+        }
+
+        self.nodes.insert(path, Node { value, permissions });
+    }
+
+    fn remove(&mut self, path: &str) -> io::Result<()> {
+        if path == "/" {
+            return Err(win32_error(ERROR_INVALID_PARAMETER));
+        }
+
+        if !self.nodes.contains_key(path) {
+            return Err(win32_error(ERROR_FILE_NOT_FOUND));
+        }
+
+        self.nodes.retain(|node, _| !is_subpath(node, path));
+        self.fire(path, true);
+
+        Ok(())
+    }
+
+    /// Signal all watches affected by a change on `path`: the ones on it and its ancestors,
+    /// and on its descendants too if it was `removed` (like xenstored).
+    fn fire(&mut self, path: &str, removed: bool) {
+        self.watches
+            .values()
+            .filter(|watch| {
+                is_subpath(path, &watch.path) || (removed && is_subpath(&watch.path, path))
+            })
+            .filter_map(|watch| watch.event.upgrade())
+            .for_each(|event| event.set());
+    }
+
+    fn allocate_id(&mut self) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn port(&mut self, port: u32) -> io::Result<&mut Port> {
+        self.ports
+            .get_mut(&port)
+            .ok_or_else(|| win32_error(ERROR_INVALID_PARAMETER))
+    }
+
+    /// Notify the peer of `port`, if bound.
+    fn notify(&mut self, port: u32) -> io::Result<()> {
+        if let Some(peer) = self.port(port)?.peer {
+            self.deliver(peer);
+        }
+
+        Ok(())
+    }
+
+    /// Signal the event of `port`, or hold the notification if masked.
+    fn deliver(&mut self, port: u32) {
+        let Some(port) = self.ports.get_mut(&port) else {
+            return;
+        };
+
+        if port.masked {
+            port.pending = true;
+            return;
+        }
+
+        if let Some(event) = port.event.upgrade() {
+            event.set();
+        }
+        port.masked = port.auto_mask;
+    }
+}
+
+/// Copy `payload` to `out_buffer` the way the driver does.
+fn output(payload: &[u8], out_buffer: Option<&mut [u8]>) -> io::Result<u32> {
+    match out_buffer {
+        Some(buffer) if buffer.len() >= payload.len() => {
+            buffer[..payload.len()].copy_from_slice(payload);
+            Ok(payload.len() as u32)
+        }
+        Some(buffer) if !buffer.is_empty() => Err(win32_error(ERROR_INVALID_PARAMETER)),
+        _ => Err(win32_error(ERROR_MORE_DATA)),
+    }
+}
+
+/// Parse a list of NUL-terminated strings (the last one must be terminated).
+fn input_strings(in_buffer: &[u8]) -> io::Result<Box<[&str]>> {
+    if in_buffer.last() != Some(&0) {
+        return Err(win32_error(ERROR_INVALID_PARAMETER));
+    }
+
+    parse_nul_list(in_buffer).map_err(|_| win32_error(ERROR_INVALID_PARAMETER))
+}
+
+fn input_usize(in_buffer: &[u8], index: usize) -> io::Result<usize> {
+    let offset = index * size_of::<usize>();
+
+    in_buffer
+        .get(offset..offset + size_of::<usize>())
+        .map(|bytes| usize::from_ne_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| win32_error(ERROR_INVALID_PARAMETER))
+}
+
+impl Emulator {
+    fn store_read(&self, in_buffer: &[u8], out_buffer: Option<&mut [u8]>) -> io::Result<u32> {
+        let store = self.store();
+        let path = match *input_strings(in_buffer)? {
+            [path] => store.resolve(path)?,
+            _ => return Err(win32_error(ERROR_INVALID_PARAMETER)),
+        };
+
+        let mut payload = store
+            .nodes
+            .get(&path)
+            .ok_or_else(|| win32_error(ERROR_FILE_NOT_FOUND))?
+            .value
+            .clone();
+        payload.push(0);
+
+        output(&payload, out_buffer)
+    }
+
+    fn store_write(&self, in_buffer: &[u8]) -> io::Result<u32> {
+        if in_buffer.len() > XENSTORE_PAYLOAD_MAX {
+            return Err(win32_error(ERROR_INVALID_PARAMETER));
+        }
+
+        let mut store = self.store();
+        // Values are raw bytes, only the path has to be UTF-8.
+        let parts: Vec<&[u8]> = in_buffer
+            .strip_suffix(&[0])
+            .ok_or_else(|| win32_error(ERROR_INVALID_PARAMETER))?
+            .split(|&c| c == 0)
+            .collect();
+        let (path, value) = match *parts {
+            // Tolerate the optional final NUL terminator.
+            [path, value] | [path, value, []] => (
+                str::from_utf8(path).map_err(|_| win32_error(ERROR_INVALID_PARAMETER))?,
+                value,
+            ),
+            _ => return Err(win32_error(ERROR_INVALID_PARAMETER)),
+        };
+        let path = store.resolve(path)?;
+
+        store.write(path, value.to_vec());
+        Ok(0)
+    }
+
+    fn store_directory(&self, in_buffer: &[u8], out_buffer: Option<&mut [u8]>) -> io::Result<u32> {
+        let store = self.store();
+        let path = match *input_strings(in_buffer)? {
+            [path] => store.resolve(path)?,
+            _ => return Err(win32_error(ERROR_INVALID_PARAMETER)),
+        };
+
+        let mut payload = vec![];
+        for child in store.directory(&path)? {
+            payload.extend_from_slice(child.as_bytes());
+            payload.push(0);
+        }
+        payload.push(0);
+
+        output(&payload, out_buffer)
+    }
+
+    fn store_remove(&self, in_buffer: &[u8]) -> io::Result<u32> {
+        let mut store = self.store();
+        let path = match *input_strings(in_buffer)? {
+            [path] => store.resolve(path)?,
+            _ => return Err(win32_error(ERROR_INVALID_PARAMETER)),
+        };
+
+        store.remove(&path)?;
+        Ok(0)
+    }
+
+    /// # Safety
+    ///
+    /// `in_buffer` must be a valid `XENIFACE_STORE_SET_PERMISSIONS_IN`.
+    unsafe fn store_set_permissions(&self, in_buffer: &[u8]) -> io::Result<u32> {
+        let path_ptr = input_usize(in_buffer, 0)?;
+        let header = in_buffer
+            .get(..SET_PERMISSIONS_IN_HEADER_LEN)
+            .ok_or_else(|| win32_error(ERROR_INVALID_PARAMETER))?;
+        let path_len = u32::from_ne_bytes(header[size_of::<usize>()..][..4].try_into().unwrap());
+        let count = u32::from_ne_bytes(header[size_of::<usize>() + 4..].try_into().unwrap());
+
+        let entries = &in_buffer[SET_PERMISSIONS_IN_HEADER_LEN..];
+        if path_ptr == 0 || count == 0 || entries.len() != count as usize * STORE_PERMISSION_LEN {
+            return Err(win32_error(ERROR_INVALID_PARAMETER));
+        }
+
+        let permissions = entries
+            .chunks_exact(STORE_PERMISSION_LEN)
+            .map(|entry| {
+                let domid = u16::from_ne_bytes(entry[..2].try_into().unwrap());
+                let mask = u32::from_ne_bytes(entry[4..].try_into().unwrap());
+
+                Access::from_mask(mask).map(|access| Permission::new(domid, access))
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| win32_error(ERROR_INVALID_PARAMETER))?;
+
+        let raw_path = unsafe {
+            std::slice::from_raw_parts(
+                std::ptr::with_exposed_provenance(path_ptr),
+                path_len as usize,
+            )
+        };
+
+        let mut store = self.store();
+        let path = match *input_strings(raw_path)? {
+            [path] => store.resolve(path)?,
+            _ => return Err(win32_error(ERROR_INVALID_PARAMETER)),
+        };
+
+        let node = store
+            .nodes
+            .get_mut(&path)
+            .ok_or_else(|| win32_error(ERROR_FILE_NOT_FOUND))?;
+        node.permissions = permissions.try_into().unwrap();
+        store.fire(&path, false);
+
+        Ok(0)
+    }
+
+    /// # Safety
+    ///
+    /// `in_buffer` must be a valid `XENIFACE_STORE_ADD_WATCH_IN`.
+    unsafe fn store_add_watch(
+        &self,
+        in_buffer: &[u8],
+        out_buffer: Option<&mut [u8]>,
+    ) -> io::Result<u32> {
+        let path_ptr = input_usize(in_buffer, 0)?;
+        // PathLength is a ULONG.
+        let path_len = input_usize(in_buffer, 1)? as u32 as usize;
+        let handle = input_usize(in_buffer, 2)?;
+
+        if path_ptr == 0 || path_len == 0 {
+            return Err(win32_error(ERROR_INVALID_PARAMETER));
+        }
+
+        let raw_path = unsafe {
+            std::slice::from_raw_parts(std::ptr::with_exposed_provenance(path_ptr), path_len)
+        };
+
+        let mut store = self.store();
+        let path = match *input_strings(raw_path)? {
+            [path] => store.resolve(path)?,
+            _ => return Err(win32_error(ERROR_INVALID_PARAMETER)),
+        };
+
+        let event = store
+            .events
+            .get(&handle)
+            .and_then(Weak::upgrade)
+            .ok_or_else(|| win32_error(ERROR_INVALID_HANDLE))?;
+
+        if store.failed_watches > 0 {
+            store.failed_watches -= 1;
+            return Err(win32_error(ERROR_NOT_ENOUGH_QUOTA));
+        }
+
+        let context = store.allocate_id();
+        let len = output(&context.to_ne_bytes(), out_buffer)?;
+
+        store.watches.insert(
+            context,
+            WatchEntry {
+                path,
+                event: Arc::downgrade(&event),
+            },
+        );
+
+        // xenstored always fires a watch once registered.
+        event.set();
+
+        Ok(len)
+    }
+
+    fn store_remove_watch(&self, in_buffer: &[u8]) -> io::Result<u32> {
+        let context = input_usize(in_buffer, 0)?;
+
+        match self.store().watches.remove(&context) {
+            Some(_) => Ok(0),
+            None => Err(win32_error(ERROR_INVALID_PARAMETER)),
+        }
+    }
+}
+
+fn input_u32(in_buffer: &[u8], offset: usize) -> io::Result<u32> {
+    in_buffer
+        .get(offset..offset + size_of::<u32>())
+        .map(|bytes| u32::from_ne_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| win32_error(ERROR_INVALID_PARAMETER))
+}
+
+impl Store {
+    /// Allocate a port signaling the `handle` event.
+    fn bind_port(
+        &mut self,
+        remote_domid: u16,
+        handle: usize,
+        auto_mask: bool,
+        peer: Option<u32>,
+    ) -> io::Result<u32> {
+        let event = self
+            .events
+            .get(&handle)
+            .ok_or_else(|| win32_error(ERROR_INVALID_HANDLE))?
+            .clone();
+        let port = self.allocate_id() as u32;
+
+        self.ports.insert(
+            port,
+            Port {
+                event,
+                remote_domid,
+                peer,
+                auto_mask,
+                masked: false,
+                pending: false,
+            },
+        );
+
+        Ok(port)
+    }
+}
+
+impl Emulator {
+    fn evtchn_bind_unbound(
+        &self,
+        in_buffer: &[u8],
+        mut out_buffer: Option<&mut [u8]>,
+    ) -> io::Result<u32> {
+        const HANDLE: usize = size_of::<usize>();
+
+        if in_buffer.len() != BIND_UNBOUND_IN_LEN {
+            return Err(win32_error(ERROR_INVALID_PARAMETER));
+        }
+
+        let remote_domid = u16::from_ne_bytes(in_buffer[..2].try_into().unwrap());
+        let handle = input_usize(in_buffer, 1)?;
+        let auto_mask = in_buffer[2 * HANDLE] != 0;
+
+        let mut store = self.store();
+        // Check the output buffer first, so that we don't leak a port.
+        output(&0u32.to_ne_bytes(), out_buffer.as_deref_mut())?;
+        let port = store.bind_port(remote_domid, handle, auto_mask, None)?;
+
+        output(&port.to_ne_bytes(), out_buffer)
+    }
+
+    fn evtchn_bind_interdomain(
+        &self,
+        in_buffer: &[u8],
+        mut out_buffer: Option<&mut [u8]>,
+    ) -> io::Result<u32> {
+        const HANDLE: usize = size_of::<usize>();
+
+        if in_buffer.len() != BIND_INTERDOMAIN_IN_LEN {
+            return Err(win32_error(ERROR_INVALID_PARAMETER));
+        }
+
+        let remote_domid = u16::from_ne_bytes(in_buffer[..2].try_into().unwrap());
+        let remote_port = input_u32(in_buffer, 4)?;
+        let handle = usize::from_ne_bytes(in_buffer[8..8 + HANDLE].try_into().unwrap());
+        let auto_mask = in_buffer[8 + HANDLE] != 0;
+
+        let mut store = self.store();
+        let domid = store.domid;
+
+        // Only loopback event channels, to an unbound port that expects us.
+        match store.ports.get(&remote_port) {
+            Some(remote)
+                if remote_domid == domid
+                    && remote.remote_domid == domid
+                    && remote.peer.is_none() => {}
+            _ => return Err(win32_error(ERROR_INVALID_PARAMETER)),
+        }
+
+        output(&0u32.to_ne_bytes(), out_buffer.as_deref_mut())?;
+        let port = store.bind_port(remote_domid, handle, auto_mask, Some(remote_port))?;
+        store.port(remote_port)?.peer = Some(port);
+
+        output(&port.to_ne_bytes(), out_buffer)
+    }
+
+    fn evtchn_close(&self, in_buffer: &[u8]) -> io::Result<u32> {
+        let mut store = self.store();
+        let port = input_u32(in_buffer, 0)?;
+
+        let closed = store
+            .ports
+            .remove(&port)
+            .ok_or_else(|| win32_error(ERROR_INVALID_PARAMETER))?;
+
+        // The remote port becomes unbound.
+        if let Some(peer) = closed.peer
+            && let Some(peer) = store.ports.get_mut(&peer)
+        {
+            peer.peer = None;
+        }
+
+        Ok(0)
+    }
+
+    fn evtchn_notify(&self, in_buffer: &[u8]) -> io::Result<u32> {
+        let port = input_u32(in_buffer, 0)?;
+
+        // Notifying an unbound port does nothing.
+        self.store().notify(port)?;
+
+        Ok(0)
+    }
+
+    fn evtchn_unmask(&self, in_buffer: &[u8]) -> io::Result<u32> {
+        let mut store = self.store();
+        let port = input_u32(in_buffer, 0)?;
+        let entry = store.port(port)?;
+
+        entry.masked = false;
+        if mem::take(&mut entry.pending) {
+            store.deliver(port);
+        }
+
+        Ok(0)
+    }
+}
+
+/// Decoded header of `XENIFACE_GNTTAB_{PERMIT_FOREIGN_ACCESS,MAP_FOREIGN_PAGES}_IN`.
+struct PagesIn {
+    request_id: u32,
+    remote_domid: u16,
+    count: usize,
+    readonly: bool,
+    notify_offset: Option<usize>,
+    notify_port: Option<u32>,
+}
+
+fn input_pages(in_buffer: &[u8]) -> io::Result<PagesIn> {
+    let flags = input_u32(in_buffer, 12)?;
+    let count = input_u32(in_buffer, 8)? as usize;
+
+    let pages_in = PagesIn {
+        request_id: input_u32(in_buffer, 0)?,
+        remote_domid: u16::from_ne_bytes(in_buffer[4..6].try_into().unwrap()),
+        count,
+        readonly: flags & GNTTAB_READONLY != 0,
+        notify_offset: (flags & GNTTAB_USE_NOTIFY_OFFSET != 0)
+            .then(|| input_u32(in_buffer, 16))
+            .transpose()?
+            .map(|offset| offset as usize),
+        notify_port: (flags & GNTTAB_USE_NOTIFY_PORT != 0)
+            .then(|| input_u32(in_buffer, 20))
+            .transpose()?,
+    };
+
+    if count == 0
+        || pages_in
+            .notify_offset
+            .is_some_and(|offset| offset >= count * PAGE_SIZE)
+    {
+        return Err(win32_error(ERROR_INVALID_PARAMETER));
+    }
+
+    Ok(pages_in)
+}
+
+impl Store {
+    fn insert_request(&mut self, pages_in: PagesIn, request: Request) -> io::Result<()> {
+        if self.requests.contains_key(&pages_in.request_id) {
+            return Err(win32_error(ERROR_INVALID_PARAMETER));
+        }
+
+        self.requests.insert(
+            pages_in.request_id,
+            Request {
+                notify_offset: pages_in.notify_offset,
+                notify_port: pages_in.notify_port,
+                ..request
+            },
+        );
+
+        Ok(())
+    }
+}
+
+impl Emulator {
+    fn gnttab_permit_foreign_access(&self, in_buffer: &[u8]) -> io::Result<u32> {
+        if in_buffer.len() != PAGES_IN_HEADER_LEN {
+            return Err(win32_error(ERROR_INVALID_PARAMETER));
+        }
+
+        let pages_in = input_pages(in_buffer)?;
+        let mut store = self.store();
+        let pages = Arc::new(Pages::new(pages_in.count)?);
+
+        let references = (0..pages_in.count)
+            .map(|index| {
+                let reference = store.allocate_id() as u32;
+
+                store.grants.insert(
+                    reference,
+                    Grant {
+                        remote_domid: pages_in.remote_domid,
+                        readonly: pages_in.readonly,
+                        pages: pages.clone(),
+                        index,
+                    },
+                );
+                reference
+            })
+            .collect();
+
+        store.insert_request(
+            pages_in,
+            Request {
+                pages,
+                offset: 0,
+                references,
+                notify_offset: None,
+                notify_port: None,
+            },
+        )?;
+
+        Ok(0)
+    }
+
+    fn gnttab_get_grant_result(
+        &self,
+        in_buffer: &[u8],
+        out_buffer: Option<&mut [u8]>,
+    ) -> io::Result<u32> {
+        let store = self.store();
+        let request = store
+            .requests
+            .get(&input_u32(in_buffer, 0)?)
+            .filter(|request| !request.references.is_empty())
+            .ok_or_else(|| win32_error(ERROR_INVALID_PARAMETER))?;
+
+        let mut payload = request.address().to_ne_bytes().to_vec();
+        request
+            .references
+            .iter()
+            .for_each(|reference| payload.extend(reference.to_ne_bytes()));
+
+        output(&payload, out_buffer)
+    }
+
+    fn gnttab_map_foreign_pages(&self, in_buffer: &[u8]) -> io::Result<u32> {
+        let pages_in = input_pages(in_buffer)?;
+
+        if in_buffer.len() != PAGES_IN_HEADER_LEN + pages_in.count * size_of::<u32>() {
+            return Err(win32_error(ERROR_INVALID_PARAMETER));
+        }
+
+        let mut store = self.store();
+        let domid = store.domid;
+
+        let grants = in_buffer[PAGES_IN_HEADER_LEN..]
+            .chunks_exact(size_of::<u32>())
+            .map(|bytes| u32::from_ne_bytes(bytes.try_into().unwrap()))
+            .map(|reference| match store.grants.get(&reference) {
+                // Only loopback grants.
+                Some(grant) if pages_in.remote_domid == domid && grant.remote_domid == domid => {
+                    Ok(grant)
+                }
+                _ => Err(win32_error(ERROR_INVALID_PARAMETER)),
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        if grants
+            .iter()
+            .any(|grant| grant.readonly && !pages_in.readonly)
+        {
+            return Err(win32_error(ERROR_ACCESS_DENIED));
+        }
+
+        // We can't remap pages, so they must be contiguous already.
+        let first = grants[0];
+        if !grants.iter().enumerate().all(|(i, grant)| {
+            Arc::ptr_eq(&grant.pages, &first.pages) && grant.index == first.index + i
+        }) {
+            return Err(win32_error(ERROR_INVALID_PARAMETER));
+        }
+
+        let request = Request {
+            pages: first.pages.clone(),
+            offset: first.index * PAGE_SIZE,
+            references: Vec::new(),
+            notify_offset: None,
+            notify_port: None,
+        };
+        store.insert_request(pages_in, request)?;
+
+        Ok(0)
+    }
+
+    fn gnttab_get_map_result(
+        &self,
+        in_buffer: &[u8],
+        out_buffer: Option<&mut [u8]>,
+    ) -> io::Result<u32> {
+        let store = self.store();
+        let request = store
+            .requests
+            .get(&input_u32(in_buffer, 0)?)
+            .filter(|request| request.references.is_empty())
+            .ok_or_else(|| win32_error(ERROR_INVALID_PARAMETER))?;
+
+        output(&request.address().to_ne_bytes(), out_buffer)
+    }
+
+    /// Revoke or unmap pages.
+    fn gnttab_release(&self, in_buffer: &[u8]) -> io::Result<u32> {
+        let mut store = self.store();
+        let request = store
+            .requests
+            .remove(&input_u32(in_buffer, 0)?)
+            .ok_or_else(|| win32_error(ERROR_INVALID_PARAMETER))?;
+
+        for reference in &request.references {
+            store.grants.remove(reference);
+        }
+
+        if let Some(offset) = request.notify_offset {
+            // The other end may access it concurrently.
+            unsafe {
+                request
+                    .pages
+                    .address
+                    .add(request.offset + offset)
+                    .write_volatile(0)
+            };
+        }
+
+        if let Some(port) = request.notify_port {
+            store.notify(port).ok();
+        }
+
+        Ok(0)
+    }
+}
+
+impl Emulator {
+    fn suspend_get_count(&self, out_buffer: Option<&mut [u8]>) -> io::Result<u32> {
+        output(&self.store().suspend_count.to_ne_bytes(), out_buffer)
+    }
+
+    fn suspend_register(
+        &self,
+        in_buffer: &[u8],
+        mut out_buffer: Option<&mut [u8]>,
+    ) -> io::Result<u32> {
+        let handle = input_usize(in_buffer, 0)?;
+        let mut store = self.store();
+
+        let event = store
+            .events
+            .get(&handle)
+            .ok_or_else(|| win32_error(ERROR_INVALID_HANDLE))?
+            .clone();
+
+        output(&0usize.to_ne_bytes(), out_buffer.as_deref_mut())?;
+        let context = store.allocate_id();
+        store.suspend_events.insert(context, event);
+
+        output(&context.to_ne_bytes(), out_buffer)
+    }
+
+    fn suspend_deregister(&self, in_buffer: &[u8]) -> io::Result<u32> {
+        let context = input_usize(in_buffer, 0)?;
+
+        match self.store().suspend_events.remove(&context) {
+            Some(_) => Ok(0),
+            None => Err(win32_error(ERROR_INVALID_PARAMETER)),
+        }
+    }
+}
+
+impl Emulator {
+    fn sharedinfo_get_time(&self, out_buffer: Option<&mut [u8]>) -> io::Result<u32> {
+        let time = self
+            .store()
+            .time
+            .unwrap_or_else(|| SystemTime::now().into());
+
+        output(&time::get_time_out(time), out_buffer)
+    }
+}
+
+impl Emulator {
+    fn log(&self, in_buffer: &[u8]) -> io::Result<u32> {
+        // XENIFACE_LOG_MAX_LENGTH includes the NUL terminator.
+        let Some((0, message)) = in_buffer.split_last() else {
+            return Err(win32_error(ERROR_INVALID_PARAMETER));
+        };
+
+        if message.is_empty()
+            || message.len() > LOG_MAX_LENGTH
+            || !message.iter().all(|&c| is_loggable(c))
+        {
+            return Err(win32_error(ERROR_INVALID_PARAMETER));
+        }
+
+        // Only ASCII characters are loggable.
+        let message = String::from_utf8(message.to_vec()).unwrap();
+        self.store().logs.push(message);
+
+        Ok(0)
+    }
+}
+
+impl Transport for Emulator {
+    type Event = EmulatedEvent;
+    type Overlapped<'a> = Completed;
+
+    unsafe fn ioctl(
+        &self,
+        control_code: u32,
+        in_buffer: &[u8],
+        out_buffer: Option<&mut [u8]>,
+    ) -> io::Result<u32> {
+        // CTL_CODE(FILE_DEVICE_UNKNOWN, function, METHOD_BUFFERED, FILE_ANY_ACCESS)
+        if control_code >> 16 != 0x22 || control_code & 0xc003 != 0 {
+            return Err(win32_error(ERROR_INVALID_FUNCTION));
+        }
+
+        self.check_present()?;
+
+        match (control_code >> 2) & 0xfff {
+            0x800 => self.store_read(in_buffer, out_buffer),
+            0x801 => self.store_write(in_buffer),
+            0x802 => self.store_directory(in_buffer, out_buffer),
+            0x803 => self.store_remove(in_buffer),
+            0x804 => unsafe { self.store_set_permissions(in_buffer) },
+            0x805 => unsafe { self.store_add_watch(in_buffer, out_buffer) },
+            0x806 => self.store_remove_watch(in_buffer),
+            0x810 => self.evtchn_bind_unbound(in_buffer, out_buffer),
+            0x811 => self.evtchn_bind_interdomain(in_buffer, out_buffer),
+            0x812 => self.evtchn_close(in_buffer),
+            0x813 => self.evtchn_notify(in_buffer),
+            0x814 => self.evtchn_unmask(in_buffer),
+            0x820 => self.gnttab_permit_foreign_access(in_buffer),
+            0x821 => self.gnttab_get_grant_result(in_buffer, out_buffer),
+            0x822 | 0x825 => self.gnttab_release(in_buffer),
+            0x823 => self.gnttab_map_foreign_pages(in_buffer),
+            0x824 => self.gnttab_get_map_result(in_buffer, out_buffer),
+            0x830 => self.suspend_get_count(out_buffer),
+            0x831 => self.suspend_register(in_buffer, out_buffer),
+            0x832 => self.suspend_deregister(in_buffer),
+            0x840 => self.sharedinfo_get_time(out_buffer),
+            0x84f => self.log(in_buffer),
+            _ => Err(win32_error(ERROR_INVALID_FUNCTION)),
+        }
+    }
+
+    /// Store operations are synchronous, the ioctl is complete once submitted.
+    unsafe fn submit(
+        &self,
+        control_code: u32,
+        in_buffer: Vec<u8>,
+        mut out_buffer: Vec<u8>,
+    ) -> io::Result<Self::Overlapped<'_>> {
+        let result = unsafe { self.ioctl(control_code, &in_buffer, Some(&mut out_buffer)) };
+
+        Ok(Completed::new(result.map(|len| {
+            out_buffer.truncate(len as usize);
+            out_buffer
+        })))
+    }
+
+    fn create_event(&self) -> io::Result<Self::Event> {
+        let mut store = self.store();
+        let id = store.allocate_id();
+        let state = Arc::new(EventState::default());
+
+        store.events.retain(|_, event| event.strong_count() > 0);
+        store.events.insert(id, Arc::downgrade(&state));
+
+        Ok(EmulatedEvent { id, state })
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(self.clone())
+    }
+}
+
+/// Manual-reset event of an [`Emulator`].
+pub struct EmulatedEvent {
+    id: usize,
+    state: Arc<EventState>,
+}
+
+#[derive(Default)]
+struct EventState {
+    inner: Mutex<EventInner>,
+}
+
+#[derive(Default)]
+struct EventInner {
+    signaled: bool,
+    wakers: Vec<Waker>,
+}
+
+impl EventState {
+    fn set(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.signaled = true;
+        inner.wakers.drain(..).for_each(Waker::wake);
+    }
+}
+
+impl EmulatedEvent {
+    /// Check whether the event is signaled.
+    pub fn is_set(&self) -> bool {
+        self.state.inner.lock().unwrap().signaled
+    }
+}
+
+impl Event for EmulatedEvent {
+    fn raw_handle(&self) -> usize {
+        self.id
+    }
+
+    fn set(&self) -> io::Result<()> {
+        self.state.set();
+        Ok(())
+    }
+
+    fn reset(&self) -> io::Result<()> {
+        self.state.inner.lock().unwrap().signaled = false;
+        Ok(())
+    }
+
+    fn poll_wait(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut inner = self.state.inner.lock().unwrap();
+
+        if inner.signaled {
+            Poll::Ready(Ok(()))
+        } else {
+            if !inner.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                inner.wakers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        }
+    }
+}
@@ -0,0 +1,347 @@
+//! Xenstore errors.
+//!
+//! The driver reports xenstore errors as Win32 error codes (translated by xenbus from
+//! the xenstored error strings), [`XsError`] maps them back to xenstore semantics.
+//!
+use std::{error::Error, ffi::OsString, fmt, io, str::Utf8Error};
+
+// Win32 error codes, as reported by xeniface.
+#[cfg(feature = "emulator")]
+pub(crate) const ERROR_INVALID_FUNCTION: u32 = 1;
+pub(crate) const ERROR_FILE_NOT_FOUND: u32 = 2;
+pub(crate) const ERROR_PATH_NOT_FOUND: u32 = 3;
+pub(crate) const ERROR_ACCESS_DENIED: u32 = 5;
+#[cfg(feature = "emulator")]
+pub(crate) const ERROR_INVALID_HANDLE: u32 = 6;
+pub(crate) const ERROR_SHARING_VIOLATION: u32 = 32;
+pub(crate) const ERROR_DEV_NOT_EXIST: u32 = 55;
+pub(crate) const ERROR_FILE_EXISTS: u32 = 80;
+pub(crate) const ERROR_INVALID_PARAMETER: u32 = 87;
+pub(crate) const ERROR_INSUFFICIENT_BUFFER: u32 = 122;
+pub(crate) const ERROR_ALREADY_EXISTS: u32 = 183;
+pub(crate) const ERROR_MORE_DATA: u32 = 234;
+pub(crate) const ERROR_NO_SUCH_DEVICE: u32 = 433;
+pub(crate) const ERROR_DEVICE_NOT_CONNECTED: u32 = 1167;
+pub(crate) const ERROR_RETRY: u32 = 1237;
+pub(crate) const ERROR_DEVICE_REMOVED: u32 = 1617;
+pub(crate) const ERROR_NOT_ENOUGH_QUOTA: u32 = 1816;
+
+/// [`io::Error`] of a Win32 error code, as transports report them.
+///
+/// This is a raw OS error on Windows. Elsewhere, raw OS errors are errnos, so the code
+/// is wrapped instead.
+pub fn win32_error(code: u32) -> io::Error {
+    #[cfg(windows)]
+    return io::Error::from_raw_os_error(code as i32);
+
+    #[cfg(not(windows))]
+    io::Error::new(XsError::from_win32(code).kind(), Win32Error(code))
+}
+
+/// Win32 error code of an [`io::Error`] made by [`win32_error`].
+pub(crate) fn win32_code(e: &io::Error) -> Option<u32> {
+    #[cfg(windows)]
+    return e.raw_os_error().map(|code| code as u32);
+
+    #[cfg(not(windows))]
+    e.get_ref()
+        .and_then(|inner| inner.downcast_ref::<Win32Error>())
+        .map(|e| e.0)
+}
+
+/// Win32 error code outside of Windows (see [`win32_error`]).
+#[cfg(not(windows))]
+#[derive(Debug)]
+struct Win32Error(u32);
+
+#[cfg(not(windows))]
+impl fmt::Display for Win32Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Win32 error {}", self.0)
+    }
+}
+
+#[cfg(not(windows))]
+impl Error for Win32Error {}
+
+/// Xenstore error.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum XsError {
+    /// Node doesn't exist (`ENOENT`).
+    NotFound,
+    /// Permission denied (`EACCES`).
+    PermissionDenied,
+    /// Node already exists (`EEXIST`).
+    AlreadyExists,
+    /// Payload is larger than what xenstore allows (`E2BIG`).
+    TooBig,
+    /// Domain quota exceeded (`EQUOTA`).
+    QuotaExceeded,
+    /// Operation should be retried (`EAGAIN`).
+    Again,
+    /// Invalid path or parameter (`EINVAL`).
+    InvalidArgument,
+    /// The xeniface device is gone (e.g disabled or driver upgraded).
+    DeviceGone,
+    /// No xeniface device found.
+    NoDevice,
+    /// Payload from the driver is malformed.
+    Malformed(Utf8Error),
+    /// Other Win32 error.
+    Os(u32),
+    /// Other I/O error.
+    Io(io::Error),
+}
+
+impl XsError {
+    /// Map a Win32 error code reported by xeniface.
+    pub fn from_win32(code: u32) -> Self {
+        match code {
+            ERROR_FILE_NOT_FOUND | ERROR_PATH_NOT_FOUND => Self::NotFound,
+            ERROR_ACCESS_DENIED => Self::PermissionDenied,
+            ERROR_ALREADY_EXISTS | ERROR_FILE_EXISTS => Self::AlreadyExists,
+            ERROR_NOT_ENOUGH_QUOTA => Self::QuotaExceeded,
+            ERROR_RETRY => Self::Again,
+            ERROR_INVALID_PARAMETER => Self::InvalidArgument,
+            ERROR_DEV_NOT_EXIST
+            | ERROR_NO_SUCH_DEVICE
+            | ERROR_DEVICE_NOT_CONNECTED
+            | ERROR_DEVICE_REMOVED => Self::DeviceGone,
+            code => Self::Os(code),
+        }
+    }
+
+    /// Whether the [`XsError`] of `e` (see [`XsError::from`]) matches `predicate`, without
+    /// consuming `e`.
+    pub(crate) fn matches(e: &io::Error, predicate: impl Fn(&XsError) -> bool) -> bool {
+        match e
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<XsError>())
+        {
+            Some(e) => predicate(e),
+            None => win32_code(e).is_some_and(|code| predicate(&Self::from_win32(code))),
+        }
+    }
+
+    /// Name of the matching xenstore error (e.g `ENOENT`), if any.
+    pub fn errno_name(&self) -> Option<&'static str> {
+        match self {
+            Self::NotFound => Some("ENOENT"),
+            Self::PermissionDenied => Some("EACCES"),
+            Self::AlreadyExists => Some("EEXIST"),
+            Self::TooBig => Some("E2BIG"),
+            Self::QuotaExceeded => Some("EQUOTA"),
+            Self::Again => Some("EAGAIN"),
+            Self::InvalidArgument => Some("EINVAL"),
+            _ => None,
+        }
+    }
+
+    /// [`io::ErrorKind`] matching this error.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Self::NotFound | Self::NoDevice => io::ErrorKind::NotFound,
+            Self::PermissionDenied => io::ErrorKind::PermissionDenied,
+            Self::AlreadyExists => io::ErrorKind::AlreadyExists,
+            Self::TooBig => io::ErrorKind::FileTooLarge,
+            Self::QuotaExceeded => io::ErrorKind::QuotaExceeded,
+            Self::Again => io::ErrorKind::WouldBlock,
+            Self::InvalidArgument => io::ErrorKind::InvalidInput,
+            Self::DeviceGone => io::ErrorKind::NotConnected,
+            Self::Malformed(_) => io::ErrorKind::InvalidData,
+            Self::Os(_) => io::ErrorKind::Other,
+            Self::Io(e) => e.kind(),
+        }
+    }
+}
+
+impl fmt::Display for XsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            Self::NotFound => "no such node",
+            Self::PermissionDenied => "permission denied",
+            Self::AlreadyExists => "node already exists",
+            Self::TooBig => "payload too large",
+            Self::QuotaExceeded => "quota exceeded",
+            Self::Again => "try again",
+            Self::InvalidArgument => "invalid argument",
+            Self::DeviceGone => "xeniface device is gone",
+            Self::NoDevice => "no xeniface device found",
+            Self::Malformed(e) => return write!(f, "malformed payload ({e})"),
+            Self::Os(code) => return write!(f, "xeniface error {code}"),
+            Self::Io(e) => return e.fmt(f),
+        };
+
+        match self.errno_name() {
+            Some(name) => write!(f, "{description} ({name})"),
+            None => f.write_str(description),
+        }
+    }
+}
+
+impl Error for XsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Malformed(e) => Some(e),
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for XsError {
+    /// Recover the [`XsError`] from an [`io::Error`], mapping Win32 codes (see [`win32_error`]).
+    fn from(e: io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<XsError>()) {
+            return *e.into_inner().unwrap().downcast().unwrap();
+        }
+
+        match win32_code(&e) {
+            Some(code) => Self::from_win32(code),
+            None => Self::Io(e),
+        }
+    }
+}
+
+impl From<Utf8Error> for XsError {
+    fn from(e: Utf8Error) -> Self {
+        Self::Malformed(e)
+    }
+}
+
+impl From<XsError> for io::Error {
+    /// The [`XsError`] can be recovered with [`XsError::from`].
+    fn from(e: XsError) -> Self {
+        match e {
+            XsError::Io(e) => e,
+            XsError::Os(code) => win32_error(code),
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl From<windows::core::Error> for XsError {
+    fn from(e: windows::core::Error) -> Self {
+        io::Error::from(e).into()
+    }
+}
+
+/// Why a xeniface device could not be opened.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum OpenFailure {
+    /// Access denied (e.g not running as administrator).
+    AccessDenied,
+    /// Already opened exclusively by someone else.
+    SharingViolation,
+    /// The interface is gone (e.g device removed, or driver being reinstalled).
+    NotPresent,
+    /// Other I/O error.
+    Io(io::Error),
+}
+
+impl From<io::Error> for OpenFailure {
+    fn from(e: io::Error) -> Self {
+        match win32_code(&e) {
+            Some(ERROR_ACCESS_DENIED) => Self::AccessDenied,
+            Some(ERROR_SHARING_VIOLATION) => Self::SharingViolation,
+            Some(
+                ERROR_FILE_NOT_FOUND
+                | ERROR_PATH_NOT_FOUND
+                | ERROR_DEV_NOT_EXIST
+                | ERROR_NO_SUCH_DEVICE
+                | ERROR_DEVICE_NOT_CONNECTED
+                | ERROR_DEVICE_REMOVED,
+            ) => Self::NotPresent,
+            _ => Self::Io(e),
+        }
+    }
+}
+
+impl fmt::Display for OpenFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AccessDenied => f.write_str("access denied"),
+            Self::SharingViolation => f.write_str("sharing violation"),
+            Self::NotPresent => f.write_str("not present"),
+            Self::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+/// Failure to open any xeniface device.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum OpenError {
+    /// The devices could not be enumerated.
+    Enumeration(io::Error),
+    /// None of the candidate interfaces could be opened (none was found if empty).
+    NoDevice(Vec<(OsString, OpenFailure)>),
+}
+
+impl OpenError {
+    /// Interface paths that were tried, and why each of them failed.
+    pub fn attempts(&self) -> &[(OsString, OpenFailure)] {
+        match self {
+            Self::Enumeration(_) => &[],
+            Self::NoDevice(attempts) => attempts,
+        }
+    }
+}
+
+impl fmt::Display for OpenError {
+    /// Everything on one line, e.g
+    /// `no usable xeniface device (<path>: access denied; <path>: not present)`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Enumeration(e) => write!(f, "unable to enumerate xeniface devices ({e})"),
+            Self::NoDevice(attempts) if attempts.is_empty() => {
+                f.write_str("no xeniface device found")
+            }
+            Self::NoDevice(attempts) => {
+                f.write_str("no usable xeniface device (")?;
+
+                for (i, (path, failure)) in attempts.iter().enumerate() {
+                    if i != 0 {
+                        f.write_str("; ")?;
+                    }
+                    write!(f, "{}: {failure}", path.display())?;
+                }
+
+                f.write_str(")")
+            }
+        }
+    }
+}
+
+impl Error for OpenError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Enumeration(e) => Some(e),
+            Self::NoDevice(_) => None,
+        }
+    }
+}
+
+impl From<OpenError> for io::Error {
+    fn from(e: OpenError) -> Self {
+        let kind = match &e {
+            OpenError::Enumeration(e) => e.kind(),
+            OpenError::NoDevice(_) => io::ErrorKind::NotFound,
+        };
+
+        io::Error::new(kind, e)
+    }
+}
+
+impl From<OpenError> for XsError {
+    /// [`XsError::NoDevice`] if no device was found, the detailed error otherwise.
+    fn from(e: OpenError) -> Self {
+        match e {
+            OpenError::NoDevice(attempts) if attempts.is_empty() => Self::NoDevice,
+            e => Self::Io(e.into()),
+        }
+    }
+}
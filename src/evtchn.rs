@@ -0,0 +1,256 @@
+//! Event channels.
+//!
+//! xeniface binds event channel ports to Win32 events, which allows building PV protocols
+//! with other domains (usually along with xenstore and grant tables).
+//!
+use std::{io, time::Duration};
+
+#[cfg(feature = "futures")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+#[cfg(feature = "futures")]
+use futures::Stream;
+
+use crate::{
+    DefaultTransport, Event, FILE_ANY_ACCESS, FILE_DEVICE_UNKNOWN, METHOD_BUFFERED, Transport,
+    XsWindows, ctl_code,
+};
+
+/* Bind an unbound event channel port, that a remote domain can bind to
+ * Input: XENIFACE_EVTCHN_BIND_UNBOUND_IN
+ * Output: XENIFACE_EVTCHN_BIND_UNBOUND_OUT
+ * #define IOCTL_XENIFACE_EVTCHN_BIND_UNBOUND \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x810, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_EVTCHN_BIND_UNBOUND: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x810, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/* Bind an event channel port to an unbound port of a remote domain
+ * Input: XENIFACE_EVTCHN_BIND_INTERDOMAIN_IN
+ * Output: XENIFACE_EVTCHN_BIND_INTERDOMAIN_OUT
+ * #define IOCTL_XENIFACE_EVTCHN_BIND_INTERDOMAIN \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x811, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_EVTCHN_BIND_INTERDOMAIN: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x811, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/* Close an event channel port
+ * Input: XENIFACE_EVTCHN_CLOSE_IN
+ * Output: None
+ * #define IOCTL_XENIFACE_EVTCHN_CLOSE \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x812, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_EVTCHN_CLOSE: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x812, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/* Notify the remote end of an event channel
+ * Input: XENIFACE_EVTCHN_NOTIFY_IN
+ * Output: None
+ * #define IOCTL_XENIFACE_EVTCHN_NOTIFY \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x813, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_EVTCHN_NOTIFY: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x813, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/* Unmask an event channel port
+ * Input: XENIFACE_EVTCHN_UNMASK_IN
+ * Output: None
+ * #define IOCTL_XENIFACE_EVTCHN_UNMASK \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x814, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_EVTCHN_UNMASK: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x814, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/*
+ * typedef struct _XENIFACE_EVTCHN_BIND_UNBOUND_IN {
+ *     USHORT  RemoteDomain;
+ *     HANDLE  Event;
+ *     BOOLEAN Mask;
+ * } XENIFACE_EVTCHN_BIND_UNBOUND_IN, *PXENIFACE_EVTCHN_BIND_UNBOUND_IN;
+ */
+pub(crate) const BIND_UNBOUND_IN_LEN: usize = 3 * size_of::<usize>();
+
+/// Encode a `XENIFACE_EVTCHN_BIND_UNBOUND_IN`.
+pub(crate) fn bind_unbound_in(remote_domid: u16, event: usize, mask: bool) -> Vec<u8> {
+    const HANDLE: usize = size_of::<usize>();
+    let mut bytes = vec![0; BIND_UNBOUND_IN_LEN];
+
+    bytes[..2].copy_from_slice(&remote_domid.to_ne_bytes());
+    bytes[HANDLE..2 * HANDLE].copy_from_slice(&event.to_ne_bytes());
+    bytes[2 * HANDLE] = mask as u8;
+
+    bytes
+}
+
+/*
+ * typedef struct _XENIFACE_EVTCHN_BIND_INTERDOMAIN_IN {
+ *     USHORT  RemoteDomain;
+ *     ULONG   RemotePort;
+ *     HANDLE  Event;
+ *     BOOLEAN Mask;
+ * } XENIFACE_EVTCHN_BIND_INTERDOMAIN_IN, *PXENIFACE_EVTCHN_BIND_INTERDOMAIN_IN;
+ */
+pub(crate) const BIND_INTERDOMAIN_IN_LEN: usize = 8 + 2 * size_of::<usize>();
+
+/// Encode a `XENIFACE_EVTCHN_BIND_INTERDOMAIN_IN`.
+pub(crate) fn bind_interdomain_in(
+    remote_domid: u16,
+    remote_port: u32,
+    event: usize,
+    mask: bool,
+) -> Vec<u8> {
+    const HANDLE: usize = size_of::<usize>();
+    let mut bytes = vec![0; BIND_INTERDOMAIN_IN_LEN];
+
+    bytes[..2].copy_from_slice(&remote_domid.to_ne_bytes());
+    bytes[4..8].copy_from_slice(&remote_port.to_ne_bytes());
+    bytes[8..8 + HANDLE].copy_from_slice(&event.to_ne_bytes());
+    bytes[8 + HANDLE] = mask as u8;
+
+    bytes
+}
+
+/*
+ * typedef struct _XENIFACE_EVTCHN_BIND_UNBOUND_OUT {
+ *     ULONG LocalPort;
+ * } XENIFACE_EVTCHN_BIND_UNBOUND_OUT, *PXENIFACE_EVTCHN_BIND_UNBOUND_OUT;
+ *
+ * (same for XENIFACE_EVTCHN_BIND_INTERDOMAIN_OUT, and LocalPort is the only member of
+ *  XENIFACE_EVTCHN_{CLOSE,NOTIFY,UNMASK}_IN)
+ */
+
+/// Event channel port bound to a Win32 event.
+///
+/// The port is closed when dropped.
+pub struct EventChannel<T: Transport = DefaultTransport> {
+    device: XsWindows<T>,
+    event: T::Event,
+    port: u32,
+}
+
+impl<T: Transport> EventChannel<T> {
+    fn bind(
+        xs: &XsWindows<T>,
+        control_code: u32,
+        in_buffer: impl FnOnce(usize) -> Vec<u8>,
+    ) -> io::Result<Self> {
+        // We want a clone of the device handle to be able to close the port.
+        let device = xs.try_clone()?;
+        let event = device.0.create_event()?;
+        let mut port = [0; size_of::<u32>()];
+
+        device.make_ioctl(
+            control_code,
+            &in_buffer(event.raw_handle()),
+            Some(&mut port),
+        )?;
+
+        Ok(Self {
+            device,
+            event,
+            port: u32::from_ne_bytes(port),
+        })
+    }
+
+    /// Allocate a port that `remote_domid` can bind to.
+    ///
+    /// If `mask` is set, the port is masked after each notification, until
+    /// [`EventChannel::unmask`] is called.
+    pub fn bind_unbound(xs: &XsWindows<T>, remote_domid: u16, mask: bool) -> io::Result<Self> {
+        Self::bind(xs, IOCTL_XENIFACE_EVTCHN_BIND_UNBOUND, |event| {
+            bind_unbound_in(remote_domid, event, mask)
+        })
+    }
+
+    /// Bind to `remote_port`, an unbound port allocated by `remote_domid`.
+    ///
+    /// See [`EventChannel::bind_unbound`] for `mask`.
+    pub fn bind_interdomain(
+        xs: &XsWindows<T>,
+        remote_domid: u16,
+        remote_port: u32,
+        mask: bool,
+    ) -> io::Result<Self> {
+        Self::bind(xs, IOCTL_XENIFACE_EVTCHN_BIND_INTERDOMAIN, |event| {
+            bind_interdomain_in(remote_domid, remote_port, event, mask)
+        })
+    }
+
+    /// Local port.
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Notify the remote end.
+    pub fn notify(&self) -> io::Result<()> {
+        self.device
+            .make_ioctl(IOCTL_XENIFACE_EVTCHN_NOTIFY, &self.port.to_ne_bytes(), None)?;
+        Ok(())
+    }
+
+    /// Unmask the port, delivering the notification that may have been held.
+    pub fn unmask(&self) -> io::Result<()> {
+        self.device
+            .make_ioctl(IOCTL_XENIFACE_EVTCHN_UNMASK, &self.port.to_ne_bytes(), None)?;
+        Ok(())
+    }
+
+    /// Block until the remote end notifies us.
+    ///
+    /// Notifications received before are coalesced.
+    pub fn wait(&self) -> io::Result<()> {
+        self.event.wait(None)?;
+        self.event.reset()
+    }
+
+    /// Poll until the remote end notifies us.
+    #[cfg(feature = "futures")]
+    pub(crate) fn poll_wait(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.event.poll_wait(cx))?;
+        Poll::Ready(self.event.reset())
+    }
+
+    /// Block until the remote end notifies us, or `timeout` expires.
+    ///
+    /// Returns `false` on timeout.
+    pub fn wait_timeout(&self, timeout: Duration) -> io::Result<bool> {
+        if self.event.wait(Some(timeout))? {
+            self.event.reset()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+impl<T: Transport> Drop for EventChannel<T> {
+    fn drop(&mut self) {
+        if let Err(e) =
+            self.device
+                .make_ioctl(IOCTL_XENIFACE_EVTCHN_CLOSE, &self.port.to_ne_bytes(), None)
+        {
+            log::warn!("Unable to close event channel {} {e}", self.port)
+        }
+    }
+}
+
+// Never pinned structurally.
+#[cfg(feature = "futures")]
+impl<T: Transport> Unpin for EventChannel<T> {}
+
+/// Notifications of the remote end, stops on error.
+#[cfg(feature = "futures")]
+impl<T: Transport> Stream for EventChannel<T> {
+    type Item = ();
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(
+            ready!(self.poll_wait(cx))
+                .inspect_err(|e| log::error!("Unable to wait for event channel: {e}"))
+                .ok(),
+        )
+    }
+}
@@ -0,0 +1,385 @@
+//! Grant tables.
+//!
+//! xeniface can share local pages with another domain ([`GrantedPages`]), and map pages
+//! that another domain shared with us ([`MappedForeignPages`]). Along with event channels,
+//! this allows building shared-memory rings.
+//!
+//! Granting and mapping are ioctls that stay pending until the pages are revoked or unmapped,
+//! the driver cancels them (thus revoking or unmapping the pages) if the device handle is
+//! closed or the issuing thread exits.
+//!
+use std::{
+    io,
+    ptr::{self, NonNull},
+    slice,
+    sync::atomic::{AtomicU32, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use crate::{
+    DefaultTransport, FILE_ANY_ACCESS, FILE_DEVICE_UNKNOWN, METHOD_BUFFERED, Overlapped, Transport,
+    XsError, XsWindows, ctl_code,
+};
+
+/// Size of a granted page.
+pub const PAGE_SIZE: usize = 4096;
+
+/* Grant permission to access local memory pages to a foreign domain
+ * Input: XENIFACE_GNTTAB_PERMIT_FOREIGN_ACCESS_IN
+ * Output: None
+ *
+ * This IOCTL must be asynchronous. The driver doesn't complete the request
+ * until the grant is explicitly revoked or the calling thread terminates.
+ * #define IOCTL_XENIFACE_GNTTAB_PERMIT_FOREIGN_ACCESS \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x820, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_GNTTAB_PERMIT_FOREIGN_ACCESS: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x820, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/* Get the result of a pending grant request
+ * Input: XENIFACE_GNTTAB_GET_GRANT_RESULT_IN
+ * Output: XENIFACE_GNTTAB_GET_GRANT_RESULT_OUT
+ * #define IOCTL_XENIFACE_GNTTAB_GET_GRANT_RESULT \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x821, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_GNTTAB_GET_GRANT_RESULT: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x821, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/* Revoke a foreign domain access to previously granted memory pages
+ * Input: XENIFACE_GNTTAB_REVOKE_FOREIGN_ACCESS_IN
+ * Output: None
+ * #define IOCTL_XENIFACE_GNTTAB_REVOKE_FOREIGN_ACCESS \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x822, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_GNTTAB_REVOKE_FOREIGN_ACCESS: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x822, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/* Map a foreign domain's granted memory pages
+ * Input: XENIFACE_GNTTAB_MAP_FOREIGN_PAGES_IN
+ * Output: None
+ *
+ * This IOCTL must be asynchronous. The driver doesn't complete the request
+ * until the memory is explicitly unmapped or the calling thread terminates.
+ * #define IOCTL_XENIFACE_GNTTAB_MAP_FOREIGN_PAGES \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x823, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_GNTTAB_MAP_FOREIGN_PAGES: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x823, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/* Get the result of a pending map request
+ * Input: XENIFACE_GNTTAB_GET_MAP_RESULT_IN
+ * Output: XENIFACE_GNTTAB_GET_MAP_RESULT_OUT
+ * #define IOCTL_XENIFACE_GNTTAB_GET_MAP_RESULT \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x824, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_GNTTAB_GET_MAP_RESULT: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x824, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/* Unmap a foreign domain's memory pages, previously mapped
+ * Input: XENIFACE_GNTTAB_UNMAP_FOREIGN_PAGES_IN
+ * Output: None
+ * #define IOCTL_XENIFACE_GNTTAB_UNMAP_FOREIGN_PAGES \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x825, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_GNTTAB_UNMAP_FOREIGN_PAGES: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x825, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/*
+ * typedef enum _XENIFACE_GNTTAB_PAGE_FLAGS {
+ *     XENIFACE_GNTTAB_READONLY          = 1 << 0,
+ *     XENIFACE_GNTTAB_USE_NOTIFY_OFFSET = 1 << 1,
+ *     XENIFACE_GNTTAB_USE_NOTIFY_PORT   = 1 << 2,
+ * } XENIFACE_GNTTAB_PAGE_FLAGS;
+ */
+pub(crate) const GNTTAB_READONLY: u32 = 1 << 0;
+pub(crate) const GNTTAB_USE_NOTIFY_OFFSET: u32 = 1 << 1;
+pub(crate) const GNTTAB_USE_NOTIFY_PORT: u32 = 1 << 2;
+
+/*
+ * typedef struct _XENIFACE_GNTTAB_PERMIT_FOREIGN_ACCESS_IN {
+ *     ULONG  RequestId;
+ *     USHORT RemoteDomain;
+ *     ULONG  NumberPages;
+ *     XENIFACE_GNTTAB_PAGE_FLAGS Flags;
+ *     ULONG  NotifyOffset;
+ *     ULONG  NotifyPort;
+ * } XENIFACE_GNTTAB_PERMIT_FOREIGN_ACCESS_IN, *PXENIFACE_GNTTAB_PERMIT_FOREIGN_ACCESS_IN;
+ *
+ * typedef struct _XENIFACE_GNTTAB_MAP_FOREIGN_PAGES_IN {
+ *     ULONG  RequestId;
+ *     USHORT RemoteDomain;
+ *     ULONG  NumberPages;
+ *     XENIFACE_GNTTAB_PAGE_FLAGS Flags;
+ *     ULONG  NotifyOffset;
+ *     ULONG  NotifyPort;
+ *     ULONG  References[ANYSIZE_ARRAY];
+ * } XENIFACE_GNTTAB_MAP_FOREIGN_PAGES_IN, *PXENIFACE_GNTTAB_MAP_FOREIGN_PAGES_IN;
+ *
+ * (RequestId is the only member of XENIFACE_GNTTAB_{GET_GRANT_RESULT,REVOKE_FOREIGN_ACCESS,
+ *  GET_MAP_RESULT,UNMAP_FOREIGN_PAGES}_IN)
+ */
+pub(crate) const PAGES_IN_HEADER_LEN: usize = 6 * size_of::<u32>();
+
+/// Encode the common header of `XENIFACE_GNTTAB_{PERMIT_FOREIGN_ACCESS,MAP_FOREIGN_PAGES}_IN`.
+fn pages_in(
+    request_id: u32,
+    remote_domid: u16,
+    count: u32,
+    readonly: bool,
+    notify: Notify,
+) -> Vec<u8> {
+    let mut flags = 0;
+    if readonly {
+        flags |= GNTTAB_READONLY;
+    }
+    if notify.offset.is_some() {
+        flags |= GNTTAB_USE_NOTIFY_OFFSET;
+    }
+    if notify.port.is_some() {
+        flags |= GNTTAB_USE_NOTIFY_PORT;
+    }
+
+    let mut bytes = Vec::with_capacity(PAGES_IN_HEADER_LEN);
+
+    bytes.extend(request_id.to_ne_bytes());
+    bytes.extend(remote_domid.to_ne_bytes());
+    bytes.extend([0; 2]); // padding
+    bytes.extend(count.to_ne_bytes());
+    bytes.extend(flags.to_ne_bytes());
+    bytes.extend(notify.offset.unwrap_or(0).to_ne_bytes());
+    bytes.extend(notify.port.unwrap_or(0).to_ne_bytes());
+
+    bytes
+}
+
+/*
+ * typedef struct _XENIFACE_GNTTAB_GET_GRANT_RESULT_OUT {
+ *     PVOID Address;
+ *     ULONG References[ANYSIZE_ARRAY];
+ * } XENIFACE_GNTTAB_GET_GRANT_RESULT_OUT, *PXENIFACE_GNTTAB_GET_GRANT_RESULT_OUT;
+ *
+ * typedef struct _XENIFACE_GNTTAB_GET_MAP_RESULT_OUT {
+ *     PVOID Address;
+ * } XENIFACE_GNTTAB_GET_MAP_RESULT_OUT, *PXENIFACE_GNTTAB_GET_MAP_RESULT_OUT;
+ */
+
+/// Request ids only need to be unique in the process.
+fn next_request_id() -> u32 {
+    static NEXT_REQUEST_ID: AtomicU32 = AtomicU32::new(1);
+
+    NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Notification the driver performs when the pages are revoked or unmapped, including
+/// when that happens because the process exits.
+///
+/// This lets the remote end know that we are gone.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Notify {
+    /// Offset of a byte of the pages that is cleared.
+    pub offset: Option<u32>,
+    /// Local event channel port that is notified.
+    pub port: Option<u32>,
+}
+
+/// Pages pending in the driver, until `release_code` is issued.
+struct Request<'a, T: Transport> {
+    xs: &'a XsWindows<T>,
+    // Dropped after the release ioctl, which completes it.
+    _ioctl: T::Overlapped<'a>,
+    id: u32,
+    release_code: u32,
+    address: NonNull<u8>,
+    len: usize,
+}
+
+impl<'a, T: Transport> Request<'a, T> {
+    /// Issue the pending `control_code` ioctl and get its result with `result_code`.
+    fn new(
+        xs: &'a XsWindows<T>,
+        (control_code, result_code, release_code): (u32, u32, u32),
+        in_buffer: impl FnOnce(u32) -> Vec<u8>,
+        count: usize,
+        out_buffer: &mut [u8],
+    ) -> io::Result<Self> {
+        let id = next_request_id();
+
+        // The input doesn't embed any pointer.
+        let mut ioctl = unsafe {
+            xs.0.submit(control_code, in_buffer(id), Vec::new())
+                .map_err(XsError::from)?
+        };
+
+        if let Err(e) = xs.make_ioctl(result_code, &id.to_ne_bytes(), Some(out_buffer)) {
+            // Report why the request failed rather than it being unknown.
+            return match ioctl.poll_complete(&mut Context::from_waker(Waker::noop())) {
+                Poll::Ready(Err(e)) => Err(XsError::from(e).into()),
+                _ => Err(e.into()),
+            };
+        }
+
+        let address = usize::from_ne_bytes(out_buffer[..size_of::<usize>()].try_into().unwrap());
+        let request = Self {
+            xs,
+            _ioctl: ioctl,
+            id,
+            release_code,
+            address: NonNull::new(ptr::with_exposed_provenance_mut(address))
+                .ok_or_else(|| io::Error::other("driver returned a null address"))?,
+            len: count * PAGE_SIZE,
+        };
+
+        Ok(request)
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.address.as_ptr(), self.len) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.address.as_ptr(), self.len) }
+    }
+}
+
+impl<T: Transport> Drop for Request<'_, T> {
+    fn drop(&mut self) {
+        if let Err(e) = self
+            .xs
+            .make_ioctl(self.release_code, &self.id.to_ne_bytes(), None)
+        {
+            // Cancelling the pending ioctl releases the pages anyway.
+            log::warn!("Unable to release grant request {} {e}", self.id)
+        }
+    }
+}
+
+/// Local pages shared with a remote domain.
+///
+/// The pages are zeroed when granted, and access is revoked when dropped.
+pub struct GrantedPages<'a, T: Transport = DefaultTransport> {
+    request: Request<'a, T>,
+    references: Box<[u32]>,
+}
+
+impl<'a, T: Transport> GrantedPages<'a, T> {
+    /// Allocate `count` pages, and grant `remote_domid` access to them.
+    ///
+    /// If `readonly` is set, the remote domain can only map them read-only.
+    pub fn new(
+        xs: &'a XsWindows<T>,
+        remote_domid: u16,
+        count: u32,
+        readonly: bool,
+        notify: Notify,
+    ) -> io::Result<Self> {
+        let count = count as usize;
+        let mut out_buffer = vec![0; size_of::<usize>() + count * size_of::<u32>()];
+
+        let request = Request::new(
+            xs,
+            (
+                IOCTL_XENIFACE_GNTTAB_PERMIT_FOREIGN_ACCESS,
+                IOCTL_XENIFACE_GNTTAB_GET_GRANT_RESULT,
+                IOCTL_XENIFACE_GNTTAB_REVOKE_FOREIGN_ACCESS,
+            ),
+            |id| pages_in(id, remote_domid, count as u32, readonly, notify),
+            count,
+            &mut out_buffer,
+        )?;
+
+        let references = out_buffer[size_of::<usize>()..]
+            .chunks_exact(size_of::<u32>())
+            .map(|bytes| u32::from_ne_bytes(bytes.try_into().unwrap()))
+            .collect();
+
+        Ok(Self {
+            request,
+            references,
+        })
+    }
+
+    /// Grant references of the pages, to give to the remote domain.
+    pub fn references(&self) -> &[u32] {
+        &self.references
+    }
+
+    /// Pointer to the pages, which the remote domain may access concurrently.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.request.address.as_ptr()
+    }
+
+    /// Content of the pages.
+    pub fn as_slice(&self) -> &[u8] {
+        self.request.as_slice()
+    }
+
+    /// Mutable content of the pages.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        self.request.as_mut_slice()
+    }
+}
+
+/// Pages of a remote domain, mapped locally.
+///
+/// The pages are unmapped when dropped.
+pub struct MappedForeignPages<'a, T: Transport = DefaultTransport> {
+    request: Request<'a, T>,
+    readonly: bool,
+}
+
+impl<'a, T: Transport> MappedForeignPages<'a, T> {
+    /// Map the pages `remote_domid` granted us, contiguously.
+    ///
+    /// If `readonly` is set, the pages are mapped read-only, and can't be written to.
+    pub fn new(
+        xs: &'a XsWindows<T>,
+        remote_domid: u16,
+        references: &[u32],
+        readonly: bool,
+        notify: Notify,
+    ) -> io::Result<Self> {
+        let mut out_buffer = [0; size_of::<usize>()];
+
+        let request = Request::new(
+            xs,
+            (
+                IOCTL_XENIFACE_GNTTAB_MAP_FOREIGN_PAGES,
+                IOCTL_XENIFACE_GNTTAB_GET_MAP_RESULT,
+                IOCTL_XENIFACE_GNTTAB_UNMAP_FOREIGN_PAGES,
+            ),
+            |id| {
+                let mut bytes =
+                    pages_in(id, remote_domid, references.len() as u32, readonly, notify);
+                references
+                    .iter()
+                    .for_each(|reference| bytes.extend(reference.to_ne_bytes()));
+                bytes
+            },
+            references.len(),
+            &mut out_buffer,
+        )?;
+
+        Ok(Self { request, readonly })
+    }
+
+    /// Whether the pages are mapped read-only.
+    pub fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+
+    /// Pointer to the pages, which the remote domain may access concurrently.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.request.address.as_ptr()
+    }
+
+    /// Content of the pages.
+    pub fn as_slice(&self) -> &[u8] {
+        self.request.as_slice()
+    }
+
+    /// Mutable content of the pages, `None` if they are mapped read-only.
+    pub fn as_mut_slice(&mut self) -> Option<&mut [u8]> {
+        // Writing to a read-only mapping faults.
+        (!self.readonly).then(|| self.request.as_mut_slice())
+    }
+}
@@ -1,38 +1,95 @@
 //! Xenstore Windows implementation.
 //! Rely on xeniface driver.
 //!
+#[cfg(windows)]
 mod device;
+mod domain;
+mod error;
+mod overlapped;
+mod permission;
+mod reconnect;
+mod suspend;
+mod transaction;
+mod transport;
 mod utils;
-
+mod watch;
+mod watch_set;
+
+pub mod evtchn;
+pub mod gnttab;
+pub mod logger;
+pub mod snapshot;
+pub mod time;
+pub mod vchan;
+pub mod walk;
+
+#[cfg(feature = "emulator")]
+pub mod emulator;
+#[cfg(feature = "serde")]
+pub mod serde;
 #[cfg(feature = "smol")]
 pub mod smol;
+#[cfg(feature = "tokio")]
+pub mod tokio;
+
+#[cfg(windows)]
+pub use device::XenifaceDevice;
+pub use domain::{DomainXs, domain_path};
+pub use error::{OpenError, OpenFailure, XsError, win32_error};
+#[cfg(windows)]
+pub use overlapped::OverlappedIoctl;
+pub use overlapped::{Completed, Overlapped};
+pub use permission::{Access, ParsePermissionError, Permission, PermissionSet};
+pub use reconnect::{ConnectionStatus, ReconnectingXs};
+#[cfg(feature = "futures")]
+pub use suspend::ResumeStream;
+pub use suspend::SuspendWatcher;
+pub use transaction::{TRANSACTION_ATTEMPTS, Transaction};
+#[cfg(windows)]
+pub use transport::EventHandle;
+#[cfg(not(windows))]
+pub use transport::Unsupported;
+pub use transport::{DefaultTransport, Event, Transport};
+pub use watch::Watch;
+#[cfg(feature = "futures")]
+pub use watch::WatchStream;
+pub use watch_set::{WATCH_SET_CAPACITY, WatchSet};
 
 use std::{
-    ffi::{CString, c_void},
+    ffi::{CStr, CString, OsStr, OsString, c_void},
     io,
-    os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle},
+    sync::Arc,
 };
 
-use device::{DeviceInfoList, GUID_INTERFACE_XENIFACE};
-use utils::{make_payload, parse_nul_list, parse_nul_string};
+#[cfg(windows)]
+use std::os::windows::{
+    ffi::OsStrExt,
+    io::{FromRawHandle, OwnedHandle},
+};
 
+use error::{ERROR_INSUFFICIENT_BUFFER, ERROR_INVALID_PARAMETER, ERROR_MORE_DATA, win32_code};
 use log::{debug, warn};
+use permission::set_permissions_in;
+use utils::{make_bytes_payload, make_payload, parse_nul_list, parse_nul_string};
+use watch::Watches;
+
+#[cfg(windows)]
+use device::{DeviceInfoList, GUID_INTERFACE_XENIFACE};
+#[cfg(windows)]
 use windows::{
     Win32::{
-        Foundation::{ERROR_NOT_FOUND, GENERIC_READ, GENERIC_WRITE, HANDLE},
+        Foundation::{GENERIC_READ, GENERIC_WRITE},
         Storage::FileSystem::{
-            CreateFileW, FILE_FLAGS_AND_ATTRIBUTES, FILE_SHARE_READ, FILE_SHARE_WRITE,
-            OPEN_EXISTING,
+            CreateFileW, FILE_FLAG_OVERLAPPED, FILE_SHARE_READ, FILE_SHARE_WRITE, OPEN_EXISTING,
         },
-        System::{IO::DeviceIoControl, Threading::CreateEventW},
     },
-    core::{PCWSTR, Result},
+    core::PCWSTR,
 };
 use xenstore_rs::Xs;
 
 // Well, there is no CTL_CODE in the windows crate so we need to add it ourselves.
 // Taken from https://docs.rs/winapi/latest/src/winapi/um/winioctl.rs.html#146-153
-fn ctl_code(device_type: u32, function: u32, method: u32, access: u32) -> u32 {
+const fn ctl_code(device_type: u32, function: u32, method: u32, access: u32) -> u32 {
     (device_type << 16) | (access << 14) | (function << 2) | method
 }
 
@@ -41,43 +98,179 @@ const METHOD_BUFFERED: u32 = 0;
 const FILE_ANY_ACCESS: u32 = 0;
 const FILE_DEVICE_UNKNOWN: u32 = 0x22;
 
+/* Read a value from XenStore
+ *  Input: NUL-terminated CHAR array containing the requested key's path
+ *  Output: NUL-terminated CHAR array containing the requested key's value
+ *  #define IOCTL_XENIFACE_STORE_READ \
+ *      CTL_CODE(FILE_DEVICE_UNKNOWN, 0x800, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_STORE_READ: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x800, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/* Write a value to XenStore
+ *  Input: NUL-terminated CHAR array containing the requested key's path,
+ *         NUL-terminated CHAR array containing the key's value, final NUL terminator
+ *  Output: None
+ * #define IOCTL_XENIFACE_STORE_WRITE \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x801, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_STORE_WRITE: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x801, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/* Enumerate all immediate child keys of a XenStore key
+ *  Input: NUL-terminated CHAR array containing the requested key's path
+ *  Output: List of NUL-terminated CHAR arrays containing the child key names,
+ *          followed by a NUL CHAR
+ *  #define IOCTL_XENIFACE_STORE_DIRECTORY \
+ *      CTL_CODE(FILE_DEVICE_UNKNOWN, 0x802, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_STORE_DIRECTORY: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x802, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/* Remove a key from XenStore
+ * Input: NUL-terminated CHAR array containing the requested key's path
+ * Output: None
+ * #define IOCTL_XENIFACE_STORE_REMOVE \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x803, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_STORE_REMOVE: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x803, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/* Set permissions for a XenStore key
+ * Input: XENIFACE_STORE_SET_PERMISSIONS_IN
+ * Output: None
+ * #define IOCTL_XENIFACE_STORE_SET_PERMISSIONS \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x804, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_STORE_SET_PERMISSIONS: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x804, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/* Add a XenStore watch
+ * Input: XENIFACE_STORE_ADD_WATCH_IN
+ * Output: XENIFACE_STORE_ADD_WATCH_OUT (PVOID)
+ * #define IOCTL_XENIFACE_STORE_ADD_WATCH \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x805, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_STORE_ADD_WATCH: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x805, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/*
+ * Remove a XenStore watch
+ * Input: XENIFACE_STORE_REMOVE_WATCH_IN (PVOID)
+ * Output: None
+ * #define IOCTL_XENIFACE_STORE_REMOVE_WATCH (PVOID)
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x806, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_STORE_REMOVE_WATCH: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x806, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/// Maximum size of a xenstore message payload.
+pub const XENSTORE_PAYLOAD_MAX: usize = 4096;
+
+// Largest output the driver can give : a full payload plus the final NUL terminator.
+// Output buffers are allocated with this size, so that any output fits in one ioctl.
+const MAX_OUTPUT_LEN: usize = XENSTORE_PAYLOAD_MAX + 1;
+
 /// Xenstore Windows implementation.
-pub struct XsWindows(OwnedHandle);
+///
+/// Talks to xeniface through a [`Transport`], which is the device handle by default.
+pub struct XsWindows<T: Transport = DefaultTransport>(T, Arc<Watches<T>>);
 
+#[cfg(windows)]
 impl XsWindows {
     /// Try to open Xenstore interface.
     ///
-    /// Uses the first working xeniface device (GUID = b2cfb085-aa5e-47e1-8bf7-9793f3154565).
-    pub fn new() -> Result<Self> {
+    /// Uses the first working xeniface device (GUID = b2cfb085-aa5e-47e1-8bf7-9793f3154565),
+    /// see [`XenifaceDevice::enumerate`] to choose one.
+    pub fn new() -> Result<Self, OpenError> {
         // Try all devices with XENIFACE class.
-        let dev_list = DeviceInfoList::new(GUID_INTERFACE_XENIFACE).unwrap();
-
-        for raw_wpath in dev_list.iter() {
-            let wpath = PCWSTR::from_raw(raw_wpath.as_ptr());
-            debug!("Trying {}", unsafe { wpath.display() });
-
-            match unsafe {
-                CreateFileW(
-                    wpath,
-                    (GENERIC_READ | GENERIC_WRITE).0,
-                    FILE_SHARE_READ | FILE_SHARE_WRITE,
-                    None,
-                    OPEN_EXISTING,
-                    FILE_FLAGS_AND_ATTRIBUTES::default(),
-                    None,
-                )
-            } {
-                Ok(file) => {
-                    debug!("Got {file:?}");
-                    return Ok(XsWindows(unsafe { OwnedHandle::from_raw_handle(file.0) }));
-                }
+        let dev_list = DeviceInfoList::new(GUID_INTERFACE_XENIFACE)
+            .map_err(|e| OpenError::Enumeration(e.into()))?;
+
+        Self::open_first(
+            dev_list.iter().map(|device| device.path().to_owned()),
+            open_handle,
+        )
+    }
+
+    /// Open a specific xeniface device.
+    pub fn open(device: &XenifaceDevice) -> Result<Self, XsError> {
+        Self::open_path(device.path())
+    }
+
+    /// Open a xeniface device from its interface path.
+    pub fn open_path(path: &OsStr) -> Result<Self, XsError> {
+        Ok(Self::with_transport(open_handle(path)?))
+    }
+}
+
+#[cfg(windows)]
+fn open_handle(path: &OsStr) -> io::Result<OwnedHandle> {
+    let wpath: Vec<u16> = path.encode_wide().chain([0]).collect();
+
+    let file = unsafe {
+        CreateFileW(
+            PCWSTR::from_raw(wpath.as_ptr()),
+            (GENERIC_READ | GENERIC_WRITE).0,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            None,
+            OPEN_EXISTING,
+            // Needed for async operations.
+            FILE_FLAG_OVERLAPPED,
+            None,
+        )
+    }?;
+
+    debug!("Got {file:?}");
+    Ok(unsafe { OwnedHandle::from_raw_handle(file.0) })
+}
+
+impl<T: Transport> XsWindows<T> {
+    /// Use Xenstore through a custom [`Transport`].
+    pub fn with_transport(transport: T) -> Self {
+        Self(transport, Arc::default())
+    }
+
+    /// Use the first of the interface `paths` that `open` succeeds with, or report why
+    /// each of them failed.
+    ///
+    /// This is what [`XsWindows::new`] does with the enumerated xeniface devices.
+    pub fn open_first<P: Into<OsString>>(
+        paths: impl IntoIterator<Item = P>,
+        mut open: impl FnMut(&OsStr) -> io::Result<T>,
+    ) -> Result<Self, OpenError> {
+        let mut attempts = vec![];
+
+        for path in paths {
+            let path = path.into();
+            debug!("Trying {}", path.display());
+
+            match open(&path) {
+                Ok(transport) => return Ok(Self::with_transport(transport)),
                 Err(e) => {
-                    warn!("Unable to open {} ({e})", unsafe { wpath.display() })
+                    warn!("Unable to open {} ({e})", path.display());
+                    attempts.push((path, e.into()));
                 }
             }
         }
 
-        return Err(ERROR_NOT_FOUND.into());
+        Err(OpenError::NoDevice(attempts))
+    }
+
+    /// Get the underlying [`Transport`].
+    pub fn transport(&self) -> &T {
+        &self.0
+    }
+
+    // Transport errors are kept as is (Win32 codes).
+    fn raw_ioctl(
+        &self,
+        control_code: u32,
+        in_buffer: &[u8],
+        out_buffer: Option<&mut [u8]>,
+    ) -> io::Result<u32> {
+        // Payloads given here are plain NUL-strings, they don't embed any pointer.
+        unsafe { self.0.ioctl(control_code, in_buffer, out_buffer) }
     }
 
     fn make_ioctl(
@@ -85,110 +278,162 @@ impl XsWindows {
         control_code: u32,
         in_buffer: &[u8],
         out_buffer: Option<&mut [u8]>,
-    ) -> Result<u32> {
-        let mut len = 0;
-        let out_buffer_len = out_buffer.as_ref().map_or(0, |s| s.len());
+    ) -> Result<u32, XsError> {
+        Ok(self.raw_ioctl(control_code, in_buffer, out_buffer)?)
+    }
 
-        unsafe {
-            DeviceIoControl(
-                HANDLE(self.0.as_raw_handle()),
-                control_code,
-                Some(in_buffer.as_ptr().cast()),
-                in_buffer.len() as u32,
-                out_buffer.map(|r| r.as_mut_ptr().cast()),
-                out_buffer_len as u32,
-                Some(&mut len),
-                None,
-            )?;
+    /// Make an ioctl that outputs a variable-length payload, up to [`MAX_OUTPUT_LEN`].
+    fn make_ioctl_output(&self, control_code: u32, in_buffer: &[u8]) -> Result<Vec<u8>, XsError> {
+        let mut out_buffer = vec![0u8; MAX_OUTPUT_LEN];
+
+        match self.raw_ioctl(control_code, in_buffer, Some(&mut out_buffer)) {
+            Ok(len) => {
+                out_buffer.truncate(len as usize);
+                Ok(out_buffer)
+            }
+            Err(e) => {
+                let probe =
+                    needs_probe(&e).then(|| self.raw_ioctl(control_code, in_buffer, Some(&mut [])));
+
+                Err(output_error(e, probe))
+            }
         }
+    }
+}
+
+/// Whether an ioctl with a variable-length output that failed with `e` must be probed
+/// again with an empty output buffer, to tell if the output was too large.
+///
+/// The driver fails with ERROR_INVALID_PARAMETER when the buffer is too small, but also
+/// for actually invalid parameters. An empty output buffer makes it report
+/// ERROR_MORE_DATA instead in the first case.
+fn needs_probe(e: &io::Error) -> bool {
+    win32_code(e) == Some(ERROR_INVALID_PARAMETER)
+}
 
-        Ok(len)
+/// Error of an ioctl with a [`MAX_OUTPUT_LEN`] long output buffer that failed with `e`,
+/// given the result of the `probe` ioctl if [`needs_probe`].
+///
+/// Shared by blocking and overlapped ioctls.
+fn output_error<R>(e: io::Error, probe: Option<io::Result<R>>) -> XsError {
+    match (win32_code(&e), probe) {
+        (Some(ERROR_MORE_DATA | ERROR_INSUFFICIENT_BUFFER), _) => XsError::TooBig,
+        (_, Some(Err(probe))) if win32_code(&probe) == Some(ERROR_MORE_DATA) => XsError::TooBig,
+        _ => e.into(),
     }
 }
 
-impl Xs for XsWindows {
+fn parse_directory(out_buffer: &[u8]) -> Result<Vec<Box<str>>, XsError> {
+    Ok(parse_nul_list(out_buffer)?
+        .iter()
+        // Skip the empty string from the final NUL terminator.
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string().into_boxed_str())
+        .collect())
+}
+
+fn parse_value(out_buffer: &[u8]) -> Result<Box<str>, XsError> {
+    Ok(parse_nul_string(out_buffer)?
+        .unwrap_or_default()
+        .to_string()
+        .into_boxed_str())
+}
+
+fn parse_value_bytes(mut out_buffer: Vec<u8>) -> Vec<u8> {
+    // Discard the terminating NUL.
+    if out_buffer.last() == Some(&0) {
+        out_buffer.pop();
+    }
+
+    out_buffer
+}
+
+/// Paths and values are NUL-delimited in xeniface payloads, they can't embed a NUL.
+fn check_write(path: &str, value: &[u8]) -> Result<(), XsError> {
+    if path.contains('\0') || value.contains(&0) {
+        return Err(XsError::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+impl<T: Transport> Xs for XsWindows<T> {
     fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
         let in_buffer = make_payload(&[path]);
-        let mut out_buffer = vec![0u8; 4096];
-
-        /* Enumerate all immediate child keys of a XenStore key
-         *  Input: NUL-terminated CHAR array containing the requested key's path
-         *  Output: List of NUL-terminated CHAR arrays containing the child key names,
-         *          followed by a NUL CHAR
-         *  #define IOCTL_XENIFACE_STORE_DIRECTORY \
-         *      CTL_CODE(FILE_DEVICE_UNKNOWN, 0x802, METHOD_BUFFERED, FILE_ANY_ACCESS)
-         */
-        let len = self.make_ioctl(
-            ctl_code(FILE_DEVICE_UNKNOWN, 0x802, METHOD_BUFFERED, FILE_ANY_ACCESS),
-            &in_buffer,
-            Some(&mut out_buffer),
-        )?;
-        out_buffer.truncate(len as usize);
-
-        Ok(parse_nul_list(&out_buffer)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
-            .iter()
-            .map(|s| s.to_string().into_boxed_str())
-            .collect())
+
+        let out_buffer = self.make_ioctl_output(IOCTL_XENIFACE_STORE_DIRECTORY, &in_buffer)?;
+
+        Ok(parse_directory(&out_buffer)?)
     }
 
     fn read(&self, path: &str) -> io::Result<Box<str>> {
         let in_buffer = make_payload(&[path]);
-        let mut out_buffer = vec![0u8; 4096];
-
-        /* Read a value from XenStore
-         *  Input: NUL-terminated CHAR array containing the requested key's path
-         *  Output: NUL-terminated CHAR array containing the requested key's value
-         *  #define IOCTL_XENIFACE_STORE_READ \
-         *      CTL_CODE(FILE_DEVICE_UNKNOWN, 0x800, METHOD_BUFFERED, FILE_ANY_ACCESS)
-         */
-        let len = self.make_ioctl(
-            ctl_code(FILE_DEVICE_UNKNOWN, 0x800, METHOD_BUFFERED, FILE_ANY_ACCESS),
-            &in_buffer,
-            Some(&mut out_buffer),
-        )?;
-        out_buffer.truncate(len as usize);
-
-        Ok(parse_nul_string(&out_buffer)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
-            .unwrap_or_default()
-            .to_string()
-            .into_boxed_str())
+
+        let out_buffer = self.make_ioctl_output(IOCTL_XENIFACE_STORE_READ, &in_buffer)?;
+
+        Ok(parse_value(&out_buffer)?)
     }
 
     fn write(&self, path: &str, data: &str) -> io::Result<()> {
-        let in_buffer = make_payload(&[path, data]);
-
-        /* Write a value to XenStore
-         *  Input: NUL-terminated CHAR array containing the requested key's path,
-         *         NUL-terminated CHAR array containing the key's value, final NUL terminator
-         *  Output: None
-         * #define IOCTL_XENIFACE_STORE_WRITE \
-         *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x801, METHOD_BUFFERED, FILE_ANY_ACCESS)
-         */
-        self.make_ioctl(
-            ctl_code(FILE_DEVICE_UNKNOWN, 0x801, METHOD_BUFFERED, FILE_ANY_ACCESS),
-            &in_buffer,
-            None,
-        )?;
+        self.write_bytes(path, data.as_bytes())
+    }
+
+    fn rm(&self, path: &str) -> io::Result<()> {
+        let in_buffer = make_payload(&[path]);
+
+        self.make_ioctl(IOCTL_XENIFACE_STORE_REMOVE, &in_buffer, None)?;
 
         Ok(())
     }
+}
 
-    fn rm(&self, path: &str) -> io::Result<()> {
+impl<T: Transport> XsWindows<T> {
+    /// Read the raw value of a node, which isn't required to be UTF-8.
+    pub fn read_bytes(&self, path: &str) -> io::Result<Vec<u8>> {
         let in_buffer = make_payload(&[path]);
 
-        /* Remove a key from XenStore
-         * Input: NUL-terminated CHAR array containing the requested key's path
-         * Output: None
-         * #define IOCTL_XENIFACE_STORE_REMOVE \
-         *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x803, METHOD_BUFFERED, FILE_ANY_ACCESS)
-         */
-        self.make_ioctl(
-            ctl_code(FILE_DEVICE_UNKNOWN, 0x803, METHOD_BUFFERED, FILE_ANY_ACCESS),
-            &in_buffer,
-            None,
-        )?;
+        let out_buffer = self.make_ioctl_output(IOCTL_XENIFACE_STORE_READ, &in_buffer)?;
+
+        Ok(parse_value_bytes(out_buffer))
+    }
+
+    /// Read the value of a node, replacing invalid UTF-8 sequences with `U+FFFD`.
+    pub fn read_lossy(&self, path: &str) -> io::Result<String> {
+        Ok(String::from_utf8_lossy(&self.read_bytes(path)?).into_owned())
+    }
+
+    /// Write a raw value, which isn't required to be UTF-8.
+    ///
+    /// Fails with [`XsError::InvalidArgument`] if `path` or `data` contains a NUL byte.
+    pub fn write_bytes(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        check_write(path, data)?;
+        let in_buffer = make_bytes_payload(&[path.as_bytes(), data]);
+
+        self.make_ioctl(IOCTL_XENIFACE_STORE_WRITE, &in_buffer, None)?;
+
+        Ok(())
+    }
+}
+
+impl<T: Transport> XsWindows<T> {
+    /// Set the permissions of a node.
+    ///
+    /// The first entry gives the owner of the node, and the access of domains that
+    /// are not listed (see [`PermissionSet`]).
+    pub fn set_permissions(&self, path: &str, permissions: &[Permission]) -> io::Result<()> {
+        if permissions.is_empty() {
+            return Err(XsError::InvalidArgument.into());
+        }
+
+        let c_path = CString::new(path)?;
+        let in_buffer = set_permissions_in(&c_path, permissions);
+
+        // c_path outlives the ioctl.
+        unsafe {
+            self.0
+                .ioctl(IOCTL_XENIFACE_STORE_SET_PERMISSIONS, &in_buffer, None)
+                .map_err(XsError::from)?;
+        }
 
         Ok(())
     }
@@ -197,63 +442,64 @@ impl Xs for XsWindows {
 #[derive(Clone, Copy, Default)]
 pub(crate) struct WatchContext([u8; size_of::<*mut c_void>()]);
 
-impl XsWindows {
+impl<T: Transport> XsWindows<T> {
     pub fn try_clone(&self) -> io::Result<Self> {
-        Ok(Self(self.0.try_clone()?))
+        // Clones share the watches, see XsWindows::rewatch.
+        Ok(Self(self.0.try_clone()?, self.1.clone()))
+    }
+
+    pub(crate) fn make_watch(&self, path: &str) -> io::Result<(T::Event, WatchContext)> {
+        let event = self.0.create_event()?;
+        let context = self.add_watch(path, &event)?;
+
+        // Like xenstored, report an initial event (whether the driver signaled it or not).
+        event.set()?;
+
+        Ok((event, context))
     }
 
-    pub(crate) fn make_watch(&self, path: &str) -> io::Result<(OwnedHandle, WatchContext)> {
-        /* Add a XenStore watch
-         * Input: XENIFACE_STORE_ADD_WATCH_IN
-         * Output: XENIFACE_STORE_ADD_WATCH_OUT (PVOID)
-         * #define IOCTL_XENIFACE_STORE_ADD_WATCH \
-         *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x805, METHOD_BUFFERED, FILE_ANY_ACCESS)
-         */
+    /// Register a watch on `path`, signaling `event`.
+    pub(crate) fn add_watch(&self, path: &str, event: &T::Event) -> io::Result<WatchContext> {
         let c_path = CString::new(path)?;
-        let event =
-            unsafe { OwnedHandle::from_raw_handle(CreateEventW(None, true, false, None)?.0) };
-
-        /*
-         * typedef struct _XENIFACE_STORE_ADD_WATCH_IN {
-         *     PCHAR  Path;       /*!< NUL-terminated path to a XenStore key */
-         *     ULONG  PathLength; /*!< Size of Path in bytes, including the NUL terminator */
-         *     HANDLE Event;      /*!< Handle to an event object that will be signaled when the watch fires */
-         * } XENIFACE_STORE_ADD_WATCH_IN, *PXENIFACE_STORE_ADD_WATCH_IN;
-         */
-        // TODO: Not sure if it would be preferable to use a repr(C) struct.
-        let watch_in_bytes = [
-            c_path.as_ptr().addr().to_ne_bytes(),
-            c_path.as_bytes_with_nul().len().to_ne_bytes(),
-            event.as_raw_handle().addr().to_ne_bytes(),
-        ];
+        let in_buffer = add_watch_in(&c_path, event);
         let mut context = WatchContext::default();
 
-        self.make_ioctl(
-            ctl_code(FILE_DEVICE_UNKNOWN, 0x805, METHOD_BUFFERED, FILE_ANY_ACCESS),
-            watch_in_bytes.as_flattened(),
-            Some(context.0.as_mut_slice()),
-        )?;
+        // c_path outlives the ioctl.
+        unsafe {
+            self.0
+                .ioctl(
+                    IOCTL_XENIFACE_STORE_ADD_WATCH,
+                    &in_buffer,
+                    Some(context.0.as_mut_slice()),
+                )
+                .map_err(XsError::from)?;
+        }
 
-        Ok((event, context))
+        Ok(context)
     }
 
     pub(crate) fn destroy_watch(&self, context: WatchContext) -> io::Result<()> {
-        /*
-         * Remove a XenStore watch
-         * Input: XENIFACE_STORE_REMOVE_WATCH_IN (PVOID)
-         * Output: None
-         * #define IOCTL_XENIFACE_STORE_REMOVE_WATCH (PVOID)
-         *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x806, METHOD_BUFFERED, FILE_ANY_ACCESS)
-         */
-        self.make_ioctl(
-            ctl_code(FILE_DEVICE_UNKNOWN, 0x806, METHOD_BUFFERED, FILE_ANY_ACCESS),
-            &context.0,
-            None,
-        )?;
+        self.make_ioctl(IOCTL_XENIFACE_STORE_REMOVE_WATCH, &context.0, None)?;
 
         Ok(())
     }
 }
 
-unsafe impl Send for XsWindows {}
-unsafe impl Sync for XsWindows {}
+/// Encode a `XENIFACE_STORE_ADD_WATCH_IN`, which refers to `path`.
+fn add_watch_in(path: &CStr, event: &impl Event) -> Vec<u8> {
+    /*
+     * typedef struct _XENIFACE_STORE_ADD_WATCH_IN {
+     *     PCHAR  Path;       /*!< NUL-terminated path to a XenStore key */
+     *     ULONG  PathLength; /*!< Size of Path in bytes, including the NUL terminator */
+     *     HANDLE Event;      /*!< Handle to an event object that will be signaled when the watch fires */
+     * } XENIFACE_STORE_ADD_WATCH_IN, *PXENIFACE_STORE_ADD_WATCH_IN;
+     */
+    // TODO: Not sure if it would be preferable to use a repr(C) struct.
+    [
+        path.as_ptr().expose_provenance().to_ne_bytes(),
+        path.to_bytes_with_nul().len().to_ne_bytes(),
+        event.raw_handle().to_ne_bytes(),
+    ]
+    .as_flattened()
+    .to_vec()
+}
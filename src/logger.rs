@@ -0,0 +1,326 @@
+//! Logging to dom0.
+//!
+//! xeniface forwards messages to the backend log, which makes guest logs visible to dom0
+//! operators. [`XenLogger`] writes [`log`] records (and `tracing` events with the `tracing`
+//! feature) through it.
+//!
+#[cfg(feature = "tracing")]
+use std::fmt;
+use std::{
+    io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+#[cfg(feature = "tracing")]
+use tracing_core::{
+    Subscriber,
+    field::{Field, Visit},
+};
+#[cfg(feature = "tracing")]
+use tracing_subscriber::{Layer, layer::Context};
+
+use crate::{
+    DefaultTransport, FILE_ANY_ACCESS, FILE_DEVICE_UNKNOWN, METHOD_BUFFERED, Transport, XsWindows,
+    ctl_code,
+};
+
+/* Log a message to dom0
+ * Input: NUL-terminated CHAR array containing the message to log, must be less than
+ *        XENIFACE_LOG_MAX_LENGTH long, and only contain printable or newline characters
+ * Output: None
+ * #define IOCTL_XENIFACE_LOG \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x84F, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_LOG: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x84F, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/// Maximum length of a logged message, without the NUL terminator
+/// (`XENIFACE_LOG_MAX_LENGTH` includes it).
+pub const LOG_MAX_LENGTH: usize = 255;
+
+/// Whether the driver accepts `c` in a message (`isprint(c) || c == '\n'`).
+pub(crate) fn is_loggable(c: u8) -> bool {
+    c == b'\n' || (b' '..=b'~').contains(&c)
+}
+
+/// Build the NUL-terminated payload of `message`, keeping only what the driver accepts.
+fn make_log_payload(message: &str) -> Vec<u8> {
+    let mut payload: Vec<u8> = message
+        .chars()
+        .map(|c| match c {
+            '\t' => b' ',
+            c if c.is_ascii() && is_loggable(c as u8) => c as u8,
+            _ => b'?',
+        })
+        .take(LOG_MAX_LENGTH)
+        .collect();
+
+    payload.push(0);
+    payload
+}
+
+impl<T: Transport> XsWindows<T> {
+    /// Write `message` to the dom0 log.
+    ///
+    /// Characters the driver rejects are replaced with `?` (tabs with spaces), and the message
+    /// is truncated to [`LOG_MAX_LENGTH`].
+    pub fn log(&self, message: &str) -> io::Result<()> {
+        if message.is_empty() {
+            return Ok(());
+        }
+
+        self.make_ioctl(IOCTL_XENIFACE_LOG, &make_log_payload(message), None)?;
+
+        Ok(())
+    }
+}
+
+/// Records logged during the current window of a [`RateLimit`].
+struct Window {
+    start: Instant,
+    count: u32,
+    suppressed: u32,
+}
+
+/// Allows at most `max` records per `interval`.
+struct RateLimit {
+    max: u32,
+    interval: Duration,
+    window: Mutex<Window>,
+}
+
+impl RateLimit {
+    fn new(max: u32, interval: Duration) -> Self {
+        Self {
+            max,
+            interval,
+            window: Mutex::new(Window {
+                start: Instant::now(),
+                count: 0,
+                suppressed: 0,
+            }),
+        }
+    }
+
+    /// Check whether a record can be logged now, if so returns the number of records
+    /// suppressed since the last logged one.
+    fn acquire(&self) -> Option<u32> {
+        let mut window = self.window.lock().unwrap();
+        let now = Instant::now();
+
+        if now.duration_since(window.start) >= self.interval {
+            window.start = now;
+            window.count = 0;
+        }
+
+        if window.count < self.max {
+            window.count += 1;
+            Some(std::mem::take(&mut window.suppressed))
+        } else {
+            window.suppressed += 1;
+            None
+        }
+    }
+}
+
+/// Logger writing to the dom0 log through xeniface.
+///
+/// Records are formatted as `LEVEL target: message`, and rate limited (50 per second by
+/// default). Without a device, or if writing fails, records go to the fallback logger
+/// instead (if any).
+pub struct XenLogger<T: Transport = DefaultTransport> {
+    device: Option<XsWindows<T>>,
+    fallback: Option<Box<dyn Log>>,
+    level: LevelFilter,
+    rate_limit: RateLimit,
+}
+
+#[cfg(windows)]
+impl XenLogger {
+    /// Log through the first working xeniface device (see [`XsWindows::new`]), or only
+    /// to the fallback if there is none.
+    pub fn new() -> Self {
+        Self::with_device(XsWindows::new().ok())
+    }
+}
+
+#[cfg(windows)]
+impl Default for XenLogger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Transport> XenLogger<T> {
+    /// Log through `device`, or only to the fallback if `None`.
+    pub fn with_device(device: Option<XsWindows<T>>) -> Self {
+        Self {
+            device,
+            fallback: None,
+            level: LevelFilter::Info,
+            rate_limit: RateLimit::new(50, Duration::from_secs(1)),
+        }
+    }
+
+    /// Send records to `fallback` when there is no device, or writing to it fails.
+    pub fn with_fallback(mut self, fallback: impl Log + 'static) -> Self {
+        self.fallback = Some(Box::new(fallback));
+        self
+    }
+
+    /// Only log records up to `level` (`Info` by default).
+    pub fn with_level(mut self, level: LevelFilter) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Write at most `max` records per `interval` to the device, the others are dropped.
+    ///
+    /// The number of dropped records is logged once writing is allowed again.
+    pub fn with_rate_limit(mut self, max: u32, interval: Duration) -> Self {
+        self.rate_limit = RateLimit::new(max, interval);
+        self
+    }
+
+    /// Whether records are written to a device (rather than only to the fallback).
+    pub fn has_device(&self) -> bool {
+        self.device.is_some()
+    }
+
+    fn fallback(&self, record: &Record<'_>) {
+        if let Some(fallback) = &self.fallback {
+            fallback.log(record);
+        }
+    }
+
+    fn write(&self, record: &Record<'_>) {
+        if record.level() > self.level {
+            return;
+        }
+
+        let Some(device) = &self.device else {
+            return self.fallback(record);
+        };
+
+        let Some(suppressed) = self.rate_limit.acquire() else {
+            return;
+        };
+
+        if suppressed != 0 {
+            let message = format!(
+                "{} {}: {suppressed} records suppressed",
+                Level::Warn,
+                module_path!()
+            );
+            device.log(&message).ok();
+        }
+
+        let message = format!("{} {}: {}", record.level(), record.target(), record.args());
+        if device.log(&message).is_err() {
+            self.fallback(record);
+        }
+    }
+}
+
+impl<T: Transport> XenLogger<T>
+where
+    Self: Log + 'static,
+{
+    /// Install as the global logger, with [`XenLogger::with_level`] as maximum level.
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        let level = self.level;
+
+        log::set_logger(Box::leak(Box::new(self)))?;
+        log::set_max_level(level);
+
+        Ok(())
+    }
+}
+
+impl<T: Transport> Log for XenLogger<T>
+where
+    XsWindows<T>: Send + Sync,
+{
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        self.write(record);
+    }
+
+    fn flush(&self) {
+        if let Some(fallback) = &self.fallback {
+            fallback.flush();
+        }
+    }
+}
+
+/// Message of a `tracing` event, followed by its other fields.
+#[cfg(feature = "tracing")]
+#[derive(Default)]
+struct EventMessage {
+    message: String,
+    fields: String,
+}
+
+#[cfg(feature = "tracing")]
+impl Visit for EventMessage {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{value}"))
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        use fmt::Write;
+
+        if field.name() == "message" {
+            write!(self.message, "{value:?}").ok();
+        } else {
+            write!(self.fields, " {}={value:?}", field.name()).ok();
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl fmt::Display for EventMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.message, self.fields)
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn log_level(level: tracing_core::Level) -> Level {
+    match level {
+        tracing_core::Level::ERROR => Level::Error,
+        tracing_core::Level::WARN => Level::Warn,
+        tracing_core::Level::INFO => Level::Info,
+        tracing_core::Level::DEBUG => Level::Debug,
+        tracing_core::Level::TRACE => Level::Trace,
+    }
+}
+
+/// Writes events the same way as records, the fallback receives them as [`log`] records.
+#[cfg(feature = "tracing")]
+impl<S: Subscriber, T: Transport + 'static> Layer<S> for XenLogger<T>
+where
+    XsWindows<T>: Send + Sync,
+{
+    fn on_event(&self, event: &tracing_core::Event<'_>, _: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut message = EventMessage::default();
+        event.record(&mut message);
+
+        self.write(
+            &Record::builder()
+                .level(log_level(*metadata.level()))
+                .target(metadata.target())
+                .module_path(metadata.module_path())
+                .file(metadata.file())
+                .line(metadata.line())
+                .args(format_args!("{message}"))
+                .build(),
+        );
+    }
+}
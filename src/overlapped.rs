@@ -0,0 +1,324 @@
+//! Overlapped (asynchronous) ioctls.
+//!
+//! [`Transport::submit`](crate::Transport::submit) starts an ioctl and returns an
+//! [`Overlapped`] operation, which is polled until the driver completes it.
+//! This doesn't depend on a specific runtime.
+//!
+#[cfg(feature = "futures")]
+use std::{ffi::CString, future};
+use std::{
+    io,
+    task::{Context, Poll},
+};
+
+#[cfg(windows)]
+use std::{
+    mem,
+    os::windows::io::{AsRawHandle, OwnedHandle},
+    task::ready,
+};
+
+#[cfg(windows)]
+use windows::Win32::{
+    Foundation::{ERROR_IO_PENDING, HANDLE},
+    System::IO::{CancelIoEx, DeviceIoControl, GetOverlappedResult, OVERLAPPED},
+};
+
+#[cfg(any(windows, feature = "futures"))]
+use crate::Event;
+#[cfg(windows)]
+use crate::transport::EventHandle;
+#[cfg(feature = "futures")]
+use crate::{
+    IOCTL_XENIFACE_STORE_ADD_WATCH, IOCTL_XENIFACE_STORE_DIRECTORY, IOCTL_XENIFACE_STORE_READ,
+    IOCTL_XENIFACE_STORE_REMOVE, IOCTL_XENIFACE_STORE_SET_PERMISSIONS, IOCTL_XENIFACE_STORE_WRITE,
+    MAX_OUTPUT_LEN, Permission, Transport, WatchContext, XsError, XsWindows, add_watch_in,
+    check_write, needs_probe, output_error, parse_directory, parse_value, parse_value_bytes,
+    permission::set_permissions_in,
+    utils::{make_bytes_payload, make_payload},
+};
+
+/// Ioctl in flight.
+///
+/// Dropping it before completion cancels the ioctl.
+pub trait Overlapped: Send + Unpin {
+    /// Poll until the ioctl completes.
+    ///
+    /// Returns the output buffer, truncated to what the driver wrote.
+    fn poll_complete(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Vec<u8>>>;
+}
+
+/// Ioctl that completed when submitted, for transports that are synchronous.
+pub struct Completed(Option<io::Result<Vec<u8>>>);
+
+impl Completed {
+    /// Operation completed with `result`.
+    pub fn new(result: io::Result<Vec<u8>>) -> Self {
+        Self(Some(result))
+    }
+}
+
+impl Overlapped for Completed {
+    fn poll_complete(&mut self, _: &mut Context<'_>) -> Poll<io::Result<Vec<u8>>> {
+        Poll::Ready(self.0.take().expect("polled after completion"))
+    }
+}
+
+/// Overlapped `DeviceIoControl` on a xeniface device, completed through an event.
+#[cfg(windows)]
+pub struct OverlappedIoctl<'a> {
+    device: &'a OwnedHandle,
+    // Boxed as the driver refers to it until completion.
+    overlapped: Box<OVERLAPPED>,
+    event: EventHandle,
+    _in_buffer: Vec<u8>,
+    out_buffer: Vec<u8>,
+    pending: bool,
+}
+
+// SAFETY: OVERLAPPED is only touched by the driver and through the device handle.
+#[cfg(windows)]
+unsafe impl Send for OverlappedIoctl<'_> {}
+
+#[cfg(windows)]
+impl<'a> OverlappedIoctl<'a> {
+    /// See [`Transport::submit`](crate::Transport::submit).
+    pub(crate) unsafe fn submit(
+        device: &'a OwnedHandle,
+        control_code: u32,
+        in_buffer: Vec<u8>,
+        mut out_buffer: Vec<u8>,
+    ) -> io::Result<Self> {
+        let event = EventHandle::new()?;
+        let mut overlapped = Box::new(OVERLAPPED {
+            hEvent: event.as_handle(),
+            ..Default::default()
+        });
+        let out_buffer_len = out_buffer.len();
+
+        let result = unsafe {
+            DeviceIoControl(
+                HANDLE(device.as_raw_handle()),
+                control_code,
+                Some(in_buffer.as_ptr().cast()),
+                in_buffer.len() as u32,
+                (out_buffer_len > 0).then(|| out_buffer.as_mut_ptr().cast()),
+                out_buffer_len as u32,
+                None,
+                Some(&mut *overlapped),
+            )
+        };
+
+        match result {
+            // Completed synchronously, the event is signaled anyway.
+            Ok(()) => (),
+            Err(e) if e.code() == ERROR_IO_PENDING.to_hresult() => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(Self {
+            device,
+            overlapped,
+            event,
+            _in_buffer: in_buffer,
+            out_buffer,
+            pending: true,
+        })
+    }
+
+    fn result(&mut self, wait: bool) -> io::Result<Vec<u8>> {
+        let mut len = 0;
+        let result = unsafe {
+            GetOverlappedResult(
+                HANDLE(self.device.as_raw_handle()),
+                &*self.overlapped,
+                &mut len,
+                wait,
+            )
+        };
+        self.pending = false;
+        result?;
+
+        let mut output = mem::take(&mut self.out_buffer);
+        output.truncate(len as usize);
+        Ok(output)
+    }
+
+    /// Block until the ioctl completes.
+    pub(crate) fn wait(&mut self) -> io::Result<Vec<u8>> {
+        self.result(true)
+    }
+}
+
+#[cfg(windows)]
+impl Overlapped for OverlappedIoctl<'_> {
+    fn poll_complete(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Vec<u8>>> {
+        ready!(self.event.poll_wait(cx))?;
+        Poll::Ready(self.result(false))
+    }
+}
+
+#[cfg(windows)]
+impl Drop for OverlappedIoctl<'_> {
+    fn drop(&mut self) {
+        if self.pending {
+            let handle = HANDLE(self.device.as_raw_handle());
+
+            // The buffers must outlive the ioctl, wait for it to be cancelled.
+            unsafe { CancelIoEx(handle, Some(&*self.overlapped)) }.ok();
+            self.wait().ok();
+        }
+    }
+}
+
+// Store operations of the async backends.
+#[cfg(feature = "futures")]
+impl<T: Transport> XsWindows<T> {
+    // Transport errors are kept as is (Win32 codes).
+    async fn raw_submit(
+        &self,
+        control_code: u32,
+        in_buffer: &[u8],
+        out_len: usize,
+    ) -> io::Result<Vec<u8>> {
+        // Payloads given here are plain NUL-strings, they don't embed any pointer.
+        let mut ioctl = unsafe {
+            self.0
+                .submit(control_code, in_buffer.to_vec(), vec![0; out_len])?
+        };
+
+        future::poll_fn(|cx| ioctl.poll_complete(cx)).await
+    }
+
+    /// Overlapped counterpart of `make_ioctl_output`.
+    async fn submit_output(&self, control_code: u32, in_buffer: &[u8]) -> Result<Vec<u8>, XsError> {
+        let e = match self
+            .raw_submit(control_code, in_buffer, MAX_OUTPUT_LEN)
+            .await
+        {
+            Ok(output) => return Ok(output),
+            Err(e) => e,
+        };
+
+        let probe = if needs_probe(&e) {
+            Some(self.raw_submit(control_code, in_buffer, 0).await)
+        } else {
+            None
+        };
+
+        Err(output_error(e, probe))
+    }
+
+    pub(crate) async fn directory_async(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        let in_buffer = make_payload(&[path]);
+        let out_buffer = self
+            .submit_output(IOCTL_XENIFACE_STORE_DIRECTORY, &in_buffer)
+            .await?;
+
+        Ok(parse_directory(&out_buffer)?)
+    }
+
+    pub(crate) async fn read_async(&self, path: &str) -> io::Result<Box<str>> {
+        let in_buffer = make_payload(&[path]);
+        let out_buffer = self
+            .submit_output(IOCTL_XENIFACE_STORE_READ, &in_buffer)
+            .await?;
+
+        Ok(parse_value(&out_buffer)?)
+    }
+
+    pub(crate) async fn read_bytes_async(&self, path: &str) -> io::Result<Vec<u8>> {
+        let in_buffer = make_payload(&[path]);
+        let out_buffer = self
+            .submit_output(IOCTL_XENIFACE_STORE_READ, &in_buffer)
+            .await?;
+
+        Ok(parse_value_bytes(out_buffer))
+    }
+
+    pub(crate) async fn write_async(&self, path: &str, data: &str) -> io::Result<()> {
+        self.write_bytes_async(path, data.as_bytes()).await
+    }
+
+    pub(crate) async fn write_bytes_async(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        check_write(path, data)?;
+        let in_buffer = make_bytes_payload(&[path.as_bytes(), data]);
+
+        self.raw_submit(IOCTL_XENIFACE_STORE_WRITE, &in_buffer, 0)
+            .await
+            .map_err(XsError::from)?;
+
+        Ok(())
+    }
+
+    pub(crate) async fn rm_async(&self, path: &str) -> io::Result<()> {
+        let in_buffer = make_payload(&[path]);
+
+        self.raw_submit(IOCTL_XENIFACE_STORE_REMOVE, &in_buffer, 0)
+            .await
+            .map_err(XsError::from)?;
+
+        Ok(())
+    }
+
+    pub(crate) async fn set_permissions_async(
+        &self,
+        path: &str,
+        permissions: &[Permission],
+    ) -> io::Result<()> {
+        if permissions.is_empty() {
+            return Err(XsError::InvalidArgument.into());
+        }
+
+        let c_path = CString::new(path)?;
+        let in_buffer = set_permissions_in(&c_path, permissions);
+
+        // c_path is declared first, so it outlives the ioctl even if cancelled.
+        let mut ioctl = unsafe {
+            self.0
+                .submit(IOCTL_XENIFACE_STORE_SET_PERMISSIONS, in_buffer, Vec::new())
+                .map_err(XsError::from)?
+        };
+
+        future::poll_fn(|cx| ioctl.poll_complete(cx))
+            .await
+            .map_err(XsError::from)?;
+
+        Ok(())
+    }
+
+    /// Overlapped counterpart of `make_watch`.
+    ///
+    /// If cancelled once the driver registered it, the watch lingers until the device
+    /// handle is closed.
+    pub(crate) async fn make_watch_async(
+        &self,
+        path: &str,
+    ) -> io::Result<(T::Event, WatchContext)> {
+        let event = self.0.create_event()?;
+        let c_path = CString::new(path)?;
+        let in_buffer = add_watch_in(&c_path, &event);
+        let mut context = WatchContext::default();
+
+        // c_path is declared first, so it outlives the ioctl even if cancelled.
+        let mut ioctl = unsafe {
+            self.0
+                .submit(
+                    IOCTL_XENIFACE_STORE_ADD_WATCH,
+                    in_buffer,
+                    vec![0; context.0.len()],
+                )
+                .map_err(XsError::from)?
+        };
+
+        let output = future::poll_fn(|cx| ioctl.poll_complete(cx))
+            .await
+            .map_err(XsError::from)?;
+        context.0[..output.len()].copy_from_slice(&output);
+
+        // Like xenstored, report an initial event (whether the driver signaled it or not).
+        event.set()?;
+
+        Ok((event, context))
+    }
+}
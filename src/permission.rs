@@ -0,0 +1,270 @@
+//! Xenstore node permissions.
+//!
+//! Permissions are a list of `(domid, access)` entries, where the first entry gives the owner
+//! of the node and the access of domains that are not listed afterward.
+//! They are usually written as a list of `<access><domid>` strings (e.g `n0 r5`).
+//!
+use std::{error::Error, ffi::CStr, fmt, str::FromStr};
+
+/// Access rights of a domain on a node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Access {
+    /// No access (`n`).
+    None,
+    /// Read-only access (`r`).
+    Read,
+    /// Write-only access (`w`).
+    Write,
+    /// Read and write access (`b`).
+    Both,
+}
+
+impl Access {
+    fn letter(self) -> char {
+        match self {
+            Access::None => 'n',
+            Access::Read => 'r',
+            Access::Write => 'w',
+            Access::Both => 'b',
+        }
+    }
+
+    fn from_letter(letter: char) -> Option<Self> {
+        match letter {
+            'n' => Some(Access::None),
+            'r' => Some(Access::Read),
+            'w' => Some(Access::Write),
+            'b' => Some(Access::Both),
+            _ => None,
+        }
+    }
+
+    // XENIFACE_STORE_PERM_{NONE,READ,WRITE}
+    pub(crate) fn mask(self) -> u32 {
+        match self {
+            Access::None => 0,
+            Access::Read => 1,
+            Access::Write => 2,
+            Access::Both => 1 | 2,
+        }
+    }
+
+    #[cfg(feature = "emulator")]
+    pub(crate) fn from_mask(mask: u32) -> Option<Self> {
+        match mask {
+            0 => Some(Access::None),
+            1 => Some(Access::Read),
+            2 => Some(Access::Write),
+            3 => Some(Access::Both),
+            _ => None,
+        }
+    }
+
+    /// Whether this access allows reading.
+    pub fn can_read(self) -> bool {
+        matches!(self, Access::Read | Access::Both)
+    }
+
+    /// Whether this access allows writing.
+    pub fn can_write(self) -> bool {
+        matches!(self, Access::Write | Access::Both)
+    }
+}
+
+/// Permission entry of a domain (e.g `r5`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Permission {
+    /// Domain this entry applies to.
+    pub domid: u16,
+    /// Access of the domain.
+    pub access: Access,
+}
+
+impl Permission {
+    /// Create a permission entry.
+    pub fn new(domid: u16, access: Access) -> Self {
+        Self { domid, access }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.access.letter(), self.domid)
+    }
+}
+
+/// Error returned when parsing an invalid [`Permission`] or [`PermissionSet`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParsePermissionError(Box<str>);
+
+impl fmt::Display for ParsePermissionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid permission {:?}", self.0)
+    }
+}
+
+impl Error for ParsePermissionError {}
+
+impl FromStr for Permission {
+    type Err = ParsePermissionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParsePermissionError(s.into());
+        let mut chars = s.chars();
+
+        let access = chars
+            .next()
+            .and_then(Access::from_letter)
+            .ok_or_else(error)?;
+        let domid = chars.as_str();
+
+        // Reject signs and such that u16::from_str accepts.
+        if domid.is_empty() || !domid.bytes().all(|c| c.is_ascii_digit()) {
+            return Err(error());
+        }
+
+        Ok(Self {
+            domid: domid.parse().map_err(|_| error())?,
+            access,
+        })
+    }
+}
+
+/// Permissions of a node.
+///
+/// The first entry is the owner of the node, its access also applies to all
+/// domains that aren't listed.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PermissionSet(Vec<Permission>);
+
+impl PermissionSet {
+    /// Permissions of a node owned by `owner`, with `default` access for other domains.
+    pub fn new(owner: u16, default: Access) -> Self {
+        Self(vec![Permission::new(owner, default)])
+    }
+
+    /// Add (or replace) the access of `domid`.
+    pub fn with(mut self, domid: u16, access: Access) -> Self {
+        match self.0[1..].iter_mut().find(|p| p.domid == domid) {
+            Some(permission) => permission.access = access,
+            None => self.0.push(Permission::new(domid, access)),
+        }
+
+        self
+    }
+
+    /// Change the owner of the node, keeping other entries.
+    pub fn with_owner(mut self, owner: u16) -> Self {
+        self.0[0].domid = owner;
+        self
+    }
+
+    /// Owner of the node.
+    pub fn owner(&self) -> u16 {
+        self.0[0].domid
+    }
+
+    /// Access of domains that are not listed.
+    pub fn default_access(&self) -> Access {
+        self.0[0].access
+    }
+
+    /// Access of `domid` on the node.
+    ///
+    /// The owner has full access regardless of the permissions.
+    pub fn access(&self, domid: u16) -> Access {
+        if domid == self.owner() {
+            return Access::Both;
+        }
+
+        self.0[1..]
+            .iter()
+            .find(|p| p.domid == domid)
+            .map_or(self.default_access(), |p| p.access)
+    }
+
+    /// Permission entries, starting with the owner one.
+    pub fn as_slice(&self) -> &[Permission] {
+        &self.0
+    }
+}
+
+impl TryFrom<Vec<Permission>> for PermissionSet {
+    type Error = ParsePermissionError;
+
+    /// Fails if there is no owner entry.
+    fn try_from(permissions: Vec<Permission>) -> Result<Self, Self::Error> {
+        if permissions.is_empty() {
+            return Err(ParsePermissionError("".into()));
+        }
+
+        Ok(Self(permissions))
+    }
+}
+
+impl AsRef<[Permission]> for PermissionSet {
+    fn as_ref(&self) -> &[Permission] {
+        &self.0
+    }
+}
+
+impl fmt::Display for PermissionSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, permission) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            permission.fmt(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for PermissionSet {
+    type Err = ParsePermissionError;
+
+    /// Parse a comma or whitespace separated list of permissions (e.g `n0,r5` or `n0 r5`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|entry| !entry.is_empty())
+            .map(Permission::from_str)
+            .collect::<Result<Vec<_>, _>>()?
+            .try_into()
+    }
+}
+
+/*
+ * typedef struct _XENIFACE_STORE_PERMISSION {
+ *     USHORT Domain;
+ *     ULONG  Mask;
+ * } XENIFACE_STORE_PERMISSION, *PXENIFACE_STORE_PERMISSION;
+ *
+ * typedef struct _XENIFACE_STORE_SET_PERMISSIONS_IN {
+ *     PCHAR Path;               /*!< NUL-terminated path to a XenStore key */
+ *     ULONG PathLength;         /*!< Size of Path in bytes, including the NUL terminator */
+ *     ULONG NumberPermissions;  /*!< Number of permissions */
+ *     XENIFACE_STORE_PERMISSION Permissions[ANYSIZE_ARRAY]; /*!< Permissions */
+ * } XENIFACE_STORE_SET_PERMISSIONS_IN, *PXENIFACE_STORE_SET_PERMISSIONS_IN;
+ */
+pub(crate) const SET_PERMISSIONS_IN_HEADER_LEN: usize = size_of::<usize>() + 2 * size_of::<u32>();
+pub(crate) const STORE_PERMISSION_LEN: usize = 8;
+
+/// Encode a `XENIFACE_STORE_SET_PERMISSIONS_IN`, which refers to `path`.
+pub(crate) fn set_permissions_in(path: &CStr, permissions: &[Permission]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(
+        SET_PERMISSIONS_IN_HEADER_LEN + permissions.len() * STORE_PERMISSION_LEN,
+    );
+
+    bytes.extend(path.as_ptr().expose_provenance().to_ne_bytes());
+    bytes.extend((path.to_bytes_with_nul().len() as u32).to_ne_bytes());
+    bytes.extend((permissions.len() as u32).to_ne_bytes());
+
+    for permission in permissions {
+        bytes.extend(permission.domid.to_ne_bytes());
+        bytes.extend([0; 2]); // padding
+        bytes.extend(permission.access.mask().to_ne_bytes());
+    }
+
+    bytes
+}
@@ -0,0 +1,253 @@
+//! Reconnection to xeniface.
+//!
+//! A device handle goes stale forever once the device is removed (e.g driver upgrade, or
+//! device disabled and enabled back). [`ReconnectingXs`] opens the device again when that
+//! happens, registers the active watches again, and retries the failed operation.
+//!
+use std::{
+    cell::Cell,
+    io,
+    sync::{Arc, Mutex, RwLock, mpsc},
+};
+
+#[cfg(feature = "futures")]
+use futures::channel::mpsc as async_mpsc;
+use xenstore_rs::Xs;
+
+use crate::{DefaultTransport, Permission, Transport, Watch, XsError, XsWindows, watch::Watches};
+
+/// Connection state of a [`ReconnectingXs`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ConnectionStatus {
+    /// A device is opened.
+    Connected,
+    /// The device is gone, and couldn't be opened again yet.
+    Disconnected,
+}
+
+/// Opens the device, on connection and reconnection.
+type Opener<T> = Box<dyn Fn() -> io::Result<T> + Send + Sync>;
+
+struct Connection<T: Transport> {
+    device: Option<XsWindows<T>>,
+    /// Bumped on each connection, so that concurrent failures only reconnect once.
+    generation: u64,
+}
+
+/// Receiver of [`ConnectionStatus`] transitions.
+enum Subscriber {
+    Blocking(mpsc::Sender<ConnectionStatus>),
+    #[cfg(feature = "futures")]
+    Async(async_mpsc::UnboundedSender<ConnectionStatus>),
+}
+
+impl Subscriber {
+    /// Returns whether the receiver is still there.
+    fn send(&self, status: ConnectionStatus) -> bool {
+        match self {
+            Self::Blocking(sender) => sender.send(status).is_ok(),
+            #[cfg(feature = "futures")]
+            Self::Async(sender) => sender.unbounded_send(status).is_ok(),
+        }
+    }
+}
+
+/// Xenstore through a xeniface device that is opened again when removed.
+///
+/// Operations failing because the device is gone are retried once reconnected, watches
+/// created with [`ReconnectingXs::watch`] are registered again and fire. The failed attempt
+/// may have been applied, so a retried `rm` succeeds if the node is already gone.
+/// Watches alone don't notice the removal, see [`ReconnectingXs::check`].
+///
+/// Other objects (event channels, grants, suspend watchers...) are bound to the device they
+/// were created on, and must be created again.
+pub struct ReconnectingXs<T: Transport = DefaultTransport> {
+    open: Opener<T>,
+    connection: RwLock<Connection<T>>,
+    watches: Arc<Watches<T>>,
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+#[cfg(windows)]
+impl ReconnectingXs {
+    /// Use the first working xeniface device (see [`XsWindows::new`]), the devices are
+    /// enumerated again on reconnection.
+    pub fn new() -> Self {
+        Self::with_opener(|| Ok(XsWindows::new()?.0))
+    }
+}
+
+#[cfg(windows)]
+impl Default for ReconnectingXs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Transport> ReconnectingXs<T> {
+    /// Use the transports given by `open`, which is called again on reconnection.
+    ///
+    /// Starts [`ConnectionStatus::Disconnected`] if `open` fails, it is then retried by the next
+    /// operation.
+    pub fn with_opener(open: impl Fn() -> io::Result<T> + Send + Sync + 'static) -> Self {
+        let xs = Self {
+            open: Box::new(open),
+            connection: RwLock::new(Connection {
+                device: None,
+                generation: 0,
+            }),
+            watches: Arc::default(),
+            subscribers: Mutex::default(),
+        };
+
+        if let Err(e) = xs.connect(0) {
+            log::warn!("Unable to connect to xeniface ({e})");
+        }
+
+        xs
+    }
+
+    /// Current connection state.
+    pub fn status(&self) -> ConnectionStatus {
+        match self.connection.read().unwrap().device {
+            Some(_) => ConnectionStatus::Connected,
+            None => ConnectionStatus::Disconnected,
+        }
+    }
+
+    /// Receive the following [`ConnectionStatus`] transitions.
+    pub fn subscribe(&self) -> mpsc::Receiver<ConnectionStatus> {
+        let (sender, receiver) = mpsc::channel();
+
+        self.subscribers
+            .lock()
+            .unwrap()
+            .push(Subscriber::Blocking(sender));
+
+        receiver
+    }
+
+    /// Async counterpart of [`ReconnectingXs::subscribe`].
+    #[cfg(feature = "futures")]
+    pub fn subscribe_stream(&self) -> async_mpsc::UnboundedReceiver<ConnectionStatus> {
+        let (sender, receiver) = async_mpsc::unbounded();
+
+        self.subscribers
+            .lock()
+            .unwrap()
+            .push(Subscriber::Async(sender));
+
+        receiver
+    }
+
+    fn publish(&self, status: ConnectionStatus) {
+        log::info!("xeniface {status:?}");
+
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(status));
+    }
+
+    /// Open the device again, unless it was already done since `generation`.
+    fn connect(&self, generation: u64) -> io::Result<()> {
+        let mut connection = self.connection.write().unwrap();
+
+        if connection.generation != generation {
+            return Ok(());
+        }
+
+        if connection.device.take().is_some() {
+            self.publish(ConnectionStatus::Disconnected);
+        }
+
+        let device = XsWindows((self.open)()?, self.watches.clone());
+        device.rewatch()?;
+
+        connection.device = Some(device);
+        connection.generation += 1;
+        self.publish(ConnectionStatus::Connected);
+
+        Ok(())
+    }
+
+    /// Run `op` on the current device, along with its generation.
+    fn try_device<R>(&self, op: &impl Fn(&XsWindows<T>) -> io::Result<R>) -> (io::Result<R>, u64) {
+        let connection = self.connection.read().unwrap();
+
+        let result = match &connection.device {
+            Some(device) => op(device),
+            None => Err(XsError::DeviceGone.into()),
+        };
+
+        (result, connection.generation)
+    }
+
+    /// Run `op`, reconnecting and retrying once if the device is gone.
+    ///
+    /// Fails with [`XsError::DeviceGone`] if the device can't be opened again, as the opener
+    /// error (e.g `ERROR_FILE_NOT_FOUND` while unplugged) would be mistaken for the one of `op`.
+    fn with_device<R>(&self, op: impl Fn(&XsWindows<T>) -> io::Result<R>) -> io::Result<R> {
+        match self.try_device(&op) {
+            (Err(e), generation) if XsError::matches(&e, |e| matches!(e, XsError::DeviceGone)) => {
+                if let Err(e) = self.connect(generation) {
+                    log::debug!("Unable to reconnect to xeniface ({e})");
+                    return Err(XsError::DeviceGone.into());
+                }
+
+                self.try_device(&op).0
+            }
+            (result, _) => result,
+        }
+    }
+
+    /// Check that the device is still there, reconnecting if it isn't.
+    ///
+    /// Call it periodically when only waiting for watches, which would otherwise stay silent.
+    pub fn check(&self) -> io::Result<()> {
+        self.with_device(|device| device.suspend_count().map(drop))
+    }
+
+    /// Watch `path` and its subtree (see [`XsWindows::watch`]), across reconnections.
+    pub fn watch(&self, path: &str) -> io::Result<Watch<T>> {
+        self.with_device(|device| device.watch(path))
+    }
+
+    /// Set the permissions of a node (see [`XsWindows::set_permissions`]).
+    pub fn set_permissions(&self, path: &str, permissions: &[Permission]) -> io::Result<()> {
+        self.with_device(|device| device.set_permissions(path, permissions))
+    }
+
+    /// Clone of the current device, for other operations.
+    ///
+    /// It isn't reconnected, objects created from it are bound to the current device.
+    pub fn device(&self) -> io::Result<XsWindows<T>> {
+        self.with_device(XsWindows::try_clone)
+    }
+}
+
+impl<T: Transport> Xs for ReconnectingXs<T> {
+    fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        self.with_device(|device| device.directory(path))
+    }
+
+    fn read(&self, path: &str) -> io::Result<Box<str>> {
+        self.with_device(|device| device.read(path))
+    }
+
+    fn write(&self, path: &str, data: &str) -> io::Result<()> {
+        self.with_device(|device| device.write(path, data))
+    }
+
+    fn rm(&self, path: &str) -> io::Result<()> {
+        let retried = Cell::new(false);
+
+        self.with_device(|device| match device.rm(path) {
+            Err(e) if retried.get() && e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => {
+                retried.set(true);
+                result
+            }
+        })
+    }
+}
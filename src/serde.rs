@@ -0,0 +1,1089 @@
+//! Serde support.
+//!
+//! [`from_xs`] and [`to_xs`] map Rust values onto xenstore subtrees:
+//! - structs and maps are directories, with a child node per field or entry;
+//! - numbers, strings and chars are node values, in their string form;
+//! - bools are `1` and `0` (`true` and `false` are accepted too when reading);
+//! - sequences (`Vec`, tuples...) are numbered children (`0`, `1`...), without gaps;
+//! - `Option` is the presence of the node, [`to_xs`] removes it for `None` (which sequence
+//!   elements can't be);
+//! - unit enum variants are values, other variants a child named after the variant.
+//!
+//! ```ignore
+//! #[derive(Deserialize)]
+//! struct Vif {
+//!     backend: String,
+//!     #[serde(rename = "backend-id")]
+//!     backend_id: u16,
+//!     mac: String,
+//!     #[serde(rename = "feature-rx-copy")]
+//!     rx_copy: Option<bool>,
+//! }
+//!
+//! let vif: Vif = from_xs(&xs, "device/vif/0")?;
+//! ```
+//!
+//! The subtree is read as a whole before being deserialized. The writes of [`to_xs`] aren't
+//! atomic, nor do they remove the nodes that aren't part of the value (but the numbered
+//! children past the end of a sequence, and the subtree of an enum, which holds a single
+//! variant).
+//!
+use std::{
+    collections::{BTreeMap, btree_map},
+    error, fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    str::{self, FromStr},
+};
+
+use serde::{
+    Serialize,
+    de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor},
+    forward_to_deserialize_any,
+    ser::{self, Impossible},
+};
+use xenstore_rs::{AsyncXs, Xs};
+
+use crate::{domain::valid_segment, transaction::optional, walk::child_path};
+
+/// Error while mapping a value, turned into an [`io::Error`] of the same kind.
+#[derive(Debug)]
+struct Error {
+    kind: io::ErrorKind,
+    message: String,
+}
+
+impl Error {
+    fn not_found(path: &str) -> Self {
+        Self {
+            kind: io::ErrorKind::NotFound,
+            message: format!("missing node {path}"),
+        }
+    }
+
+    fn invalid_data(message: String) -> Self {
+        Self {
+            kind: io::ErrorKind::InvalidData,
+            message,
+        }
+    }
+
+    fn invalid_input(message: String) -> Self {
+        Self {
+            kind: io::ErrorKind::InvalidInput,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Self::invalid_data(message.to_string())
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Self::invalid_input(message.to_string())
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        io::Error::new(e.kind, e.message)
+    }
+}
+
+/// Node of a subtree read from xenstore.
+struct Node {
+    value: Box<str>,
+    children: BTreeMap<Box<str>, Node>,
+}
+
+/// Read the subtree of `path`, `None` if it doesn't exist.
+fn fetch(xs: &(impl Xs + ?Sized), path: &str) -> io::Result<Option<Node>> {
+    let Some(value) = optional(xs.read(path))? else {
+        return Ok(None);
+    };
+    let mut children = BTreeMap::new();
+
+    for child in optional(xs.directory(path))?.unwrap_or_default() {
+        // Removed while reading, skip it like a missing field.
+        if let Some(node) = fetch(xs, &child_path(path, &child))? {
+            children.insert(child, node);
+        }
+    }
+
+    Ok(Some(Node { value, children }))
+}
+
+type FetchFuture<'a> = Pin<Box<dyn Future<Output = io::Result<Option<Node>>> + Send + 'a>>;
+
+/// Async counterpart of [`fetch`].
+fn fetch_async<X: AsyncXs + Sync + ?Sized>(xs: &X, path: Box<str>) -> FetchFuture<'_> {
+    Box::pin(async move {
+        let Some(value) = optional(xs.read(&path).await)? else {
+            return Ok(None);
+        };
+        let mut children = BTreeMap::new();
+
+        for child in optional(xs.directory(&path).await)?.unwrap_or_default() {
+            if let Some(node) = fetch_async(xs, child_path(&path, &child)).await? {
+                children.insert(child, node);
+            }
+        }
+
+        Ok(Some(Node { value, children }))
+    })
+}
+
+/// Read the subtree of `path` as a `T` (see the [module](self) for the mapping).
+///
+/// Fails with [`io::ErrorKind::NotFound`] if `path` doesn't exist (and `T` isn't an
+/// `Option`), and [`io::ErrorKind::InvalidData`] if the subtree doesn't match `T`.
+pub fn from_xs<T: DeserializeOwned>(xs: &(impl Xs + ?Sized), path: &str) -> io::Result<T> {
+    let node = fetch(xs, path)?;
+
+    Ok(T::deserialize(NodeDeserializer::new(
+        node.as_ref(),
+        path.into(),
+    ))?)
+}
+
+/// Async counterpart of [`from_xs`].
+pub async fn from_xs_async<T: DeserializeOwned>(
+    xs: &(impl AsyncXs + Sync + ?Sized),
+    path: &str,
+) -> io::Result<T> {
+    let node = fetch_async(xs, path.into()).await?;
+
+    Ok(T::deserialize(NodeDeserializer::new(
+        node.as_ref(),
+        path.into(),
+    ))?)
+}
+
+/// Write `value` as the subtree of `path` (see the [module](self) for the mapping).
+///
+/// Fails with [`io::ErrorKind::InvalidInput`] if `value` can't be mapped (e.g map keys that
+/// aren't strings or numbers, or valid node names), before writing anything.
+pub fn to_xs<T: Serialize + ?Sized>(
+    xs: &(impl Xs + ?Sized),
+    path: &str,
+    value: &T,
+) -> io::Result<()> {
+    for op in serialize(path, value)? {
+        match op {
+            Op::Write(path, value) => xs.write(&path, &value)?,
+            Op::Remove(path) => optional(xs.rm(&path)).map(drop)?,
+            Op::Truncate(path, len) => {
+                for child in optional(xs.directory(&path))?.unwrap_or_default() {
+                    if past_end(&child, len) {
+                        optional(xs.rm(&child_path(&path, &child)))?;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Async counterpart of [`to_xs`].
+pub async fn to_xs_async<T: Serialize + ?Sized>(
+    xs: &(impl AsyncXs + Sync + ?Sized),
+    path: &str,
+    value: &T,
+) -> io::Result<()> {
+    for op in serialize(path, value)? {
+        match op {
+            Op::Write(path, value) => xs.write(&path, &value).await?,
+            Op::Remove(path) => optional(xs.rm(&path).await).map(drop)?,
+            Op::Truncate(path, len) => {
+                for child in optional(xs.directory(&path).await)?.unwrap_or_default() {
+                    if past_end(&child, len) {
+                        optional(xs.rm(&child_path(&path, &child)).await)?;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Deserializer of a (possibly missing) node.
+struct NodeDeserializer<'de> {
+    node: Option<&'de Node>,
+    path: Box<str>,
+}
+
+impl<'de> NodeDeserializer<'de> {
+    fn new(node: Option<&'de Node>, path: Box<str>) -> Self {
+        Self { node, path }
+    }
+
+    fn node(&self) -> Result<&'de Node, Error> {
+        self.node.ok_or_else(|| Error::not_found(&self.path))
+    }
+
+    fn value(&self) -> Result<&'de str, Error> {
+        Ok(&self.node()?.value)
+    }
+
+    fn parse<T: FromStr<Err: fmt::Display>>(&self) -> Result<T, Error> {
+        let value = self.value()?;
+
+        value.parse().map_err(|e| {
+            Error::invalid_data(format!("invalid value {value:?} of {} ({e})", self.path))
+        })
+    }
+}
+
+macro_rules! deserialize_parse {
+    ($($deserialize:ident => $visit:ident,)*) => {
+        $(
+            fn $deserialize<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                visitor.$visit(self.parse()?)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for NodeDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.node()?.children.is_empty() {
+            true => self.deserialize_str(visitor),
+            false => self.deserialize_map(visitor),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value()? {
+            "1" | "true" => visitor.visit_bool(true),
+            "0" | "false" => visitor.visit_bool(false),
+            value => Err(Error::invalid_data(format!(
+                "invalid bool {value:?} of {}",
+                self.path
+            ))),
+        }
+    }
+
+    deserialize_parse! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_i128 => visit_i128,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_u128 => visit_u128,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_str(self.value()?)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_bytes(self.value()?.as_bytes())
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.node {
+            Some(_) => visitor.visit_some(self),
+            None => visitor.visit_none(),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.node()?;
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(SeqAccess {
+            node: self.node()?,
+            path: self.path,
+            index: 0,
+        })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_map(MapAccess {
+            children: self.node()?.children.iter(),
+            path: self.path,
+            value: None,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let node = self.node()?;
+        let mut children = node.children.iter();
+
+        match (children.next(), children.next()) {
+            (None, _) => visitor.visit_enum(node.value.as_ref().into_deserializer()),
+            (Some((variant, node)), None) => visitor.visit_enum(EnumAccess {
+                variant,
+                content: NodeDeserializer::new(Some(node), child_path(&self.path, variant)),
+            }),
+            (Some(_), Some(_)) => Err(Error::invalid_data(format!(
+                "more than one enum variant under {}",
+                self.path
+            ))),
+        }
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+}
+
+/// Name of a child, as a map key or an enum variant.
+struct KeyDeserializer<'de>(&'de str);
+
+impl<'de> KeyDeserializer<'de> {
+    fn parse<T: FromStr<Err: fmt::Display>>(&self) -> Result<T, Error> {
+        self.0
+            .parse()
+            .map_err(|e| Error::invalid_data(format!("invalid key {:?} ({e})", self.0)))
+    }
+}
+
+impl<'de> de::Deserializer<'de> for KeyDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_str(self.0)
+    }
+
+    deserialize_parse! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_i128 => visit_i128,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_u128 => visit_u128,
+        deserialize_char => visit_char,
+    }
+
+    forward_to_deserialize_any! {
+        bool f32 f64 str string bytes byte_buf option unit unit_struct newtype_struct seq
+        tuple tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+struct SeqAccess<'de> {
+    node: &'de Node,
+    path: Box<str>,
+    index: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for SeqAccess<'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        let name = self.index.to_string();
+        let Some(node) = self.node.children.get(name.as_str()) else {
+            // The elements after a gap would be dropped.
+            if self
+                .node
+                .children
+                .keys()
+                .any(|child| past_end(child, self.index))
+            {
+                return Err(Error::invalid_data(format!(
+                    "missing sequence element {}",
+                    child_path(&self.path, &name)
+                )));
+            }
+
+            return Ok(None);
+        };
+
+        self.index += 1;
+        seed.deserialize(NodeDeserializer::new(
+            Some(node),
+            child_path(&self.path, &name),
+        ))
+        .map(Some)
+    }
+}
+
+struct MapAccess<'de> {
+    children: btree_map::Iter<'de, Box<str>, Node>,
+    path: Box<str>,
+    /// Child whose key was just deserialized.
+    value: Option<(&'de str, &'de Node)>,
+}
+
+impl<'de> de::MapAccess<'de> for MapAccess<'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        let Some((name, node)) = self.children.next() else {
+            return Ok(None);
+        };
+
+        self.value = Some((name, node));
+        seed.deserialize(KeyDeserializer(name)).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let (name, node) = self
+            .value
+            .take()
+            .expect("next_value_seed should follow next_key_seed");
+
+        seed.deserialize(NodeDeserializer::new(
+            Some(node),
+            child_path(&self.path, name),
+        ))
+    }
+}
+
+/// Variant given by a child node.
+struct EnumAccess<'de> {
+    variant: &'de str,
+    content: NodeDeserializer<'de>,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumAccess<'de> {
+    type Error = Error;
+    type Variant = NodeDeserializer<'de>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, NodeDeserializer<'de>), Error> {
+        Ok((
+            seed.deserialize(KeyDeserializer(self.variant))?,
+            self.content,
+        ))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for NodeDeserializer<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+/// Operation to apply on xenstore.
+enum Op {
+    Write(Box<str>, String),
+    /// Remove a node, if it exists.
+    Remove(Box<str>),
+    /// Remove the numbered children from the given index.
+    Truncate(Box<str>, usize),
+}
+
+fn past_end(child: &str, len: usize) -> bool {
+    child.parse::<usize>().is_ok_and(|index| index >= len)
+}
+
+/// Turn `value` into the operations writing it at `path`.
+fn serialize<T: Serialize + ?Sized>(path: &str, value: &T) -> Result<Vec<Op>, Error> {
+    let mut ops = Vec::new();
+
+    value.serialize(NodeSerializer {
+        path: path.into(),
+        ops: &mut ops,
+        element: false,
+    })?;
+
+    Ok(ops)
+}
+
+/// Serializer of a node.
+struct NodeSerializer<'a> {
+    path: Box<str>,
+    ops: &'a mut Vec<Op>,
+    /// Sequence element, which can't be missing (reading stops at the first gap).
+    element: bool,
+}
+
+impl<'a> NodeSerializer<'a> {
+    fn write(self, value: String) -> Result<(), Error> {
+        self.ops.push(Op::Write(self.path, value));
+        Ok(())
+    }
+
+    /// Create the node as a directory.
+    fn directory(self) -> DirectorySerializer<'a> {
+        self.ops.push(Op::Write(self.path.clone(), String::new()));
+
+        DirectorySerializer {
+            path: self.path,
+            ops: self.ops,
+            len: 0,
+            key: None,
+        }
+    }
+
+    fn child(self, name: &str) -> Self {
+        Self {
+            path: child_path(&self.path, name),
+            ops: self.ops,
+            element: false,
+        }
+    }
+
+    /// Remove the node first, so that the child of a previous variant doesn't stay.
+    fn variant(self) -> Self {
+        self.ops.push(Op::Remove(self.path.clone()));
+        self
+    }
+}
+
+macro_rules! serialize_display {
+    ($($serialize:ident($type:ty),)*) => {
+        $(
+            fn $serialize(self, value: $type) -> Result<(), Error> {
+                self.write(value.to_string())
+            }
+        )*
+    };
+}
+
+impl<'a> ser::Serializer for NodeSerializer<'a> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = DirectorySerializer<'a>;
+    type SerializeTuple = DirectorySerializer<'a>;
+    type SerializeTupleStruct = DirectorySerializer<'a>;
+    type SerializeTupleVariant = DirectorySerializer<'a>;
+    type SerializeMap = DirectorySerializer<'a>;
+    type SerializeStruct = DirectorySerializer<'a>;
+    type SerializeStructVariant = DirectorySerializer<'a>;
+
+    fn serialize_bool(self, value: bool) -> Result<(), Error> {
+        self.write(if value { "1" } else { "0" }.to_string())
+    }
+
+    serialize_display! {
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_i128(i128),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_u128(u128),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+    }
+
+    fn serialize_bytes(self, value: &[u8]) -> Result<(), Error> {
+        match str::from_utf8(value) {
+            Ok(value) => self.write(value.to_string()),
+            Err(_) => Err(Error::invalid_input(format!(
+                "non UTF-8 bytes for {}",
+                self.path
+            ))),
+        }
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        if self.element {
+            return Err(Error::invalid_input(format!(
+                "missing sequence element {}",
+                self.path
+            )));
+        }
+
+        self.ops.push(Op::Remove(self.path));
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        self.write(String::new())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<(), Error> {
+        self.variant().write(variant.to_string())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self.variant().child(variant))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<DirectorySerializer<'a>, Error> {
+        Ok(self.directory())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<DirectorySerializer<'a>, Error> {
+        Ok(self.directory())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<DirectorySerializer<'a>, Error> {
+        Ok(self.directory())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<DirectorySerializer<'a>, Error> {
+        Ok(self.variant().child(variant).directory())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<DirectorySerializer<'a>, Error> {
+        Ok(self.directory())
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<DirectorySerializer<'a>, Error> {
+        Ok(self.directory())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<DirectorySerializer<'a>, Error> {
+        Ok(self.variant().child(variant).directory())
+    }
+}
+
+/// Serializer of the children of a node.
+struct DirectorySerializer<'a> {
+    path: Box<str>,
+    ops: &'a mut Vec<Op>,
+    /// Number of sequence elements so far.
+    len: usize,
+    /// Map key waiting for its value.
+    key: Option<String>,
+}
+
+impl DirectorySerializer<'_> {
+    fn child(&mut self, name: &str) -> NodeSerializer<'_> {
+        NodeSerializer {
+            path: child_path(&self.path, name),
+            ops: self.ops,
+            element: false,
+        }
+    }
+
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let name = self.len.to_string();
+        self.len += 1;
+
+        value.serialize(NodeSerializer {
+            element: true,
+            ..self.child(&name)
+        })
+    }
+
+    /// End of a sequence, remove the elements of a longer one.
+    fn truncate(self) -> Result<(), Error> {
+        self.ops.push(Op::Truncate(self.path, self.len));
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for DirectorySerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.truncate()
+    }
+}
+
+impl ser::SerializeTuple for DirectorySerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.truncate()
+    }
+}
+
+impl ser::SerializeTupleStruct for DirectorySerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.truncate()
+    }
+}
+
+impl ser::SerializeTupleVariant for DirectorySerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.truncate()
+    }
+}
+
+impl ser::SerializeMap for DirectorySerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        let key = key.serialize(KeySerializer)?;
+
+        // Used as is in the path of the child.
+        if !valid_segment(&key) {
+            return Err(Error::invalid_input(format!(
+                "invalid map key {key:?} for {}",
+                self.path
+            )));
+        }
+
+        self.key = Some(key);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .expect("serialize_value should follow serialize_key");
+
+        value.serialize(self.child(&key))
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for DirectorySerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self.child(key))
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for DirectorySerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self.child(key))
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Serializer of a map key, which must be a string or a number.
+struct KeySerializer;
+
+fn key_error() -> Error {
+    Error::invalid_input("map keys must be strings or numbers".to_string())
+}
+
+macro_rules! serialize_key {
+    ($($serialize:ident($type:ty),)*) => {
+        $(
+            fn $serialize(self, value: $type) -> Result<String, Error> {
+                Ok(value.to_string())
+            }
+        )*
+    };
+}
+
+macro_rules! serialize_key_error {
+    ($($serialize:ident($($type:ty),*),)*) => {
+        $(
+            fn $serialize(self, $(_: $type),*) -> Result<String, Error> {
+                Err(key_error())
+            }
+        )*
+    };
+}
+
+impl ser::Serializer for KeySerializer {
+    type Ok = String;
+    type Error = Error;
+    type SerializeSeq = Impossible<String, Error>;
+    type SerializeTuple = Impossible<String, Error>;
+    type SerializeTupleStruct = Impossible<String, Error>;
+    type SerializeTupleVariant = Impossible<String, Error>;
+    type SerializeMap = Impossible<String, Error>;
+    type SerializeStruct = Impossible<String, Error>;
+    type SerializeStructVariant = Impossible<String, Error>;
+
+    serialize_key! {
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_i128(i128),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_u128(u128),
+        serialize_char(char),
+        serialize_str(&str),
+    }
+
+    serialize_key_error! {
+        serialize_bool(bool),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_bytes(&[u8]),
+        serialize_none(),
+        serialize_unit(),
+        serialize_unit_struct(&'static str),
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<String, Error> {
+        Ok(variant.to_string())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, _value: &T) -> Result<String, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<String, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<String, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Err(key_error())
+    }
+}
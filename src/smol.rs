@@ -1,93 +1,81 @@
-use std::{
-    future::{self, Future},
-    io,
-    os::windows::io::{AsRawHandle, OwnedHandle},
-    pin::Pin,
-    task::{Context, Poll},
-};
-
-use async_io::os::windows::Waitable;
-use futures::{Stream, ready};
-use windows::{
-    Win32::{Foundation::HANDLE, System::Threading::ResetEvent},
-    core::Result,
-};
-use xenstore_rs::{AsyncWatch, AsyncXs, Xs};
-
-use crate::{WatchContext, XsWindows};
-
-pub struct XsSmolWindows(XsWindows);
+use std::io;
 
+use futures::Stream;
+use xenstore_rs::{AsyncWatch, AsyncXs};
+
+#[cfg(windows)]
+use crate::XsError;
+use crate::{DefaultTransport, Permission, Transport, XsWindows, walk::Walk};
+
+pub struct XsSmolWindows<T: Transport = DefaultTransport>(XsWindows<T>);
+
+#[cfg(windows)]
 impl XsSmolWindows {
-    pub async fn new() -> Result<Self> {
+    pub async fn new() -> Result<Self, XsError> {
         Ok(Self(XsWindows::new()?))
     }
 }
 
-// TODO: Find a way to use overlapped IO instead.
-impl AsyncXs for XsSmolWindows {
-    fn directory(&self, path: &str) -> impl Future<Output = io::Result<Vec<Box<str>>>> + Send {
-        future::ready(self.0.directory(path))
+impl<T: Transport> From<XsWindows<T>> for XsSmolWindows<T> {
+    fn from(xs: XsWindows<T>) -> Self {
+        Self(xs)
     }
+}
 
-    fn read(&self, path: &str) -> impl Future<Output = io::Result<Box<str>>> + Send {
-        future::ready(self.0.read(path))
+impl<T: Transport> XsSmolWindows<T> {
+    /// Set the permissions of a node (see [`XsWindows::set_permissions`]).
+    pub async fn set_permissions(&self, path: &str, permissions: &[Permission]) -> io::Result<()> {
+        self.0.set_permissions_async(path, permissions).await
     }
 
-    fn write(&self, path: &str, data: &str) -> impl Future<Output = io::Result<()>> + Send {
-        future::ready(self.0.write(path, data))
+    /// Read the raw value of a node (see [`XsWindows::read_bytes`]).
+    pub async fn read_bytes(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.0.read_bytes_async(path).await
     }
 
-    fn rm(&self, path: &str) -> impl Future<Output = io::Result<()>> + Send {
-        future::ready(self.0.rm(path))
+    /// Read the value of a node, lossily (see [`XsWindows::read_lossy`]).
+    pub async fn read_lossy(&self, path: &str) -> io::Result<String> {
+        let value = self.0.read_bytes_async(path).await?;
+
+        Ok(String::from_utf8_lossy(&value).into_owned())
     }
-}
 
-pub struct XsWindowsWatch {
-    device: XsWindows,
-    waitable: Waitable<OwnedHandle>,
-    context: WatchContext,
-    path: Box<str>,
-}
+    /// Write a raw value (see [`XsWindows::write_bytes`]).
+    pub async fn write_bytes(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.0.write_bytes_async(path, data).await
+    }
 
-impl Stream for XsWindowsWatch {
-    type Item = Box<str>;
-
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        Poll::Ready(ready!(self.waitable.poll_ready(cx)).ok().map(|_| {
-            unsafe {
-                ResetEvent(HANDLE(self.waitable.get_ref().as_raw_handle()))
-                    .inspect_err(|e| log::error!("Unable to reset event handle: {e}"))
-                    .ok()
-            };
-            self.path.clone()
-        }))
+    /// Walk the subtree of `path` (see [`Walk::stream`]).
+    pub fn walk(&self, path: &str) -> Walk<'_, Self> {
+        Walk::new(self, path)
     }
 }
 
-impl Drop for XsWindowsWatch {
-    fn drop(&mut self) {
-        if let Err(e) = self.device.destroy_watch(self.context) {
-            log::warn!("Unable to destroy watch object {e}")
-        }
+// Store operations (and watch registrations) are overlapped ioctls, they don't block the
+// executor.
+impl<T: Transport> AsyncXs for XsSmolWindows<T> {
+    async fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        self.0.directory_async(path).await
+    }
+
+    async fn read(&self, path: &str) -> io::Result<Box<str>> {
+        self.0.read_async(path).await
+    }
+
+    async fn write(&self, path: &str, data: &str) -> io::Result<()> {
+        self.0.write_async(path, data).await
+    }
+
+    async fn rm(&self, path: &str) -> io::Result<()> {
+        self.0.rm_async(path).await
     }
 }
 
-impl AsyncWatch for XsSmolWindows {
+impl<T: Transport + 'static> AsyncWatch for XsSmolWindows<T> {
     async fn watch(
         &self,
         path: &str,
     ) -> io::Result<impl Stream<Item = Box<str>> + Unpin + 'static> {
-        // We want a clone of the device handle to be able to destroy the watch.
-        let device = self.0.try_clone()?;
-        let (event_handle, context) = self.0.make_watch(path)?;
-        let waitable = Waitable::new(event_handle)?;
-
-        Ok(XsWindowsWatch {
-            device,
-            context,
-            waitable,
-            path: path.into(),
-        })
+        Ok(self.0.watch_async(path).await?.into_stream())
     }
 }
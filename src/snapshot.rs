@@ -0,0 +1,305 @@
+//! Subtree export and import.
+//!
+//! A [`Snapshot`] holds the nodes of a subtree, which can be saved as JSON (with the `serde`
+//! feature) or in the `xenstore-ls -f` text format, and written back to a xenstore.
+//!
+//! The JSON format is an object with the `root` path, and the `nodes` below it, by path
+//! relative to the root:
+//! ```json
+//! {
+//!   "root": "/local/domain/1",
+//!   "nodes": {
+//!     "data": "",
+//!     "data/key": "value",
+//!     "name": "guest"
+//!   }
+//! }
+//! ```
+//!
+use std::{collections::BTreeMap, fmt::Write, io, ops::Bound, str};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use xenstore_rs::Xs;
+
+use crate::walk::{Walk, child_path};
+
+/// Whether [`Snapshot::apply`] leaves the nodes that aren't in the snapshot.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ApplyMode {
+    /// Only write the nodes of the snapshot.
+    #[default]
+    Merge,
+    /// Also remove the nodes under the root that aren't in the snapshot (nor parents of
+    /// its nodes).
+    Mirror,
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Path of `path` relative to `root`, if strictly under it.
+fn relative<'a>(root: &str, path: &'a str) -> Option<&'a str> {
+    let relative = path.strip_prefix(root)?;
+    let relative = match root.ends_with('/') {
+        true => relative,
+        false => relative.strip_prefix('/')?,
+    };
+
+    (!relative.is_empty()).then_some(relative)
+}
+
+/// Nodes of a subtree, without its root.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct Snapshot {
+    root: Box<str>,
+    /// Values by path relative to `root`.
+    nodes: BTreeMap<Box<str>, Box<str>>,
+}
+
+impl Snapshot {
+    /// Empty snapshot of `root`.
+    pub fn new(root: &str) -> Self {
+        Self {
+            root: root.into(),
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    /// Capture the subtree of `root`.
+    ///
+    /// Nodes removed while capturing are left out.
+    pub fn capture(xs: &(impl Xs + ?Sized), root: &str) -> io::Result<Self> {
+        let mut snapshot = Self::new(root);
+
+        for entry in Walk::new(xs, root) {
+            let (path, value) = match entry {
+                Ok(entry) => entry,
+                Err(e) if e.error().kind() == io::ErrorKind::NotFound && e.path() != root => {
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            if let Some(relative) = relative(root, &path) {
+                snapshot.nodes.insert(relative.into(), value);
+            }
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Root of the subtree.
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    /// Move the snapshot to another root.
+    pub fn with_root(mut self, root: &str) -> Self {
+        self.root = root.into();
+        self
+    }
+
+    /// Value of a node, by path relative to the root.
+    pub fn get(&self, relative: &str) -> Option<&str> {
+        self.nodes.get(relative).map(AsRef::as_ref)
+    }
+
+    /// Add a node, by path relative to the root.
+    pub fn insert(&mut self, relative: &str, value: &str) {
+        self.nodes.insert(relative.into(), value.into());
+    }
+
+    /// Number of nodes.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Whether there is no node.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Nodes by absolute path, parents first.
+    pub fn iter(&self) -> impl Iterator<Item = (Box<str>, &str)> + '_ {
+        self.nodes
+            .iter()
+            .map(|(relative, value)| (child_path(&self.root, relative), value.as_ref()))
+    }
+
+    /// Write the nodes to `xs`, below the root.
+    ///
+    /// This isn't atomic, a failure leaves the nodes written so far.
+    pub fn apply(&self, xs: &(impl Xs + ?Sized), mode: ApplyMode) -> io::Result<()> {
+        for (path, value) in self.iter() {
+            xs.write(&path, value)?;
+        }
+
+        if mode == ApplyMode::Mirror {
+            // Parents come first, so their subtree is skipped once removed.
+            let mut removed: Option<Box<str>> = None;
+            let mut extra = Vec::new();
+
+            for entry in Walk::new(xs, &self.root) {
+                let path = match entry {
+                    Ok((path, _)) => path,
+                    // Removed meanwhile, which is what we are after.
+                    Err(e) if e.error().kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                };
+
+                if relative(&self.root, &path).is_some_and(|relative| !self.contains(relative)) {
+                    extra.push(path);
+                }
+            }
+
+            for path in extra {
+                if removed
+                    .as_ref()
+                    .is_some_and(|removed| relative(removed, &path).is_some())
+                {
+                    continue;
+                }
+
+                match xs.rm(&path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => removed = Some(path),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether `relative` is a node of the snapshot, or the parent of one.
+    fn contains(&self, relative: &str) -> bool {
+        let prefix = format!("{relative}/");
+
+        self.nodes.contains_key(relative)
+            || self
+                .nodes
+                .range::<str, _>((Bound::Included(prefix.as_str()), Bound::Unbounded))
+                .next()
+                .is_some_and(|(node, _)| node.starts_with(&prefix))
+    }
+
+    /// Save as JSON (see the [module](self)).
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        // Only string keys and values, which can't fail.
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Load from JSON (see the [module](self)).
+    #[cfg(feature = "serde")]
+    pub fn from_json(json: &str) -> io::Result<Self> {
+        serde_json::from_str(json).map_err(|e| invalid_data(e.to_string()))
+    }
+
+    /// Save in the `xenstore-ls -f <root>` format.
+    ///
+    /// One `<path> = "<value>"` line per node, non-printable characters and `\` being
+    /// escaped in the value.
+    pub fn to_xenstore_ls(&self) -> String {
+        let mut text = String::new();
+
+        for (path, value) in self.iter() {
+            text.push_str(&path);
+            text.push_str(" = \"");
+            sanitise_value(&mut text, value);
+            text.push_str("\"\n");
+        }
+
+        text
+    }
+
+    /// Load from the output of `xenstore-ls -f <root>`.
+    pub fn from_xenstore_ls(root: &str, text: &str) -> io::Result<Self> {
+        let mut snapshot = Self::new(root);
+
+        for (number, line) in text.lines().enumerate() {
+            let error = |message: &str| invalid_data(format!("line {}: {message}", number + 1));
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let (path, value) = line
+                .split_once(" = \"")
+                .and_then(|(path, value)| Some((path, value.strip_suffix('"')?)))
+                .ok_or_else(|| error("expected `<path> = \"<value>\"`"))?;
+            let relative = relative(root, path).ok_or_else(|| error("path out of the root"))?;
+            let value = unsanitise_value(value).ok_or_else(|| error("invalid value"))?;
+
+            snapshot.nodes.insert(relative.into(), value.into());
+        }
+
+        Ok(snapshot)
+    }
+}
+
+/// Escape a value like `xenstore-ls` does.
+fn sanitise_value(out: &mut String, value: &str) {
+    for c in value.bytes() {
+        match c {
+            b'\t' => out.push_str("\\t"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\\' => out.push_str("\\\\"),
+            b' '..=b'~' => out.push(c as char),
+            _ => write!(out, "\\{c:03o}").unwrap(), // infallible
+        }
+    }
+}
+
+/// Reverse of [`sanitise_value`], also accepting `\xHH` and shorter octal escapes.
+fn unsanitise_value(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.as_bytes();
+
+    while let Some((&c, rest)) = input.split_first() {
+        input = rest;
+
+        if c != b'\\' {
+            bytes.push(c);
+            continue;
+        }
+
+        let (&escape, rest) = input.split_first()?;
+        input = rest;
+
+        bytes.push(match escape {
+            b't' => b'\t',
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b'\\' | b'"' => escape,
+            b'x' => {
+                let digits = str::from_utf8(input.get(..2)?).ok()?;
+                input = &input[2..];
+                u8::from_str_radix(digits, 16).ok()?
+            }
+            b'0'..=b'7' => {
+                // Up to 3 octal digits.
+                let mut octal = u32::from(escape - b'0');
+
+                for _ in 0..2 {
+                    match input.split_first() {
+                        Some((&digit @ b'0'..=b'7', rest)) => {
+                            octal = octal * 8 + u32::from(digit - b'0');
+                            input = rest;
+                        }
+                        _ => break,
+                    }
+                }
+
+                u8::try_from(octal).ok()?
+            }
+            _ => return None,
+        });
+    }
+
+    String::from_utf8(bytes).ok()
+}
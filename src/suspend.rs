@@ -0,0 +1,246 @@
+//! Suspend and resume notifications.
+//!
+//! The guest may be suspended and resumed at any time (e.g live migration), which
+//! xeniface reports through an event and a suspend count. Stateful protocols (watches,
+//! grants, event channels...) may need to be rebuilt afterward.
+//!
+use std::{
+    io,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+#[cfg(feature = "futures")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+#[cfg(feature = "futures")]
+use futures::Stream;
+
+use crate::{
+    DefaultTransport, Event, FILE_ANY_ACCESS, FILE_DEVICE_UNKNOWN, METHOD_BUFFERED, Transport,
+    XsWindows, ctl_code,
+};
+
+/* Get the current suspend count
+ * Input: None
+ * Output: ULONG
+ * #define IOCTL_XENIFACE_SUSPEND_GET_COUNT \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x830, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_SUSPEND_GET_COUNT: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x830, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/* Register an event which is signaled on resume
+ * Input: XENIFACE_SUSPEND_REGISTER_IN (HANDLE Event)
+ * Output: XENIFACE_SUSPEND_REGISTER_OUT (PVOID Context)
+ * #define IOCTL_XENIFACE_SUSPEND_REGISTER \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x831, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_SUSPEND_REGISTER: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x831, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/* Deregister an event which is signaled on resume
+ * Input: XENIFACE_SUSPEND_REGISTER_OUT (PVOID Context)
+ * Output: None
+ * #define IOCTL_XENIFACE_SUSPEND_DEREGISTER \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x832, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_SUSPEND_DEREGISTER: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x832, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+impl<T: Transport> XsWindows<T> {
+    /// Number of times the guest was suspended (and resumed) since boot.
+    pub fn suspend_count(&self) -> io::Result<u32> {
+        let mut count = [0; size_of::<u32>()];
+
+        self.make_ioctl(IOCTL_XENIFACE_SUSPEND_GET_COUNT, &[], Some(&mut count))?;
+
+        Ok(u32::from_ne_bytes(count))
+    }
+}
+
+/// Receives resumes of the guest, as suspend counts.
+///
+/// Spurious events of the driver are filtered out by checking that the suspend count changed.
+pub struct SuspendWatcher<T: Transport = DefaultTransport> {
+    device: XsWindows<T>,
+    event: T::Event,
+    context: [u8; size_of::<usize>()],
+    count: AtomicU32,
+    rewatch: bool,
+}
+
+impl<T: Transport> SuspendWatcher<T> {
+    /// Register for resumes on `xs`.
+    pub fn new(xs: &XsWindows<T>) -> io::Result<Self> {
+        // We want a clone of the device handle to be able to deregister.
+        let device = xs.try_clone()?;
+        let event = device.0.create_event()?;
+        let mut context = [0; size_of::<usize>()];
+
+        device.make_ioctl(
+            IOCTL_XENIFACE_SUSPEND_REGISTER,
+            &event.raw_handle().to_ne_bytes(),
+            Some(&mut context),
+        )?;
+
+        let watcher = Self {
+            count: AtomicU32::new(0),
+            device,
+            event,
+            context,
+            rewatch: false,
+        };
+        // Read once registered, so that no resume is missed.
+        watcher
+            .count
+            .store(watcher.device.suspend_count()?, Ordering::Relaxed);
+
+        Ok(watcher)
+    }
+
+    /// Register again the watches of `xs` (see [`XsWindows::rewatch`]) before reporting
+    /// a resume.
+    ///
+    /// This only happens while the watcher is received from, it must be driven for the
+    /// watches to keep firing. If registering fails, the error is returned and the next
+    /// receive tries again.
+    pub fn with_rewatch(mut self) -> Self {
+        self.rewatch = true;
+        self
+    }
+
+    /// Suspend count as of the last received resume (or the registration).
+    pub fn suspend_count(&self) -> u32 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Check whether the suspend count changed, after the event was signaled.
+    fn consume(&self) -> io::Result<Option<u32>> {
+        // Reset before reading the count, so that a later resume signals it again.
+        self.event.reset()?;
+        let count = self.device.suspend_count()?;
+
+        let previous = self.count.swap(count, Ordering::Relaxed);
+        if previous == count {
+            return Ok(None);
+        }
+
+        if self.rewatch
+            && let Err(e) = self.device.rewatch()
+        {
+            // Not consumed, so that the next receive retries instead of waiting for
+            // another resume.
+            self.count.store(previous, Ordering::Relaxed);
+            self.event.set()?;
+            return Err(e);
+        }
+
+        Ok(Some(count))
+    }
+
+    /// Poll until the guest is resumed.
+    #[cfg(feature = "futures")]
+    fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<io::Result<u32>> {
+        loop {
+            ready!(self.event.poll_wait(cx))?;
+
+            if let Some(count) = self.consume()? {
+                return Poll::Ready(Ok(count));
+            }
+        }
+    }
+
+    /// Block until the guest is resumed, returns the new suspend count.
+    pub fn recv(&self) -> io::Result<u32> {
+        loop {
+            self.event.wait(None)?;
+
+            if let Some(count) = self.consume()? {
+                return Ok(count);
+            }
+        }
+    }
+
+    /// Block until the guest is resumed, or `timeout` expires.
+    ///
+    /// Returns `None` on timeout.
+    pub fn recv_timeout(&self, timeout: Duration) -> io::Result<Option<u32>> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+
+            if !self.event.wait(Some(timeout))? {
+                return Ok(None);
+            }
+
+            if let Some(count) = self.consume()? {
+                return Ok(Some(count));
+            }
+        }
+    }
+}
+
+impl<T: Transport> Iterator for SuspendWatcher<T> {
+    type Item = u32;
+
+    /// Block until the guest is resumed, stops on error.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv()
+            .inspect_err(|e| log::error!("Unable to wait for resume: {e}"))
+            .ok()
+    }
+}
+
+impl<T: Transport> Drop for SuspendWatcher<T> {
+    fn drop(&mut self) {
+        if let Err(e) =
+            self.device
+                .make_ioctl(IOCTL_XENIFACE_SUSPEND_DEREGISTER, &self.context, None)
+        {
+            log::warn!("Unable to deregister suspend event {e}")
+        }
+    }
+}
+
+impl<T: Transport> SuspendWatcher<T> {
+    /// Turn the watcher into a [`Stream`].
+    #[cfg(feature = "futures")]
+    pub fn into_stream(self) -> ResumeStream<T> {
+        ResumeStream(self)
+    }
+}
+
+/// Async counterpart of [`SuspendWatcher`], created with [`SuspendWatcher::into_stream`].
+///
+/// Yields the suspend count on each resume, stops on error.
+#[cfg(feature = "futures")]
+pub struct ResumeStream<T: Transport = DefaultTransport>(SuspendWatcher<T>);
+
+#[cfg(feature = "futures")]
+impl<T: Transport> ResumeStream<T> {
+    /// Suspend count as of the last received resume (see [`SuspendWatcher::suspend_count`]).
+    pub fn suspend_count(&self) -> u32 {
+        self.0.suspend_count()
+    }
+}
+
+// Never pinned structurally.
+#[cfg(feature = "futures")]
+impl<T: Transport> Unpin for ResumeStream<T> {}
+
+#[cfg(feature = "futures")]
+impl<T: Transport> Stream for ResumeStream<T> {
+    type Item = u32;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(
+            ready!(self.0.poll_recv(cx))
+                .inspect_err(|e| log::error!("Unable to wait for resume: {e}"))
+                .ok(),
+        )
+    }
+}
@@ -0,0 +1,242 @@
+//! Xen wallclock time.
+//!
+//! xeniface reports the wallclock time of the shared-info page, which is maintained by Xen.
+//! Comparing it to the guest clock detects drift without any network time source.
+//!
+use std::{
+    fmt, io, thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use xenstore_rs::Xs;
+
+use crate::{
+    DefaultTransport, FILE_ANY_ACCESS, FILE_DEVICE_UNKNOWN, METHOD_BUFFERED, Transport, XsWindows,
+    ctl_code,
+};
+
+/* Get the current time
+ * Input: None
+ * Output: XENIFACE_SHAREDINFO_GET_TIME_OUT
+ * #define IOCTL_XENIFACE_SHAREDINFO_GET_TIME \
+ *     CTL_CODE(FILE_DEVICE_UNKNOWN, 0x840, METHOD_BUFFERED, FILE_ANY_ACCESS)
+ */
+const IOCTL_XENIFACE_SHAREDINFO_GET_TIME: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x840, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/*
+ * typedef struct _XENIFACE_SHAREDINFO_GET_TIME_OUT {
+ *     FILETIME Time;
+ *     BOOLEAN  Local;
+ * } XENIFACE_SHAREDINFO_GET_TIME_OUT, *PXENIFACE_SHAREDINFO_GET_TIME_OUT;
+ *
+ * FILETIME is { DWORD dwLowDateTime; DWORD dwHighDateTime; }.
+ */
+pub(crate) const GET_TIME_OUT_LEN: usize = 12;
+
+/// FILETIME of the Unix epoch (FILETIMEs count 100ns intervals since 1601-01-01).
+const FILETIME_UNIX_EPOCH: u64 = 116_444_736_000_000_000;
+const FILETIME_TICK_NANOS: u32 = 100;
+const FILETIME_TICKS_PER_SEC: u64 = 10_000_000;
+
+/// Encode a `XENIFACE_SHAREDINFO_GET_TIME_OUT`.
+#[cfg(feature = "emulator")]
+pub(crate) fn get_time_out(time: XenTime) -> [u8; GET_TIME_OUT_LEN] {
+    let mut bytes = [0; GET_TIME_OUT_LEN];
+
+    bytes[..4].copy_from_slice(&(time.filetime as u32).to_ne_bytes());
+    bytes[4..8].copy_from_slice(&((time.filetime >> 32) as u32).to_ne_bytes());
+    bytes[8] = time.local as u8;
+
+    bytes
+}
+
+/// Decode a `XENIFACE_SHAREDINFO_GET_TIME_OUT`.
+fn parse_get_time_out(bytes: &[u8; GET_TIME_OUT_LEN]) -> XenTime {
+    let low = u32::from_ne_bytes(bytes[..4].try_into().unwrap());
+    let high = u32::from_ne_bytes(bytes[4..8].try_into().unwrap());
+
+    XenTime {
+        filetime: (high as u64) << 32 | low as u64,
+        local: bytes[8] != 0,
+    }
+}
+
+/// Wallclock time reported by Xen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct XenTime {
+    filetime: u64,
+    local: bool,
+}
+
+impl XenTime {
+    /// Time from a FILETIME (100ns intervals since 1601-01-01).
+    ///
+    /// `local` tells whether it is a local time, rather than UTC.
+    pub fn from_filetime(filetime: u64, local: bool) -> Self {
+        Self { filetime, local }
+    }
+
+    /// FILETIME value (100ns intervals since 1601-01-01).
+    pub fn filetime(&self) -> u64 {
+        self.filetime
+    }
+
+    /// Whether this is a local time rather than UTC, which depends on the `RealTimeIsUniversal`
+    /// setting of the guest.
+    ///
+    /// [`XenTime::to_system_time`] is then off by the timezone offset.
+    pub fn is_local(&self) -> bool {
+        self.local
+    }
+
+    /// Convert to a [`SystemTime`], as if this was UTC.
+    pub fn to_system_time(&self) -> SystemTime {
+        let since_epoch = |ticks: u64| {
+            Duration::new(
+                ticks / FILETIME_TICKS_PER_SEC,
+                (ticks % FILETIME_TICKS_PER_SEC) as u32 * FILETIME_TICK_NANOS,
+            )
+        };
+
+        match self.filetime.checked_sub(FILETIME_UNIX_EPOCH) {
+            Some(ticks) => UNIX_EPOCH + since_epoch(ticks),
+            None => UNIX_EPOCH - since_epoch(FILETIME_UNIX_EPOCH - self.filetime),
+        }
+    }
+}
+
+impl From<SystemTime> for XenTime {
+    /// UTC time, truncated to 100ns (and saturated to the FILETIME range).
+    fn from(time: SystemTime) -> Self {
+        let ticks = |duration: Duration| {
+            duration.as_secs() * FILETIME_TICKS_PER_SEC
+                + (duration.subsec_nanos() / FILETIME_TICK_NANOS) as u64
+        };
+
+        let filetime = match time.duration_since(UNIX_EPOCH) {
+            Ok(duration) => FILETIME_UNIX_EPOCH.saturating_add(ticks(duration)),
+            Err(e) => FILETIME_UNIX_EPOCH.saturating_sub(ticks(e.duration())),
+        };
+
+        Self::from_filetime(filetime, false)
+    }
+}
+
+impl From<XenTime> for SystemTime {
+    fn from(time: XenTime) -> Self {
+        time.to_system_time()
+    }
+}
+
+impl<T: Transport> XsWindows<T> {
+    /// Wallclock time of Xen.
+    pub fn xen_time(&self) -> io::Result<XenTime> {
+        let mut out_buffer = [0; GET_TIME_OUT_LEN];
+
+        self.make_ioctl(
+            IOCTL_XENIFACE_SHAREDINFO_GET_TIME,
+            &[],
+            Some(&mut out_buffer),
+        )?;
+
+        Ok(parse_get_time_out(&out_buffer))
+    }
+}
+
+/// Skew of the guest clock, relative to Xen's.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Skew {
+    /// Difference between the clocks.
+    pub duration: Duration,
+    /// Whether the guest clock is ahead.
+    pub ahead: bool,
+}
+
+impl Skew {
+    /// Skew of `guest` relative to `xen`.
+    pub fn between(xen: SystemTime, guest: SystemTime) -> Self {
+        match guest.duration_since(xen) {
+            Ok(duration) => Self {
+                duration,
+                ahead: true,
+            },
+            Err(e) => Self {
+                duration: e.duration(),
+                ahead: false,
+            },
+        }
+    }
+
+    /// Signed skew in milliseconds, positive when the guest is ahead (saturated).
+    pub fn as_millis(&self) -> i64 {
+        let millis = i64::try_from(self.duration.as_millis()).unwrap_or(i64::MAX);
+
+        if self.ahead { millis } else { -millis }
+    }
+}
+
+impl fmt::Display for Skew {
+    /// Signed milliseconds (e.g `-1500`).
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_millis())
+    }
+}
+
+/// Periodically compares the guest clock to Xen's, and publishes the skew in xenstore.
+pub struct DriftMonitor<T: Transport = DefaultTransport> {
+    device: XsWindows<T>,
+    key: Box<str>,
+    interval: Duration,
+}
+
+impl<T: Transport> DriftMonitor<T> {
+    /// Default interval between samples.
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// Monitor the guest clock, publishing its skew (see [`Skew`]'s `Display`) at `key`.
+    pub fn new(xs: &XsWindows<T>, key: &str) -> io::Result<Self> {
+        Ok(Self {
+            device: xs.try_clone()?,
+            key: key.into(),
+            interval: Self::DEFAULT_INTERVAL,
+        })
+    }
+
+    /// Sample every `interval`, instead of [`DriftMonitor::DEFAULT_INTERVAL`].
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Compare the clocks once, and publish the skew.
+    ///
+    /// Fails with [`io::ErrorKind::Unsupported`] if the Xen time is local (see
+    /// [`XenTime::is_local`]), as the skew would include the timezone offset.
+    pub fn sample(&self) -> io::Result<Skew> {
+        let xen = self.device.xen_time()?;
+
+        if xen.is_local() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Xen time is local, it can't be compared to the guest clock",
+            ));
+        }
+
+        let skew = Skew::between(xen.to_system_time(), SystemTime::now());
+
+        self.device.write(&self.key, &skew.to_string())?;
+
+        Ok(skew)
+    }
+
+    /// Sample forever, only returns on error.
+    pub fn run(&self) -> io::Result<()> {
+        loop {
+            let skew = self.sample()?;
+            log::debug!("Guest clock skew {skew}ms");
+
+            thread::sleep(self.interval);
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Tokio backend.
+//!
+//! Store operations and watch registrations are overlapped ioctls, like with the smol
+//! backend. Opening the device is blocking, so it runs on the tokio blocking pool.
+//!
+use std::{io, sync::Arc};
+
+use futures::Stream;
+#[cfg(windows)]
+use tokio::task;
+use xenstore_rs::{AsyncWatch, AsyncXs};
+
+#[cfg(windows)]
+use crate::XsError;
+use crate::{DefaultTransport, Permission, Transport, XsWindows, walk::Walk};
+
+pub struct XsTokioWindows<T: Transport = DefaultTransport>(Arc<XsWindows<T>>);
+
+#[cfg(windows)]
+impl XsTokioWindows {
+    pub async fn new() -> Result<Self, XsError> {
+        let xs = task::spawn_blocking(XsWindows::new)
+            .await
+            .map_err(io::Error::from)??;
+
+        Ok(xs.into())
+    }
+}
+
+impl<T: Transport> From<XsWindows<T>> for XsTokioWindows<T> {
+    fn from(xs: XsWindows<T>) -> Self {
+        Self(Arc::new(xs))
+    }
+}
+
+impl<T: Transport> XsTokioWindows<T> {
+    /// Set the permissions of a node (see [`XsWindows::set_permissions`]).
+    pub async fn set_permissions(&self, path: &str, permissions: &[Permission]) -> io::Result<()> {
+        self.0.set_permissions_async(path, permissions).await
+    }
+
+    /// Read the raw value of a node (see [`XsWindows::read_bytes`]).
+    pub async fn read_bytes(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.0.read_bytes_async(path).await
+    }
+
+    /// Read the value of a node, lossily (see [`XsWindows::read_lossy`]).
+    pub async fn read_lossy(&self, path: &str) -> io::Result<String> {
+        let value = self.0.read_bytes_async(path).await?;
+
+        Ok(String::from_utf8_lossy(&value).into_owned())
+    }
+
+    /// Write a raw value (see [`XsWindows::write_bytes`]).
+    pub async fn write_bytes(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.0.write_bytes_async(path, data).await
+    }
+
+    /// Walk the subtree of `path` (see [`Walk::stream`]).
+    pub fn walk(&self, path: &str) -> Walk<'_, Self> {
+        Walk::new(self, path)
+    }
+}
+
+// Store operations (and watch registrations) are overlapped ioctls, they don't block the
+// executor.
+impl<T: Transport> AsyncXs for XsTokioWindows<T> {
+    async fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        self.0.directory_async(path).await
+    }
+
+    async fn read(&self, path: &str) -> io::Result<Box<str>> {
+        self.0.read_async(path).await
+    }
+
+    async fn write(&self, path: &str, data: &str) -> io::Result<()> {
+        self.0.write_async(path, data).await
+    }
+
+    async fn rm(&self, path: &str) -> io::Result<()> {
+        self.0.rm_async(path).await
+    }
+}
+
+impl<T: Transport + 'static> AsyncWatch for XsTokioWindows<T> {
+    async fn watch(
+        &self,
+        path: &str,
+    ) -> io::Result<impl Stream<Item = Box<str>> + Unpin + 'static> {
+        Ok(self.0.watch_async(path).await?.into_stream())
+    }
+}
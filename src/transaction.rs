@@ -0,0 +1,286 @@
+//! Client-side transactions.
+//!
+//! xeniface has no transaction ioctls, so [`Transaction`] emulates them with optimistic
+//! concurrency: reads are recorded, writes and removes are buffered, and the commit checks
+//! that the recorded reads still hold before applying the buffered operations.
+//!
+//! This is weaker than xenstored transactions:
+//! - the check and the operations aren't atomic, a concurrent change in between goes
+//!   unnoticed;
+//! - a failing operation leaves the previous ones applied;
+//! - only what was read is checked, a conflicting write to a node that wasn't read is not
+//!   detected.
+//!
+//! It is enough for read-modify-write patterns (e.g allocating an index), as long as every
+//! writer goes through a transaction and reads what it depends on.
+//!
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    io, str,
+};
+
+use xenstore_rs::{Xs, XsTransaction, XsTransactionSpan};
+
+use crate::{DefaultTransport, Transport, XsError, XsWindows, check_write};
+
+/// How many times [`XsWindows::with_transaction`] tries before giving up.
+pub const TRANSACTION_ATTEMPTS: usize = 16;
+
+/// Buffered operation.
+enum Op {
+    Write(Vec<u8>),
+    Remove,
+}
+
+/// Whether `path` is `ancestor` or under it.
+fn is_under(path: &str, ancestor: &str) -> bool {
+    match path.strip_prefix(ancestor) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || ancestor.ends_with('/'),
+        None => false,
+    }
+}
+
+/// First segment of `path` under `parent`, if `path` is strictly under it.
+fn child_of<'a>(path: &'a str, parent: &str) -> Option<&'a str> {
+    let rest = path.strip_prefix(parent)?;
+    let rest = match parent.ends_with('/') {
+        true => rest,
+        false => rest.strip_prefix('/')?,
+    };
+
+    rest.split('/').next().filter(|child| !child.is_empty())
+}
+
+/// Parent of an absolute `path`.
+fn parent_of(path: &str) -> Option<&str> {
+    match path.rsplit_once('/')? {
+        ("", "") => None,
+        ("", _) => Some("/"),
+        (parent, _) => Some(parent),
+    }
+}
+
+/// `Ok(None)` if the node doesn't exist.
+pub(crate) fn optional<R>(result: io::Result<R>) -> io::Result<Option<R>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Read-modify-write on a xenstore, emulated client-side (see the [module](self) for the
+/// guarantees).
+///
+/// Started with [`XsTransaction::transaction`], on a clone of the device.
+/// Paths are compared as given, use absolute paths.
+/// Dropping it without committing discards the buffered operations.
+pub struct Transaction<T: Transport = DefaultTransport> {
+    xs: XsWindows<T>,
+    /// Values read from the store, `None` if the node didn't exist.
+    values: RefCell<BTreeMap<String, Option<Vec<u8>>>>,
+    /// Directories listed from the store, `None` if the node didn't exist.
+    directories: RefCell<BTreeMap<String, Option<Vec<Box<str>>>>>,
+    ops: RefCell<Vec<(String, Op)>>,
+}
+
+impl<T: Transport> Transaction<T> {
+    fn new(xs: XsWindows<T>) -> Self {
+        Self {
+            xs,
+            values: RefCell::default(),
+            directories: RefCell::default(),
+            ops: RefCell::default(),
+        }
+    }
+
+    /// Read `path` from the store, recording it.
+    fn store_value(&self, path: &str) -> io::Result<Option<Vec<u8>>> {
+        if let Some(value) = self.values.borrow().get(path) {
+            return Ok(value.clone());
+        }
+
+        let value = optional(self.xs.read_bytes(path))?;
+        self.values
+            .borrow_mut()
+            .insert(path.to_string(), value.clone());
+
+        Ok(value)
+    }
+
+    /// List `path` from the store, recording it.
+    fn store_directory(&self, path: &str) -> io::Result<Option<Vec<Box<str>>>> {
+        if let Some(entries) = self.directories.borrow().get(path) {
+            return Ok(entries.clone());
+        }
+
+        let entries = optional(self.xs.directory(path))?;
+        self.directories
+            .borrow_mut()
+            .insert(path.to_string(), entries.clone());
+
+        Ok(entries)
+    }
+
+    /// Read the raw value of a node, as seen by the transaction.
+    pub fn read_bytes(&self, path: &str) -> io::Result<Vec<u8>> {
+        // Value given by the buffered operations, `None` if they leave the store value.
+        let mut value = None;
+        // Whether a node was written under it since, which creates it implicitly.
+        let mut implicit = false;
+        let ops = self.ops.borrow();
+
+        for (op_path, op) in ops.iter() {
+            match op {
+                Op::Write(data) if op_path == path => (value, implicit) = (Some(Some(data)), false),
+                Op::Remove if is_under(path, op_path) => (value, implicit) = (Some(None), false),
+                Op::Write(_) if is_under(op_path, path) => implicit = true,
+                _ => (),
+            }
+        }
+
+        let value = match value {
+            Some(value) => value.cloned(),
+            None => self.store_value(path)?,
+        };
+
+        match value {
+            Some(value) => Ok(value),
+            None if implicit => Ok(Vec::new()),
+            None => Err(XsError::NotFound.into()),
+        }
+    }
+
+    /// Write a raw value (see [`XsWindows::write_bytes`]), on commit.
+    pub fn write_bytes(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        // Fail now rather than on commit.
+        check_write(path, data)?;
+
+        self.ops
+            .borrow_mut()
+            .push((path.to_string(), Op::Write(data.to_vec())));
+
+        Ok(())
+    }
+}
+
+impl<T: Transport> Xs for Transaction<T> {
+    fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        let mut entries: Option<BTreeSet<Box<str>>> = self
+            .store_directory(path)?
+            .map(|entries| entries.into_iter().collect());
+
+        for (op_path, op) in self.ops.borrow().iter() {
+            match op {
+                Op::Remove if is_under(path, op_path) => entries = None,
+                Op::Remove if parent_of(op_path) == Some(path) => {
+                    if let (Some(entries), Some(child)) = (&mut entries, child_of(op_path, path)) {
+                        entries.remove(child);
+                    }
+                }
+                Op::Remove => (),
+                Op::Write(_) if is_under(op_path, path) => {
+                    let entries = entries.get_or_insert_default();
+
+                    if let Some(child) = child_of(op_path, path) {
+                        entries.insert(child.into());
+                    }
+                }
+                Op::Write(_) => (),
+            }
+        }
+
+        match entries {
+            Some(entries) => Ok(entries.into_iter().collect()),
+            None => Err(XsError::NotFound.into()),
+        }
+    }
+
+    fn read(&self, path: &str) -> io::Result<Box<str>> {
+        let value = self.read_bytes(path)?;
+
+        Ok(str::from_utf8(&value).map_err(XsError::from)?.into())
+    }
+
+    fn write(&self, path: &str, data: &str) -> io::Result<()> {
+        self.write_bytes(path, data.as_bytes())
+    }
+
+    fn rm(&self, path: &str) -> io::Result<()> {
+        self.ops.borrow_mut().push((path.to_string(), Op::Remove));
+
+        Ok(())
+    }
+}
+
+impl<T: Transport> XsTransactionSpan for Transaction<T> {
+    /// Check the recorded reads, and apply the buffered operations.
+    ///
+    /// Fails with [`XsError::Again`] if one of the recorded reads changed, nothing is applied
+    /// then, and the transaction should be retried (see [`XsWindows::with_transaction`]).
+    fn commit(self) -> io::Result<()> {
+        for (path, value) in self.values.into_inner() {
+            if optional(self.xs.read_bytes(&path))? != value {
+                log::debug!("Transaction conflict on {path}");
+                return Err(XsError::Again.into());
+            }
+        }
+
+        for (path, entries) in self.directories.into_inner() {
+            if optional(self.xs.directory(&path))? != entries {
+                log::debug!("Transaction conflict on {path}");
+                return Err(XsError::Again.into());
+            }
+        }
+
+        for (path, op) in self.ops.into_inner() {
+            match op {
+                Op::Write(data) => self.xs.write_bytes(&path, &data)?,
+                // Removing a missing node is fine.
+                Op::Remove => optional(self.xs.rm(&path)).map(drop)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: Transport> XsTransaction for XsWindows<T> {
+    type Span = Transaction<T>;
+
+    /// Start a client-side [`Transaction`].
+    fn transaction(&self) -> io::Result<Transaction<T>> {
+        Ok(Transaction::new(self.try_clone()?))
+    }
+}
+
+impl<T: Transport> XsWindows<T> {
+    /// Run `f` in a [`Transaction`] and commit it, retrying on conflict.
+    ///
+    /// `f` may run several times, and must not have side effects out of the transaction.
+    /// An error from `f` aborts the transaction. Gives up with [`XsError::Again`] after
+    /// [`TRANSACTION_ATTEMPTS`] conflicts.
+    pub fn with_transaction<R>(
+        &self,
+        mut f: impl FnMut(&Transaction<T>) -> io::Result<R>,
+    ) -> io::Result<R> {
+        let mut attempt = 1;
+
+        loop {
+            let transaction = XsTransaction::transaction(self)?;
+            let result = f(&transaction)?;
+
+            match transaction.commit() {
+                Err(e)
+                    if XsError::matches(&e, |e| matches!(e, XsError::Again))
+                        && attempt < TRANSACTION_ATTEMPTS =>
+                {
+                    attempt += 1
+                }
+                Err(e) => return Err(e),
+                Ok(()) => return Ok(result),
+            }
+        }
+    }
+}
@@ -0,0 +1,426 @@
+//! Xeniface ioctl transport.
+//!
+//! [`XsWindows`](crate::XsWindows) doesn't talk to the driver directly, but through a [`Transport`].
+//! The default one is the xeniface device handle, other implementations can be used
+//! to emulate the driver (e.g for testing).
+//!
+//! Ioctls can either be blocking ([`Transport::ioctl`]) or overlapped ([`Transport::submit`]),
+//! the latter being used by the async backends.
+//!
+use std::{
+    io,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+#[cfg(windows)]
+use std::{
+    ffi::c_void,
+    os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use crate::Overlapped;
+
+#[cfg(windows)]
+use crate::overlapped::OverlappedIoctl;
+#[cfg(windows)]
+use windows::Win32::{
+    Foundation::{BOOLEAN, HANDLE, INVALID_HANDLE_VALUE, WAIT_FAILED, WAIT_OBJECT_0, WAIT_TIMEOUT},
+    System::Threading::{
+        CreateEventW, INFINITE, RegisterWaitForSingleObject, ResetEvent, SetEvent,
+        UnregisterWaitEx, WT_EXECUTEONLYONCE, WaitForMultipleObjects, WaitForSingleObject,
+    },
+};
+
+/// Transport used by default: the xeniface device handle.
+#[cfg(windows)]
+pub type DefaultTransport = OwnedHandle;
+
+/// Transport used by default.
+///
+/// There is no xeniface device outside of Windows, see [`Unsupported`].
+#[cfg(not(windows))]
+pub type DefaultTransport = Unsupported;
+
+/// Way to issue ioctls to a xeniface device.
+pub trait Transport: Sized + Send + Sync {
+    /// Event object the driver signals (e.g when a watch fires).
+    type Event: Event;
+
+    /// Ioctl in flight, started with [`Transport::submit`].
+    type Overlapped<'a>: Overlapped
+    where
+        Self: 'a;
+
+    /// Issue the `control_code` ioctl with `in_buffer` as input, and `out_buffer` as output.
+    ///
+    /// Returns the number of bytes written to `out_buffer`. Failures are reported as Win32
+    /// error codes (see [`win32_error`](crate::win32_error)).
+    ///
+    /// # Safety
+    ///
+    /// Some ioctls embed pointers in `in_buffer` (e.g `XENIFACE_STORE_ADD_WATCH_IN`),
+    /// those pointers must be valid for the whole call.
+    unsafe fn ioctl(
+        &self,
+        control_code: u32,
+        in_buffer: &[u8],
+        out_buffer: Option<&mut [u8]>,
+    ) -> io::Result<u32>;
+
+    /// Start the `control_code` ioctl without waiting for its completion.
+    ///
+    /// `in_buffer` and `out_buffer` are owned by the operation until it completes,
+    /// an empty `out_buffer` means no output.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`Transport::ioctl`], pointers embedded in `in_buffer` must be valid
+    /// until the operation completes or is dropped.
+    unsafe fn submit(
+        &self,
+        control_code: u32,
+        in_buffer: Vec<u8>,
+        out_buffer: Vec<u8>,
+    ) -> io::Result<Self::Overlapped<'_>>;
+
+    /// Create a new manual-reset event (initially non-signaled) that can be given to the driver.
+    fn create_event(&self) -> io::Result<Self::Event>;
+
+    /// Create another transport to the same device.
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+/// Event object that can be given to the driver.
+pub trait Event: Send + Sync + 'static {
+    /// Raw handle value as given to the driver.
+    fn raw_handle(&self) -> usize;
+
+    /// Signal the event.
+    fn set(&self) -> io::Result<()>;
+
+    /// Set the event back to non-signaled state.
+    fn reset(&self) -> io::Result<()>;
+
+    /// Poll until the event is signaled, waking `cx` once it is.
+    ///
+    /// This doesn't depend on a specific async runtime.
+    fn poll_wait(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>>;
+
+    /// Block until one of `events` is signaled, or `timeout` expires.
+    ///
+    /// Returns the index of a signaled event, or `None` on timeout.
+    /// The default implementation parks the thread between [`Event::poll_wait`] calls.
+    fn wait_any(events: &[&Self], timeout: Option<Duration>) -> io::Result<Option<usize>>
+    where
+        Self: Sized,
+    {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            for (index, event) in events.iter().enumerate() {
+                if let Poll::Ready(result) = event.poll_wait(&mut cx) {
+                    return result.map(|_| Some(index));
+                }
+            }
+
+            match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(None);
+                    }
+                    thread::park_timeout(deadline - now);
+                }
+                None => thread::park(),
+            }
+        }
+    }
+
+    /// Block until the event is signaled, or `timeout` expires.
+    ///
+    /// Returns `false` on timeout.
+    fn wait(&self, timeout: Option<Duration>) -> io::Result<bool>
+    where
+        Self: Sized,
+    {
+        Ok(Self::wait_any(&[self], timeout)?.is_some())
+    }
+}
+
+/// Wakes a thread blocked in [`Event::wait_any`].
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// The device is opened with `FILE_FLAG_OVERLAPPED`, so blocking ioctls are overlapped ones
+/// that are waited for.
+#[cfg(windows)]
+impl Transport for OwnedHandle {
+    type Event = EventHandle;
+    type Overlapped<'a> = OverlappedIoctl<'a>;
+
+    unsafe fn ioctl(
+        &self,
+        control_code: u32,
+        in_buffer: &[u8],
+        out_buffer: Option<&mut [u8]>,
+    ) -> io::Result<u32> {
+        let out_buffer_len = out_buffer.as_ref().map_or(0, |s| s.len());
+        let mut ioctl =
+            unsafe { self.submit(control_code, in_buffer.to_vec(), vec![0; out_buffer_len])? };
+        let output = ioctl.wait()?;
+
+        if let Some(out_buffer) = out_buffer {
+            out_buffer[..output.len()].copy_from_slice(&output);
+        }
+
+        Ok(output.len() as u32)
+    }
+
+    unsafe fn submit(
+        &self,
+        control_code: u32,
+        in_buffer: Vec<u8>,
+        out_buffer: Vec<u8>,
+    ) -> io::Result<Self::Overlapped<'_>> {
+        unsafe { OverlappedIoctl::submit(self, control_code, in_buffer, out_buffer) }
+    }
+
+    fn create_event(&self) -> io::Result<Self::Event> {
+        EventHandle::new()
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        OwnedHandle::try_clone(self)
+    }
+}
+
+/// Win32 event object of a xeniface device.
+///
+/// Async waits go through a thread-pool wait (`RegisterWaitForSingleObject`), which
+/// wakes the task once the event is signaled.
+#[cfg(windows)]
+pub struct EventHandle {
+    handle: OwnedHandle,
+    slot: Arc<WaitSlot>,
+    wait: Mutex<Option<WaitHandle>>,
+}
+
+/// State shared with the thread-pool wait callback.
+#[cfg(windows)]
+#[derive(Default)]
+struct WaitSlot {
+    waker: Mutex<Option<Waker>>,
+    fired: AtomicBool,
+}
+
+/// Registered thread-pool wait.
+#[cfg(windows)]
+struct WaitHandle(HANDLE);
+
+// SAFETY: Wait handles can be unregistered from any thread.
+#[cfg(windows)]
+unsafe impl Send for WaitHandle {}
+
+#[cfg(windows)]
+unsafe extern "system" fn wait_callback(context: *mut c_void, _timed_out: BOOLEAN) {
+    // SAFETY: The slot outlives the wait, which is always unregistered by blocking until the
+    // callback completes (see EventHandle::poll_wait and EventHandle::drop).
+    let slot = unsafe { &*context.cast::<WaitSlot>() };
+
+    slot.fired.store(true, Ordering::Release);
+    if let Some(waker) = slot.waker.lock().unwrap().take() {
+        waker.wake();
+    }
+}
+
+#[cfg(windows)]
+impl EventHandle {
+    /// Create a manual-reset event, initially non-signaled.
+    pub(crate) fn new() -> io::Result<Self> {
+        let handle =
+            unsafe { OwnedHandle::from_raw_handle(CreateEventW(None, true, false, None)?.0) };
+
+        Ok(Self {
+            handle,
+            slot: Arc::default(),
+            wait: Mutex::new(None),
+        })
+    }
+
+    pub(crate) fn as_handle(&self) -> HANDLE {
+        HANDLE(self.handle.as_raw_handle())
+    }
+
+    fn is_signaled(&self) -> io::Result<bool> {
+        match unsafe { WaitForSingleObject(self.as_handle(), 0) } {
+            WAIT_OBJECT_0 => Ok(true),
+            WAIT_TIMEOUT => Ok(false),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl AsRawHandle for EventHandle {
+    fn as_raw_handle(&self) -> std::os::windows::io::RawHandle {
+        self.handle.as_raw_handle()
+    }
+}
+
+#[cfg(windows)]
+impl Drop for EventHandle {
+    fn drop(&mut self) {
+        if let Some(WaitHandle(wait)) = self.wait.get_mut().unwrap().take() {
+            // Block until a running callback completes, as it refers to the slot.
+            unsafe { UnregisterWaitEx(wait, INVALID_HANDLE_VALUE) }
+                .inspect_err(|e| log::warn!("Unable to unregister wait {e}"))
+                .ok();
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Event for EventHandle {
+    fn raw_handle(&self) -> usize {
+        self.handle.as_raw_handle().addr()
+    }
+
+    fn set(&self) -> io::Result<()> {
+        unsafe { SetEvent(self.as_handle())? };
+        Ok(())
+    }
+
+    fn reset(&self) -> io::Result<()> {
+        unsafe { ResetEvent(self.as_handle())? };
+        Ok(())
+    }
+
+    fn poll_wait(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Store the waker first, so that a concurrent callback can't be missed.
+        *self.slot.waker.lock().unwrap() = Some(cx.waker().clone());
+
+        if self.is_signaled()? {
+            return Poll::Ready(Ok(()));
+        }
+
+        let mut wait = self.wait.lock().unwrap();
+
+        // The wait is one-shot, a fired one needs to be registered again.
+        if wait.is_some() && self.slot.fired.swap(false, Ordering::Acquire) {
+            let WaitHandle(handle) = wait.take().unwrap();
+            // Block until the callback completes (it is at most finishing, as it fired), as it
+            // refers to the slot, which may be freed right after.
+            unsafe { UnregisterWaitEx(handle, INVALID_HANDLE_VALUE) }
+                .inspect_err(|e| log::warn!("Unable to unregister wait {e}"))
+                .ok();
+        }
+
+        if wait.is_none() {
+            let mut handle = HANDLE::default();
+
+            unsafe {
+                RegisterWaitForSingleObject(
+                    &mut handle,
+                    self.as_handle(),
+                    Some(wait_callback),
+                    Some(Arc::as_ptr(&self.slot).cast()),
+                    INFINITE,
+                    WT_EXECUTEONLYONCE,
+                )?
+            };
+
+            *wait = Some(WaitHandle(handle));
+        }
+
+        Poll::Pending
+    }
+
+    fn wait_any(events: &[&Self], timeout: Option<Duration>) -> io::Result<Option<usize>> {
+        let handles: Vec<HANDLE> = events.iter().map(|e| e.as_handle()).collect();
+        // Saturate below INFINITE.
+        let timeout = timeout.map_or(INFINITE, |t| t.as_millis().min(INFINITE as u128 - 1) as u32);
+
+        // NOTE: Limited to MAXIMUM_WAIT_OBJECTS (64) handles.
+        match unsafe { WaitForMultipleObjects(&handles, false, timeout) } {
+            WAIT_TIMEOUT => Ok(None),
+            WAIT_FAILED => Err(io::Error::last_os_error()),
+            result => match (result.0 - WAIT_OBJECT_0.0) as usize {
+                index if index < events.len() => Ok(Some(index)),
+                // WAIT_ABANDONED_0 is only for mutexes.
+                _ => Err(io::Error::other(format!(
+                    "unexpected wait result {}",
+                    result.0
+                ))),
+            },
+        }
+    }
+}
+
+/// Default transport outside of Windows, which can't be created.
+///
+/// Only there so that types have the same default [`Transport`] on every host, use a
+/// custom one (e.g the emulator of the `emulator` feature) instead.
+#[cfg(not(windows))]
+pub enum Unsupported {}
+
+#[cfg(not(windows))]
+impl Transport for Unsupported {
+    type Event = Unsupported;
+    type Overlapped<'a> = Unsupported;
+
+    unsafe fn ioctl(&self, _: u32, _: &[u8], _: Option<&mut [u8]>) -> io::Result<u32> {
+        match *self {}
+    }
+
+    unsafe fn submit(&self, _: u32, _: Vec<u8>, _: Vec<u8>) -> io::Result<Self::Overlapped<'_>> {
+        match *self {}
+    }
+
+    fn create_event(&self) -> io::Result<Self::Event> {
+        match *self {}
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        match *self {}
+    }
+}
+
+#[cfg(not(windows))]
+impl Event for Unsupported {
+    fn raw_handle(&self) -> usize {
+        match *self {}
+    }
+
+    fn set(&self) -> io::Result<()> {
+        match *self {}
+    }
+
+    fn reset(&self) -> io::Result<()> {
+        match *self {}
+    }
+
+    fn poll_wait(&self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match *self {}
+    }
+}
+
+#[cfg(not(windows))]
+impl Overlapped for Unsupported {
+    fn poll_complete(&mut self, _: &mut Context<'_>) -> Poll<io::Result<Vec<u8>>> {
+        match *self {}
+    }
+}
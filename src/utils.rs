@@ -1,6 +1,6 @@
 /// Some NUL-string payload related utilities.
 /// Taken from xenstore-rs wire.rs
-/// 
+///
 use std::{
     io::Write,
     str::{self, Utf8Error},
@@ -17,6 +17,18 @@ pub fn make_payload(strings: &[&str]) -> Box<[u8]> {
     payload.into_boxed_slice()
 }
 
+/// Same as `make_payload`, with raw byte strings (which must not contain NUL).
+pub fn make_bytes_payload(strings: &[&[u8]]) -> Box<[u8]> {
+    let mut payload: Vec<u8> = Vec::new();
+
+    for s in strings {
+        payload.extend_from_slice(s);
+        payload.push(0);
+    }
+
+    payload.into_boxed_slice()
+}
+
 pub fn parse_nul_string(mut buffer: &[u8]) -> Result<Option<&str>, Utf8Error> {
     // Assuming terminating NUL
     if buffer.is_empty() {
@@ -0,0 +1,691 @@
+//! Vchan, byte streams between domains (compatible with libxenvchan).
+//!
+//! The server shares a control page (along with the rings, if they don't fit in it) and an
+//! event channel with the client, and publishes them in xenstore as `<path>/ring-ref` and
+//! `<path>/event-channel`. The path is usually `/local/domain/<server>/data/vchan/<client>/<name>`
+//! (see [`path`]).
+//!
+//! [`Vchan`] is a blocking [`Read`] and [`Write`] stream, and also an `AsyncRead` and
+//! `AsyncWrite` one with the `smol` feature.
+//!
+use std::{
+    io::{self, Read, Write},
+    ptr,
+    sync::atomic::{self, AtomicU8, AtomicU32, Ordering},
+};
+#[cfg(feature = "smol")]
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Wake, Waker, ready},
+};
+
+#[cfg(feature = "smol")]
+use futures::{AsyncRead, AsyncWrite};
+use xenstore_rs::Xs;
+
+use crate::{
+    Access, DefaultTransport, Permission, Transport, XsWindows,
+    evtchn::EventChannel,
+    gnttab::{GrantedPages, MappedForeignPages, Notify, PAGE_SIZE},
+};
+
+/*
+ * struct ring_shared {
+ *     uint32_t cons, prod;
+ * };
+ *
+ * struct vchan_interface {
+ *     struct ring_shared left, right;
+ *     uint8_t srv_live;
+ *     uint8_t cli_live;
+ *     uint8_t srv_notify;
+ *     uint8_t cli_notify;
+ *     uint16_t left_order;
+ *     uint16_t right_order;
+ *     uint32_t grants[0];
+ * };
+ *
+ * The server reads from the left ring, and writes to the right one.
+ */
+const LEFT: usize = 0;
+const RIGHT: usize = 8;
+const SRV_LIVE: usize = 16;
+const CLI_LIVE: usize = 17;
+const SRV_NOTIFY: usize = 18;
+const CLI_NOTIFY: usize = 19;
+const LEFT_ORDER: usize = 20;
+const RIGHT_ORDER: usize = 22;
+const GRANTS: usize = 24;
+
+// Bits of srv_notify/cli_notify, set by the end that wants to be notified.
+const VCHAN_NOTIFY_WRITE: u8 = 1 << 0;
+const VCHAN_NOTIFY_READ: u8 = 1 << 1;
+
+// cli_live before the client connects.
+const CLI_LIVE_PENDING: u8 = 2;
+
+// Rings of order 10 and 11 are in the control page, larger ones are in separate pages.
+const SMALL_RING_SHIFT: u16 = 10;
+const LARGE_RING_SHIFT: u16 = 11;
+const PAGE_SHIFT: u16 = 12;
+const MAX_RING_SHIFT: u16 = 20;
+
+/// Offset of a ring in the control page, if it fits in it.
+fn ring_offset(order: u16) -> Option<usize> {
+    match order {
+        SMALL_RING_SHIFT => Some(1024),
+        LARGE_RING_SHIFT => Some(2048),
+        _ => None,
+    }
+}
+
+/// Smallest order of a ring in separate pages, that holds `min` bytes.
+fn allocate_order(min: usize) -> io::Result<u16> {
+    let order = min
+        .checked_next_power_of_two()
+        .map_or(usize::BITS, usize::trailing_zeros) as u16;
+    let order = order.max(PAGE_SHIFT);
+
+    if order > MAX_RING_SHIFT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("vchan ring of {min} bytes is too large"),
+        ));
+    }
+
+    Ok(order)
+}
+
+/// Orders of the left and right rings, picked the way libxenvchan does.
+fn server_orders(left_min: usize, right_min: usize) -> io::Result<(u16, u16)> {
+    const MAX_SMALL_RING: usize = 1 << SMALL_RING_SHIFT;
+    const MAX_LARGE_RING: usize = 1 << LARGE_RING_SHIFT;
+
+    Ok(
+        if left_min <= MAX_SMALL_RING && right_min <= MAX_LARGE_RING {
+            (SMALL_RING_SHIFT, LARGE_RING_SHIFT)
+        } else if left_min <= MAX_LARGE_RING && right_min <= MAX_SMALL_RING {
+            (LARGE_RING_SHIFT, SMALL_RING_SHIFT)
+        } else if left_min <= MAX_LARGE_RING {
+            (LARGE_RING_SHIFT, allocate_order(right_min)?)
+        } else if right_min <= MAX_LARGE_RING {
+            (allocate_order(left_min)?, LARGE_RING_SHIFT)
+        } else {
+            (allocate_order(left_min)?, allocate_order(right_min)?)
+        },
+    )
+}
+
+/// Number of separate pages of the left and right rings, checking that their layout is valid.
+fn ring_page_counts(left_order: u16, right_order: u16) -> io::Result<(usize, usize)> {
+    let pages = |order: u16| match order {
+        PAGE_SHIFT.. => 1 << (order - PAGE_SHIFT),
+        _ => 0,
+    };
+    let (left, right) = (pages(left_order), pages(right_order));
+
+    // Grants must not overlap the rings in the control page.
+    let grants_end = [left_order, right_order]
+        .into_iter()
+        .filter_map(ring_offset)
+        .min()
+        .unwrap_or(PAGE_SIZE);
+
+    if ![left_order, right_order]
+        .iter()
+        .all(|order| (SMALL_RING_SHIFT..=MAX_RING_SHIFT).contains(order))
+        || (left_order == right_order && left_order < PAGE_SHIFT)
+        || GRANTS + (left + right) * size_of::<u32>() > grants_end
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid vchan ring orders {left_order}/{right_order}"),
+        ));
+    }
+
+    Ok((left, right))
+}
+
+fn parse_node<N: std::str::FromStr>(value: &str) -> io::Result<N> {
+    value.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid vchan node {value:?}"),
+        )
+    })
+}
+
+/// Conventional xenstore path of the `name` vchan, between `server_domid` and `client_domid`.
+pub fn path(server_domid: u16, client_domid: u16, name: &str) -> String {
+    format!("/local/domain/{server_domid}/data/vchan/{client_domid}/{name}")
+}
+
+enum Pages<'a, T: Transport> {
+    Granted(GrantedPages<'a, T>),
+    Mapped(MappedForeignPages<'a, T>),
+}
+
+impl<T: Transport> Pages<'_, T> {
+    fn as_ptr(&self) -> *mut u8 {
+        match self {
+            Pages::Granted(pages) => pages.as_ptr(),
+            Pages::Mapped(pages) => pages.as_ptr(),
+        }
+    }
+}
+
+/// Place the ring of `order` in the control page, or in pages given by `share`.
+fn ring_buffer<'a, T: Transport>(
+    control: &Pages<'a, T>,
+    order: u16,
+    ring_pages: &mut Vec<Pages<'a, T>>,
+    share: impl FnOnce() -> io::Result<Pages<'a, T>>,
+) -> io::Result<*mut u8> {
+    match ring_offset(order) {
+        Some(offset) => Ok(unsafe { control.as_ptr().add(offset) }),
+        None => {
+            let pages = share()?;
+            let buffer = pages.as_ptr();
+
+            ring_pages.push(pages);
+            Ok(buffer)
+        }
+    }
+}
+
+/// One direction of the vchan.
+struct Ring {
+    /// `ring_shared` of the ring, in the control page.
+    shared: *mut u8,
+    buffer: *mut u8,
+    size: usize,
+}
+
+impl Ring {
+    fn new(control: *mut u8, shared: usize, buffer: *mut u8, order: u16) -> Self {
+        Self {
+            shared: unsafe { control.add(shared) },
+            buffer,
+            size: 1 << order,
+        }
+    }
+
+    fn cons(&self) -> &AtomicU32 {
+        unsafe { AtomicU32::from_ptr(self.shared.cast()) }
+    }
+
+    fn prod(&self) -> &AtomicU32 {
+        unsafe { AtomicU32::from_ptr(self.shared.add(size_of::<u32>()).cast()) }
+    }
+
+    /// Bytes between the consumer and producer indexes.
+    fn used(&self) -> io::Result<usize> {
+        let used = self
+            .prod()
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.cons().load(Ordering::Acquire)) as usize;
+
+        if used > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "vchan ring indexes are corrupted",
+            ));
+        }
+
+        Ok(used)
+    }
+}
+
+/// Tasks waiting on the event channel, reading and writing.
+///
+/// Both directions share the event channel, which wakes them all so that each one checks
+/// its ring again.
+#[cfg(feature = "smol")]
+#[derive(Default)]
+struct Waiters([Mutex<Option<Waker>>; 2]);
+
+#[cfg(feature = "smol")]
+const READING: usize = 0;
+#[cfg(feature = "smol")]
+const WRITING: usize = 1;
+
+#[cfg(feature = "smol")]
+impl Wake for Waiters {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        for waker in &self.0 {
+            if let Some(waker) = waker.lock().unwrap().take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Vchan stream, either end of it.
+///
+/// The remote end is notified and the shared pages are released when dropped, the server
+/// also removes its xenstore nodes. As the driver releases the pages when the thread that
+/// created them exits, the vchan can't be sent to another thread.
+pub struct Vchan<'a, T: Transport = DefaultTransport> {
+    xs: &'a XsWindows<T>,
+    path: Box<str>,
+    is_server: bool,
+    read: Ring,
+    write: Ring,
+    // Released before the control page, and the event channel it notifies.
+    _ring_pages: Vec<Pages<'a, T>>,
+    control: Pages<'a, T>,
+    event: EventChannel<T>,
+    #[cfg(feature = "smol")]
+    waiters: Arc<Waiters>,
+}
+
+impl<'a, T: Transport> Vchan<'a, T> {
+    /// Create the server end of a vchan with `domid`, published at `path`.
+    ///
+    /// The rings hold at least `read_min` and `write_min` bytes (from the server side),
+    /// up to 1 MiB. Writes are buffered until the client connects.
+    pub fn server(
+        xs: &'a XsWindows<T>,
+        domid: u16,
+        path: &str,
+        read_min: usize,
+        write_min: usize,
+    ) -> io::Result<Self> {
+        let (left_order, right_order) = server_orders(read_min, write_min)?;
+        let (left_pages, right_pages) = ring_page_counts(left_order, right_order)?;
+        let own_domid: u16 = parse_node(&xs.read("domid")?)?;
+
+        let event = EventChannel::bind_unbound(xs, domid, false)?;
+        let notify = Notify {
+            offset: Some(SRV_LIVE as u32),
+            port: Some(event.port()),
+        };
+        let control = Pages::Granted(GrantedPages::new(xs, domid, 1, false, notify)?);
+        let mut ring_pages = Vec::new();
+        let mut grants = Vec::new();
+
+        let mut share = |count: usize| {
+            let pages = GrantedPages::new(xs, domid, count as u32, false, Notify::default())?;
+
+            grants.extend_from_slice(pages.references());
+            Ok(Pages::Granted(pages))
+        };
+        let left = ring_buffer(&control, left_order, &mut ring_pages, || share(left_pages))?;
+        let right = ring_buffer(&control, right_order, &mut ring_pages, || {
+            share(right_pages)
+        })?;
+
+        // The pages are zeroed, so are the indexes.
+        let base = control.as_ptr();
+        unsafe {
+            base.add(LEFT_ORDER).cast::<u16>().write(left_order);
+            base.add(RIGHT_ORDER).cast::<u16>().write(right_order);
+            for (i, grant) in grants.iter().enumerate() {
+                base.add(GRANTS).cast::<u32>().add(i).write(*grant);
+            }
+        }
+
+        let vchan = Self {
+            xs,
+            path: path.into(),
+            is_server: true,
+            read: Ring::new(base, LEFT, left, left_order),
+            write: Ring::new(base, RIGHT, right, right_order),
+            _ring_pages: ring_pages,
+            control,
+            event,
+            #[cfg(feature = "smol")]
+            waiters: Arc::default(),
+        };
+
+        vchan
+            .byte(CLI_LIVE)
+            .store(CLI_LIVE_PENDING, Ordering::Relaxed);
+        vchan.byte(SRV_LIVE).store(1, Ordering::Relaxed);
+        vchan
+            .byte(CLI_NOTIFY)
+            .store(VCHAN_NOTIFY_WRITE, Ordering::Release);
+
+        // ring-ref goes last, as clients expect both nodes once it exists.
+        let Pages::Granted(control) = &vchan.control else {
+            unreachable!()
+        };
+        let permissions = [
+            Permission::new(own_domid, Access::None),
+            Permission::new(domid, Access::Read),
+        ];
+        for (node, value) in [
+            ("event-channel", vchan.event.port()),
+            ("ring-ref", control.references()[0]),
+        ] {
+            let node = format!("{path}/{node}");
+
+            xs.write(&node, &value.to_string())?;
+            xs.set_permissions(&node, &permissions)?;
+        }
+
+        Ok(vchan)
+    }
+
+    /// Connect to the vchan `domid` published at `path`.
+    pub fn client(xs: &'a XsWindows<T>, domid: u16, path: &str) -> io::Result<Self> {
+        let ring_ref = parse_node(&xs.read(&format!("{path}/ring-ref"))?)?;
+        let remote_port = parse_node(&xs.read(&format!("{path}/event-channel"))?)?;
+
+        let event = EventChannel::bind_interdomain(xs, domid, remote_port, false)?;
+        let notify = Notify {
+            offset: Some(CLI_LIVE as u32),
+            port: Some(event.port()),
+        };
+        let control = Pages::Mapped(MappedForeignPages::new(
+            xs,
+            domid,
+            &[ring_ref],
+            false,
+            notify,
+        )?);
+
+        let base = control.as_ptr();
+        let (left_order, right_order, cli_live) = unsafe {
+            (
+                base.add(LEFT_ORDER).cast::<u16>().read_volatile(),
+                base.add(RIGHT_ORDER).cast::<u16>().read_volatile(),
+                AtomicU8::from_ptr(base.add(CLI_LIVE)).load(Ordering::Acquire),
+            )
+        };
+
+        if cli_live != CLI_LIVE_PENDING {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "vchan is already connected",
+            ));
+        }
+
+        let (left_pages, right_pages) = ring_page_counts(left_order, right_order)?;
+        let grants: Vec<u32> = (0..left_pages + right_pages)
+            .map(|i| unsafe { base.add(GRANTS).cast::<u32>().add(i).read_volatile() })
+            .collect();
+        let (left_grants, right_grants) = grants.split_at(left_pages);
+        let mut ring_pages = Vec::new();
+
+        let map = |references| {
+            MappedForeignPages::new(xs, domid, references, false, Notify::default())
+                .map(Pages::Mapped)
+        };
+        let left = ring_buffer(&control, left_order, &mut ring_pages, || map(left_grants))?;
+        let right = ring_buffer(&control, right_order, &mut ring_pages, || map(right_grants))?;
+
+        let vchan = Self {
+            xs,
+            path: path.into(),
+            is_server: false,
+            read: Ring::new(base, RIGHT, right, right_order),
+            write: Ring::new(base, LEFT, left, left_order),
+            _ring_pages: ring_pages,
+            control,
+            event,
+            #[cfg(feature = "smol")]
+            waiters: Arc::default(),
+        };
+
+        vchan.byte(CLI_LIVE).store(1, Ordering::Release);
+        vchan
+            .byte(SRV_NOTIFY)
+            .store(VCHAN_NOTIFY_WRITE, Ordering::Release);
+        vchan.event.notify()?;
+
+        Ok(vchan)
+    }
+
+    fn byte(&self, offset: usize) -> &AtomicU8 {
+        unsafe { AtomicU8::from_ptr(self.control.as_ptr().add(offset)) }
+    }
+
+    /// Whether the remote end is still there (or is yet to connect, for the server).
+    pub fn is_open(&self) -> bool {
+        let live = if self.is_server { CLI_LIVE } else { SRV_LIVE };
+
+        self.byte(live).load(Ordering::Acquire) != 0
+    }
+
+    /// Ask the remote end to notify us of `bit` events.
+    fn request_notify(&self, bit: u8) {
+        let notify = if self.is_server {
+            CLI_NOTIFY
+        } else {
+            SRV_NOTIFY
+        };
+
+        // Post the request before the caller reads the indexes again.
+        self.byte(notify).fetch_or(bit, Ordering::SeqCst);
+    }
+
+    /// Notify the remote end of a `bit` event, if it asked for it.
+    fn send_notify(&self, bit: u8) -> io::Result<()> {
+        let notify = if self.is_server {
+            SRV_NOTIFY
+        } else {
+            CLI_NOTIFY
+        };
+
+        // Update the indexes before checking the request.
+        atomic::fence(Ordering::SeqCst);
+        if self.byte(notify).fetch_and(!bit, Ordering::SeqCst) & bit != 0 {
+            self.event.notify()?;
+        }
+
+        Ok(())
+    }
+
+    /// Bytes that can be read without blocking.
+    pub fn data_ready(&self) -> io::Result<usize> {
+        // The caller may wait for it to change.
+        self.request_notify(VCHAN_NOTIFY_WRITE);
+        self.read.used()
+    }
+
+    /// Bytes that can be written without blocking.
+    pub fn buffer_space(&self) -> io::Result<usize> {
+        self.request_notify(VCHAN_NOTIFY_READ);
+        Ok(self.write.size - self.write.used()?)
+    }
+
+    /// Bytes to read, only requesting a notification if there are none.
+    fn readable(&self) -> io::Result<usize> {
+        match self.read.used()? {
+            0 => self.data_ready(),
+            ready => Ok(ready),
+        }
+    }
+
+    /// Space to write, only requesting a notification if there is none.
+    fn writable(&self) -> io::Result<usize> {
+        match self.write.size - self.write.used()? {
+            0 => self.buffer_space(),
+            space => Ok(space),
+        }
+    }
+
+    fn recv(&self, buf: &mut [u8], ready: usize) -> io::Result<usize> {
+        let ring = &self.read;
+        let len = buf.len().min(ready);
+        let cons = ring.cons().load(Ordering::Relaxed);
+        let index = cons as usize & (ring.size - 1);
+        let contiguous = len.min(ring.size - index);
+
+        unsafe {
+            ptr::copy_nonoverlapping(ring.buffer.add(index), buf.as_mut_ptr(), contiguous);
+            ptr::copy_nonoverlapping(
+                ring.buffer,
+                buf[contiguous..].as_mut_ptr(),
+                len - contiguous,
+            );
+        }
+
+        ring.cons()
+            .store(cons.wrapping_add(len as u32), Ordering::Release);
+        self.send_notify(VCHAN_NOTIFY_READ)?;
+
+        Ok(len)
+    }
+
+    fn send(&self, buf: &[u8], space: usize) -> io::Result<usize> {
+        let ring = &self.write;
+        let len = buf.len().min(space);
+        let prod = ring.prod().load(Ordering::Relaxed);
+        let index = prod as usize & (ring.size - 1);
+        let contiguous = len.min(ring.size - index);
+
+        unsafe {
+            ptr::copy_nonoverlapping(buf.as_ptr(), ring.buffer.add(index), contiguous);
+            ptr::copy_nonoverlapping(buf[contiguous..].as_ptr(), ring.buffer, len - contiguous);
+        }
+
+        ring.prod()
+            .store(prod.wrapping_add(len as u32), Ordering::Release);
+        self.send_notify(VCHAN_NOTIFY_WRITE)?;
+
+        Ok(len)
+    }
+
+    /// Poll until the remote end notifies us, for the task waiting in `direction`.
+    #[cfg(feature = "smol")]
+    fn poll_notified(&self, cx: &mut Context<'_>, direction: usize) -> Poll<io::Result<()>> {
+        *self.waiters.0[direction].lock().unwrap() = Some(cx.waker().clone());
+
+        // The event only keeps one waker, give it one for both directions.
+        let waker = Waker::from(self.waiters.clone());
+        ready!(self.event.poll_wait(&mut Context::from_waker(&waker)))?;
+
+        // The notification may be for the other direction, which can't see it once the event
+        // is reset.
+        waker.wake();
+        Poll::Ready(Ok(()))
+    }
+
+    /// Read what is available, `None` if we need to wait.
+    fn try_read(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        if buf.is_empty() {
+            return Ok(Some(0));
+        }
+
+        // Checked first, so that data written before closing is read.
+        let open = self.is_open();
+
+        match self.readable()? {
+            0 if open => Ok(None),
+            // End of stream.
+            0 => Ok(Some(0)),
+            ready => self.recv(buf, ready).map(Some),
+        }
+    }
+
+    /// Write what fits, `None` if we need to wait.
+    fn try_write(&self, buf: &[u8]) -> io::Result<Option<usize>> {
+        if buf.is_empty() {
+            return Ok(Some(0));
+        }
+
+        if !self.is_open() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        match self.writable()? {
+            0 => Ok(None),
+            space => self.send(buf, space).map(Some),
+        }
+    }
+}
+
+impl<T: Transport> Read for Vchan<'_, T> {
+    /// Block until some data is available, returns 0 once the remote end is gone.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.try_read(buf)? {
+                Some(len) => return Ok(len),
+                None => self.event.wait()?,
+            }
+        }
+    }
+}
+
+impl<T: Transport> Write for Vchan<'_, T> {
+    /// Block until some data fits in the ring, fails with [`io::ErrorKind::BrokenPipe`]
+    /// once the remote end is gone.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            match self.try_write(buf)? {
+                Some(len) => return Ok(len),
+                None => self.event.wait()?,
+            }
+        }
+    }
+
+    /// Data is visible to the remote end once written.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "smol")]
+impl<T: Transport> AsyncRead for Vchan<'_, T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            match self.try_read(buf)? {
+                Some(len) => return Poll::Ready(Ok(len)),
+                None => ready!(self.poll_notified(cx, READING))?,
+            }
+        }
+    }
+}
+
+#[cfg(feature = "smol")]
+impl<T: Transport> AsyncWrite for Vchan<'_, T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            match self.try_write(buf)? {
+                Some(len) => return Poll::Ready(Ok(len)),
+                None => ready!(self.poll_notified(cx, WRITING))?,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// The vchan is only closed when dropped.
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: Transport> Drop for Vchan<'_, T> {
+    fn drop(&mut self) {
+        let live = if self.is_server { SRV_LIVE } else { CLI_LIVE };
+
+        self.byte(live).store(0, Ordering::Release);
+        if let Err(e) = self.event.notify() {
+            log::warn!("Unable to notify vchan {} {e}", self.path)
+        }
+
+        if self.is_server
+            && let Err(e) = self.xs.rm(&self.path)
+        {
+            log::warn!("Unable to remove vchan {} {e}", self.path)
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! Recursive subtree walking.
+//!
+//! [`Walk`] lists a subtree with [`Xs::directory`], and yields the `(path, value)` pair of
+//! every node, as an [`Iterator`] or as a [`Stream`](futures::Stream) for [`AsyncXs`].
+//!
+//! The walk isn't atomic: nodes may be removed or added while walking, the errors it
+//! causes (e.g a node removed between listing and reading) are yielded or skipped, see
+//! [`OnError`].
+//!
+use std::{collections::VecDeque, error, fmt, io};
+
+#[cfg(feature = "futures")]
+use futures::{Stream, stream};
+#[cfg(feature = "futures")]
+use xenstore_rs::AsyncXs;
+use xenstore_rs::Xs;
+
+use crate::{Transport, XsWindows};
+
+/// Order in which [`Walk`] yields the nodes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Order {
+    /// A node, then the subtree of each of its children in turn.
+    #[default]
+    DepthFirst,
+    /// A node, then its children, then their children...
+    BreadthFirst,
+}
+
+/// What [`Walk`] does with the error of a node.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum OnError {
+    /// Yield the error, and carry on.
+    #[default]
+    Collect,
+    /// Log the error (at debug level), and carry on.
+    Skip,
+}
+
+/// Error on a node, yielded by [`Walk`].
+#[derive(Debug)]
+pub struct WalkError {
+    path: Box<str>,
+    error: io::Error,
+}
+
+impl WalkError {
+    /// Path of the node.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Error of the node.
+    pub fn error(&self) -> &io::Error {
+        &self.error
+    }
+
+    /// Get back the error of the node.
+    pub fn into_error(self) -> io::Error {
+        self.error
+    }
+}
+
+impl fmt::Display for WalkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.error)
+    }
+}
+
+impl error::Error for WalkError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<WalkError> for io::Error {
+    fn from(e: WalkError) -> Self {
+        io::Error::new(e.error.kind(), e)
+    }
+}
+
+/// Whether `path` matches the glob `pattern`.
+///
+/// `*` matches anything in a segment, `**` anything across segments and `?` a single
+/// character (but `/`).
+fn glob_match(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| glob_match(rest, &path[i..])),
+        [b'*', rest @ ..] => (0..=path.len())
+            .take_while(|&i| i == 0 || path[i - 1] != b'/')
+            .any(|i| glob_match(rest, &path[i..])),
+        [b'?', rest @ ..] => {
+            matches!(path, [c, tail @ ..] if *c != b'/' && glob_match(rest, tail))
+        }
+        [c, rest @ ..] => matches!(path, [d, tail @ ..] if c == d && glob_match(rest, tail)),
+    }
+}
+
+pub(crate) fn child_path(parent: &str, child: &str) -> Box<str> {
+    match parent.ends_with('/') {
+        true => format!("{parent}{child}").into(),
+        false => format!("{parent}/{child}").into(),
+    }
+}
+
+type Item = Result<(Box<str>, Box<str>), WalkError>;
+
+/// Walking state, shared by the blocking and async walks.
+struct Walker {
+    /// Nodes to visit, along with their depth.
+    queue: VecDeque<(Box<str>, usize)>,
+    /// Items of the last visited node.
+    pending: VecDeque<Item>,
+    order: Order,
+    max_depth: Option<usize>,
+    glob: Option<Box<str>>,
+    on_error: OnError,
+}
+
+impl Walker {
+    fn matches(&self, path: &str) -> bool {
+        self.glob
+            .as_ref()
+            .is_none_or(|glob| glob_match(glob.as_bytes(), path.as_bytes()))
+    }
+
+    fn error(&mut self, path: Box<str>, error: io::Error) {
+        match self.on_error {
+            OnError::Collect => self.pending.push_back(Err(WalkError { path, error })),
+            OnError::Skip => log::debug!("Skipping {path} ({error})"),
+        }
+    }
+
+    /// Visit the value of a node, returns whether its children should be listed.
+    fn value(&mut self, path: &str, depth: usize, value: io::Result<Box<str>>) -> bool {
+        let matches = self.matches(path);
+
+        match value {
+            Ok(value) => {
+                if matches {
+                    self.pending.push_back(Ok((path.into(), value)));
+                }
+            }
+            // Removed while walking, along with its children.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if matches {
+                    self.error(path.into(), e);
+                }
+                return false;
+            }
+            Err(e) => {
+                if matches {
+                    self.error(path.into(), e);
+                }
+            }
+        }
+
+        self.max_depth.is_none_or(|max_depth| depth < max_depth)
+    }
+
+    /// Visit the children of a node.
+    fn children(&mut self, path: &str, depth: usize, children: io::Result<Vec<Box<str>>>) {
+        let children = match children {
+            Ok(children) => children,
+            // Always reported, as it hides the subtree.
+            Err(e) => return self.error(path.into(), e),
+        };
+
+        let children = children
+            .iter()
+            .map(|child| (child_path(path, child), depth + 1));
+
+        match self.order {
+            Order::DepthFirst => {
+                for child in children.rev() {
+                    self.queue.push_front(child);
+                }
+            }
+            Order::BreadthFirst => self.queue.extend(children),
+        }
+    }
+}
+
+/// Walk of the subtree of a xenstore path.
+///
+/// Yields the root first, then its subtree in [`Order::DepthFirst`] order by default.
+/// Children are listed in the order given by [`Xs::directory`].
+pub struct Walk<'a, X: ?Sized> {
+    xs: &'a X,
+    walker: Walker,
+}
+
+impl<'a, X: ?Sized> Walk<'a, X> {
+    /// Walk the subtree of `path` on `xs`.
+    pub fn new(xs: &'a X, path: &str) -> Self {
+        Self {
+            xs,
+            walker: Walker {
+                queue: VecDeque::from([(path.into(), 0)]),
+                pending: VecDeque::new(),
+                order: Order::default(),
+                max_depth: None,
+                glob: None,
+                on_error: OnError::default(),
+            },
+        }
+    }
+
+    /// Use `order` instead of [`Order::DepthFirst`].
+    pub fn with_order(mut self, order: Order) -> Self {
+        self.walker.order = order;
+        self
+    }
+
+    /// Only walk nodes up to `max_depth` below the root (0 only yields the root).
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.walker.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Only yield the nodes whose path matches `glob` (e.g `/local/domain/*/name`).
+    ///
+    /// `*` matches anything in a segment, `**` anything across segments and `?` a single
+    /// character. The whole subtree is still walked, and errors listing a node are
+    /// reported even if it doesn't match.
+    pub fn with_glob(mut self, glob: &str) -> Self {
+        self.walker.glob = Some(glob.into());
+        self
+    }
+
+    /// Use `on_error` instead of [`OnError::Collect`].
+    pub fn with_errors(mut self, on_error: OnError) -> Self {
+        self.walker.on_error = on_error;
+        self
+    }
+}
+
+impl<X: Xs + ?Sized> Iterator for Walk<'_, X> {
+    type Item = Result<(Box<str>, Box<str>), WalkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.walker.pending.pop_front() {
+                return Some(item);
+            }
+
+            let (path, depth) = self.walker.queue.pop_front()?;
+
+            if self.walker.value(&path, depth, self.xs.read(&path)) {
+                let children = self.xs.directory(&path);
+                self.walker.children(&path, depth, children);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "futures")]
+impl<'a, X: AsyncXs + Sync + ?Sized> Walk<'a, X> {
+    /// Walk asynchronously.
+    pub fn stream(self) -> impl Stream<Item = Result<(Box<str>, Box<str>), WalkError>> + Send + 'a {
+        stream::unfold(self, |mut walk| async move {
+            loop {
+                if let Some(item) = walk.walker.pending.pop_front() {
+                    return Some((item, walk));
+                }
+
+                let (path, depth) = walk.walker.queue.pop_front()?;
+
+                if walk.walker.value(&path, depth, walk.xs.read(&path).await) {
+                    let children = walk.xs.directory(&path).await;
+                    walk.walker.children(&path, depth, children);
+                }
+            }
+        })
+    }
+}
+
+impl<T: Transport> XsWindows<T> {
+    /// Walk the subtree of `path` (see [`Walk`]).
+    pub fn walk(&self, path: &str) -> Walk<'_, Self> {
+        Walk::new(self, path)
+    }
+}
@@ -0,0 +1,258 @@
+//! Xenstore watches.
+//!
+//! [`Watch`] is blocking, [`WatchStream`] is its async counterpart, and is shared by
+//! the async backends as it doesn't depend on a specific runtime.
+//!
+use std::{
+    io,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+#[cfg(feature = "futures")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+#[cfg(feature = "futures")]
+use futures::Stream;
+
+use crate::{DefaultTransport, Event, Transport, WatchContext, XsWindows};
+
+/// Blocking watch on a xenstore path, created with [`XsWindows::watch`].
+///
+/// xeniface doesn't report which node changed, so the watched path is always the one
+/// reported. Events fired before one is received are coalesced into it, and how many were
+/// can't be known, as Win32 events don't count how many times they were signaled.
+/// The watch is removed when dropped.
+pub struct Watch<T: Transport = DefaultTransport> {
+    state: Arc<WatchState<T>>,
+}
+
+/// Registration of a watch, shared with [`Watches`].
+pub(crate) struct WatchState<T: Transport> {
+    path: Box<str>,
+    event: T::Event,
+    /// Device the watch is registered on, `None` once the watch is destroyed.
+    registration: Mutex<Option<(XsWindows<T>, WatchContext)>>,
+}
+
+/// Watches of an [`XsWindows`] and its clones.
+pub(crate) struct Watches<T: Transport>(Mutex<Vec<Weak<WatchState<T>>>>);
+
+impl<T: Transport> Default for Watches<T> {
+    fn default() -> Self {
+        Self(Mutex::default())
+    }
+}
+
+impl<T: Transport> XsWindows<T> {
+    /// Watch `path` and its subtree.
+    ///
+    /// Like with xenstored, the watch fires once when registered (whether the driver
+    /// does it or not).
+    ///
+    /// Watches aren't registered again by themselves after the guest is resumed (e.g live
+    /// migration), and stay silent. Callers must drive a
+    /// [`SuspendWatcher`](crate::SuspendWatcher) built with
+    /// [`with_rewatch`](crate::SuspendWatcher::with_rewatch), or call [`XsWindows::rewatch`]
+    /// on each resume.
+    pub fn watch(&self, path: &str) -> io::Result<Watch<T>> {
+        // We want a clone of the device handle to be able to destroy the watch.
+        let device = self.try_clone()?;
+        let (event, context) = self.make_watch(path)?;
+
+        Ok(self.track_watch(path, event, (device, context)))
+    }
+
+    /// Async counterpart of [`XsWindows::watch`], registering the watch with an overlapped
+    /// ioctl.
+    #[cfg(feature = "futures")]
+    pub(crate) async fn watch_async(&self, path: &str) -> io::Result<Watch<T>> {
+        let device = self.try_clone()?;
+        let (event, context) = self.make_watch_async(path).await?;
+
+        Ok(self.track_watch(path, event, (device, context)))
+    }
+
+    /// Track a registered watch, so that [`XsWindows::rewatch`] registers it again.
+    fn track_watch(
+        &self,
+        path: &str,
+        event: T::Event,
+        registration: (XsWindows<T>, WatchContext),
+    ) -> Watch<T> {
+        let state = Arc::new(WatchState {
+            path: path.into(),
+            event,
+            registration: Mutex::new(Some(registration)),
+        });
+
+        let mut watches = self.1.0.lock().unwrap();
+        watches.retain(|watch| watch.strong_count() > 0);
+        watches.push(Arc::downgrade(&state));
+        drop(watches);
+
+        Watch { state }
+    }
+
+    /// Register again the active watches of this device and its clones (e.g after a resume),
+    /// and make them fire.
+    ///
+    /// See also [`SuspendWatcher::with_rewatch`](crate::SuspendWatcher::with_rewatch).
+    pub fn rewatch(&self) -> io::Result<()> {
+        let watches: Vec<_> = self.1.0.lock().unwrap().clone();
+
+        for state in watches.iter().filter_map(Weak::upgrade) {
+            // Held so that the watch isn't destroyed meanwhile.
+            let mut registration = state.registration.lock().unwrap();
+            let Some((old_device, old)) = registration.take() else {
+                continue;
+            };
+
+            // Kept on the old device until registered again, to be destroyed on drop.
+            let new = self
+                .try_clone()
+                .and_then(|device| Ok((device, self.add_watch(&state.path, &state.event)?)));
+            let new = match new {
+                Ok(new) => new,
+                Err(e) => {
+                    *registration = Some((old_device, old));
+                    return Err(e);
+                }
+            };
+            *registration = Some(new);
+
+            // The driver may have dropped it already (e.g the device was removed).
+            if let Err(e) = old_device.destroy_watch(old) {
+                log::debug!("Unable to destroy stale watch object {e}")
+            }
+
+            state.event.set()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: Transport> Watch<T> {
+    /// Watched path.
+    pub fn path(&self) -> &str {
+        &self.state.path
+    }
+
+    // The event is re-armed before reporting, so that a watch firing afterward
+    // is reported by the next receive (at-least-once delivery).
+    fn consume(&self) -> io::Result<Box<str>> {
+        self.state.event.reset()?;
+        Ok(self.state.path.clone())
+    }
+
+    /// Poll until the watch fires.
+    #[cfg(feature = "futures")]
+    pub(crate) fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<io::Result<Box<str>>> {
+        ready!(self.state.event.poll_wait(cx))?;
+        Poll::Ready(self.consume())
+    }
+
+    /// Block until the watch fires.
+    pub fn recv(&self) -> io::Result<Box<str>> {
+        self.state.event.wait(None)?;
+        self.consume()
+    }
+
+    /// Block until the watch fires, or `timeout` expires.
+    ///
+    /// Returns `None` on timeout.
+    pub fn recv_timeout(&self, timeout: Duration) -> io::Result<Option<Box<str>>> {
+        if self.state.event.wait(Some(timeout))? {
+            self.consume().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Check whether the watch fired, without blocking.
+    pub fn try_recv(&self) -> io::Result<Option<Box<str>>> {
+        self.recv_timeout(Duration::ZERO)
+    }
+
+    /// Block until one of `watches` fires, or `timeout` expires.
+    ///
+    /// Returns the index of the watch that fired and its path, or `None` on timeout.
+    /// With xeniface, at most 64 watches can be waited for at once (see
+    /// [`WATCH_SET_CAPACITY`](crate::WATCH_SET_CAPACITY)).
+    pub fn recv_any(
+        watches: &[&Self],
+        timeout: Option<Duration>,
+    ) -> io::Result<Option<(usize, Box<str>)>> {
+        let events: Vec<&T::Event> = watches.iter().map(|w| &w.state.event).collect();
+
+        match T::Event::wait_any(&events, timeout)? {
+            Some(index) => Ok(Some((index, watches[index].consume()?))),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<T: Transport> Watch<T> {
+    /// Turn the watch into a [`Stream`].
+    #[cfg(feature = "futures")]
+    pub fn into_stream(self) -> WatchStream<T> {
+        WatchStream(self)
+    }
+}
+
+impl<T: Transport> Iterator for Watch<T> {
+    type Item = Box<str>;
+
+    /// Block until the watch fires, stops on error.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv()
+            .inspect_err(|e| log::error!("Unable to wait for watch: {e}"))
+            .ok()
+    }
+}
+
+impl<T: Transport> Drop for Watch<T> {
+    fn drop(&mut self) {
+        let registration = self.state.registration.lock().unwrap().take();
+
+        if let Some((device, context)) = registration
+            && let Err(e) = device.destroy_watch(context)
+        {
+            log::warn!("Unable to destroy watch object {e}")
+        }
+    }
+}
+
+/// Async watch on a xenstore path, created with [`Watch::into_stream`].
+///
+/// Stops on error. The watch is removed when dropped.
+#[cfg(feature = "futures")]
+pub struct WatchStream<T: Transport = DefaultTransport>(Watch<T>);
+
+#[cfg(feature = "futures")]
+impl<T: Transport> WatchStream<T> {
+    /// Watched path.
+    pub fn path(&self) -> &str {
+        self.0.path()
+    }
+}
+
+// Never pinned structurally.
+#[cfg(feature = "futures")]
+impl<T: Transport> Unpin for WatchStream<T> {}
+
+#[cfg(feature = "futures")]
+impl<T: Transport> Stream for WatchStream<T> {
+    type Item = Box<str>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(
+            ready!(self.0.poll_recv(cx))
+                .inspect_err(|e| log::error!("Unable to wait for watch: {e}"))
+                .ok(),
+        )
+    }
+}
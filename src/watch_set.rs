@@ -0,0 +1,348 @@
+//! Multiplexed xenstore watches.
+//!
+//! xeniface only signals that something changed under a watched path. A [`WatchSet`]
+//! receives many watches at once, each identified by a user token, and can diff the
+//! watched subtree to report which nodes actually changed.
+//!
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "futures")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+#[cfg(feature = "futures")]
+use futures::{FutureExt, Stream, future::BoxFuture};
+use xenstore_rs::Xs;
+
+use crate::{DefaultTransport, Transport, Watch, XsError, XsWindows};
+
+/// Values of a subtree, by path.
+type Snapshot = BTreeMap<Box<str>, Box<str>>;
+
+/// Maximum number of watches in a [`WatchSet`].
+///
+/// Waiting for many Win32 events at once is limited to `MAXIMUM_WAIT_OBJECTS` of them.
+pub const WATCH_SET_CAPACITY: usize = 64;
+
+/// Set of watches identified by tokens of type `K`, received together.
+///
+/// Events are `(token, path)` pairs, `path` being the watched path unless the watch
+/// was added with [`WatchSet::add_diffed`]. A set holds at most [`WATCH_SET_CAPACITY`]
+/// watches.
+pub struct WatchSet<K, T: Transport = DefaultTransport> {
+    device: Arc<XsWindows<T>>,
+    entries: Vec<Entry<K, T>>,
+    pending: VecDeque<(K, Box<str>)>,
+}
+
+struct Entry<K, T: Transport> {
+    token: K,
+    watch: Watch<T>,
+    mode: Mode,
+}
+
+enum Mode {
+    Plain,
+    Diffed {
+        /// Snapshot of the subtree as of the last event (`None` before the first one).
+        previous: Option<Snapshot>,
+        /// Overlapped read of the subtree after the watch fired, for the [`Stream`].
+        #[cfg(feature = "futures")]
+        reading: Option<BoxFuture<'static, io::Result<Snapshot>>>,
+    },
+}
+
+impl Mode {
+    fn diffed() -> Self {
+        Self::Diffed {
+            previous: None,
+            #[cfg(feature = "futures")]
+            reading: None,
+        }
+    }
+}
+
+impl<K: Clone, T: Transport> WatchSet<K, T> {
+    /// Create an empty set of watches on `xs`.
+    pub fn new(xs: &XsWindows<T>) -> io::Result<Self> {
+        Ok(Self {
+            device: Arc::new(xs.try_clone()?),
+            entries: Vec::new(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Watch `path`, reporting its events with `token`.
+    ///
+    /// Fails with [`XsError::QuotaExceeded`] if the set already holds
+    /// [`WATCH_SET_CAPACITY`] watches.
+    pub fn add(&mut self, token: K, path: &str) -> io::Result<()> {
+        self.add_entry(token, path, Mode::Plain)
+    }
+
+    /// Watch `path`, reporting the paths of the nodes that changed in its subtree
+    /// (created, modified or removed) with `token`.
+    ///
+    /// The subtree is read each time the watch fires, and compared to the previous read.
+    /// The initial event reports `path` itself. Same limit as [`WatchSet::add`].
+    pub fn add_diffed(&mut self, token: K, path: &str) -> io::Result<()> {
+        self.add_entry(token, path, Mode::diffed())
+    }
+
+    fn add_entry(&mut self, token: K, path: &str, mode: Mode) -> io::Result<()> {
+        if self.entries.len() >= WATCH_SET_CAPACITY {
+            return Err(XsError::QuotaExceeded.into());
+        }
+
+        let watch = self.device.watch(path)?;
+        self.entries.push(Entry { token, watch, mode });
+        Ok(())
+    }
+
+    /// Remove the watches of `token`, dropping their pending events.
+    ///
+    /// Returns `false` if there was none.
+    pub fn remove(&mut self, token: &K) -> bool
+    where
+        K: PartialEq,
+    {
+        let len = self.entries.len();
+
+        self.entries.retain(|entry| entry.token != *token);
+        self.pending.retain(|(pending, _)| pending != token);
+
+        self.entries.len() != len
+    }
+
+    /// Number of watches.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether there is no watch.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Queue the events of the `index` watch, which fired for `path`.
+    fn fired(&mut self, index: usize, path: Box<str>) -> io::Result<()> {
+        let entry = &mut self.entries[index];
+
+        match &mut entry.mode {
+            Mode::Plain => self.pending.push_back((entry.token.clone(), path)),
+            Mode::Diffed { previous, .. } => {
+                let snapshot = read_subtree(&self.device, &path)?;
+                push_diff(&mut self.pending, &entry.token, previous, path, snapshot);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Block until one of the watches fires, or `timeout` expires.
+    ///
+    /// Returns `None` on timeout. Fails with [`XsError::InvalidArgument`] if the set is empty.
+    pub fn recv_timeout(&mut self, timeout: Option<Duration>) -> io::Result<Option<(K, Box<str>)>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(Some(event));
+            }
+
+            if self.entries.is_empty() {
+                return Err(XsError::InvalidArgument.into());
+            }
+
+            let timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            let watches: Vec<&Watch<T>> = self.entries.iter().map(|e| &e.watch).collect();
+
+            match Watch::recv_any(&watches, timeout)? {
+                Some((index, path)) => self.fired(index, path)?,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Block until one of the watches fires.
+    pub fn recv(&mut self) -> io::Result<(K, Box<str>)> {
+        // Can't time out.
+        Ok(self.recv_timeout(None)?.unwrap())
+    }
+}
+
+/// Queue the events of a diffed watch on `path`, given the subtree read after it fired.
+fn push_diff<K: Clone>(
+    pending: &mut VecDeque<(K, Box<str>)>,
+    token: &K,
+    previous: &mut Option<Snapshot>,
+    path: Box<str>,
+    snapshot: Snapshot,
+) {
+    match previous {
+        Some(previous) => {
+            pending.extend(changed_paths(previous, &snapshot).map(|p| (token.clone(), p)))
+        }
+        None => pending.push_back((token.clone(), path)),
+    }
+
+    *previous = Some(snapshot);
+}
+
+/// Read the values of all the nodes under `path` (included).
+///
+/// A missing `path` gives an empty snapshot.
+fn read_subtree<T: Transport>(xs: &XsWindows<T>, path: &str) -> io::Result<Snapshot> {
+    let mut snapshot = Snapshot::new();
+    let mut queue = vec![Box::<str>::from(path)];
+
+    while let Some(path) = queue.pop() {
+        // Nodes can be removed while we walk the tree.
+        let value = match xs.read(&path) {
+            Ok(value) => value,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let children = match xs.directory(&path) {
+            Ok(children) => children,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        let prefix = path.trim_end_matches('/');
+        queue.extend(children.iter().map(|c| format!("{prefix}/{c}").into()));
+        snapshot.insert(path, value);
+    }
+
+    Ok(snapshot)
+}
+
+/// Overlapped counterpart of [`read_subtree`].
+#[cfg(feature = "futures")]
+async fn read_subtree_async<T: Transport>(
+    xs: Arc<XsWindows<T>>,
+    path: Box<str>,
+) -> io::Result<Snapshot> {
+    let mut snapshot = Snapshot::new();
+    let mut queue = vec![path];
+
+    while let Some(path) = queue.pop() {
+        // Nodes can be removed while we walk the tree.
+        let value = match xs.read_async(&path).await {
+            Ok(value) => value,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let children = match xs.directory_async(&path).await {
+            Ok(children) => children,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        let prefix = path.trim_end_matches('/');
+        queue.extend(children.iter().map(|c| format!("{prefix}/{c}").into()));
+        snapshot.insert(path, value);
+    }
+
+    Ok(snapshot)
+}
+
+/// Paths created, modified or removed between `old` and `new`, sorted.
+fn changed_paths(old: &Snapshot, new: &Snapshot) -> impl Iterator<Item = Box<str>> {
+    let mut changed: Vec<Box<str>> = new
+        .iter()
+        .filter(|(path, value)| old.get(*path) != Some(*value))
+        .map(|(path, _)| path.clone())
+        .chain(old.keys().filter(|path| !new.contains_key(*path)).cloned())
+        .collect();
+
+    changed.sort_unstable();
+    changed.into_iter()
+}
+
+// Never pinned structurally.
+#[cfg(feature = "futures")]
+impl<K, T: Transport> Unpin for WatchSet<K, T> {}
+
+#[cfg(feature = "futures")]
+impl<K: Clone, T: Transport + 'static> WatchSet<K, T> {
+    /// Poll the `index` watch, queuing its events.
+    ///
+    /// Returns whether it made progress.
+    fn poll_entry(&mut self, index: usize, cx: &mut Context<'_>) -> io::Result<bool> {
+        let entry = &mut self.entries[index];
+
+        let Mode::Diffed { previous, reading } = &mut entry.mode else {
+            return match entry.watch.poll_recv(cx) {
+                Poll::Ready(path) => {
+                    self.pending.push_back((entry.token.clone(), path?));
+                    Ok(true)
+                }
+                Poll::Pending => Ok(false),
+            };
+        };
+
+        if reading.is_none() {
+            match entry.watch.poll_recv(cx) {
+                Poll::Ready(path) => {
+                    *reading = Some(read_subtree_async(self.device.clone(), path?).boxed())
+                }
+                Poll::Pending => return Ok(false),
+            }
+        }
+
+        // Set above if it wasn't.
+        let Poll::Ready(snapshot) = reading.as_mut().unwrap().poll_unpin(cx) else {
+            return Ok(false);
+        };
+        *reading = None;
+
+        let path = entry.watch.path().into();
+        push_diff(&mut self.pending, &entry.token, previous, path, snapshot?);
+        Ok(true)
+    }
+}
+
+/// Ends when the set is empty or on error.
+///
+/// Diffed watches read the subtree with overlapped ioctls.
+#[cfg(feature = "futures")]
+impl<K: Clone, T: Transport + 'static> Stream for WatchSet<K, T> {
+    type Item = (K, Box<str>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Poll::Ready(Some(event));
+            }
+
+            if self.entries.is_empty() {
+                return Poll::Ready(None);
+            }
+
+            let mut progress = false;
+
+            for index in 0..self.entries.len() {
+                match self.poll_entry(index, cx) {
+                    Ok(polled) => progress |= polled,
+                    Err(e) => {
+                        log::error!("Unable to wait for watches: {e}");
+                        return Poll::Ready(None);
+                    }
+                }
+            }
+
+            // A diffed watch may fire without any change.
+            if !progress {
+                return Poll::Pending;
+            }
+        }
+    }
+}
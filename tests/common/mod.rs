@@ -0,0 +1,176 @@
+//! Helpers shared by the integration tests.
+//!
+// Each test crate only uses some of them.
+#![allow(dead_code)]
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use xenstore_win::{
+    Completed, Event, Transport, XsWindows,
+    emulator::{EmulatedEvent, Emulator},
+};
+
+/// Raw handle of a [`FakeEvent`].
+pub const FAKE_EVENT_HANDLE: usize = 0x1234;
+
+/// Event that is never waited for.
+pub struct FakeEvent;
+
+impl Event for FakeEvent {
+    fn raw_handle(&self) -> usize {
+        FAKE_EVENT_HANDLE
+    }
+
+    fn set(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reset(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn poll_wait(&self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        unimplemented!()
+    }
+}
+
+/// Control code and input of an ioctl.
+pub type Ioctl = (u32, Vec<u8>);
+
+/// Gives the result of an ioctl, from its control code, input and output buffer (if any).
+type Output = dyn Fn(u32, &[u8], Option<&mut [u8]>) -> io::Result<u32> + Send + Sync;
+
+/// Transport recording the ioctls (shared by clones), their results given by a hook.
+///
+/// Overlapped ioctls complete when submitted, empty output buffers are given as `None`.
+#[derive(Clone)]
+pub struct Recorder {
+    ioctls: Arc<Mutex<Vec<Ioctl>>>,
+    output: Arc<Output>,
+}
+
+impl Recorder {
+    /// Record the ioctls, whose results are given by `output`.
+    pub fn new(
+        output: impl Fn(u32, &[u8], Option<&mut [u8]>) -> io::Result<u32> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            ioctls: Arc::default(),
+            output: Arc::new(output),
+        }
+    }
+
+    /// Take the ioctls recorded so far.
+    pub fn take(&self) -> Vec<Ioctl> {
+        std::mem::take(&mut self.ioctls.lock().unwrap())
+    }
+}
+
+impl Default for Recorder {
+    /// Ioctls succeed without any output.
+    fn default() -> Self {
+        Self::new(|_, _, _| Ok(0))
+    }
+}
+
+impl Transport for Recorder {
+    type Event = FakeEvent;
+    type Overlapped<'a> = Completed;
+
+    unsafe fn ioctl(
+        &self,
+        control_code: u32,
+        in_buffer: &[u8],
+        out_buffer: Option<&mut [u8]>,
+    ) -> io::Result<u32> {
+        self.ioctls
+            .lock()
+            .unwrap()
+            .push((control_code, in_buffer.to_vec()));
+
+        (self.output)(control_code, in_buffer, out_buffer)
+    }
+
+    unsafe fn submit(
+        &self,
+        control_code: u32,
+        in_buffer: Vec<u8>,
+        mut out_buffer: Vec<u8>,
+    ) -> io::Result<Completed> {
+        let out = (!out_buffer.is_empty()).then_some(&mut out_buffer[..]);
+        let result = unsafe { self.ioctl(control_code, &in_buffer, out) };
+
+        Ok(Completed::new(result.map(|len| {
+            out_buffer.truncate(len as usize);
+            out_buffer
+        })))
+    }
+
+    fn create_event(&self) -> io::Result<FakeEvent> {
+        Ok(FakeEvent)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(self.clone())
+    }
+}
+
+/// CTL_CODE(FILE_DEVICE_UNKNOWN, function, METHOD_BUFFERED, FILE_ANY_ACCESS)
+pub fn ctl_code(function: u32) -> u32 {
+    (0x22 << 16) | (function << 2)
+}
+
+/// Xenstore over a new emulator, along with a handle to inspect it.
+pub fn xs() -> (Emulator, XsWindows<Emulator>) {
+    let emulator = Emulator::new();
+    (emulator.clone(), XsWindows::with_transport(emulator))
+}
+
+/// Emulator that panics on blocking store operations, which must be overlapped instead.
+///
+/// Blocking watch registrations are allowed if the second field is set (e.g for a
+/// [`WatchSet`](xenstore_win::WatchSet)).
+#[derive(Clone, Default)]
+pub struct OverlappedStore(pub Emulator, pub bool);
+
+impl Transport for OverlappedStore {
+    type Event = EmulatedEvent;
+    type Overlapped<'a> = Completed;
+
+    unsafe fn ioctl(
+        &self,
+        control_code: u32,
+        in_buffer: &[u8],
+        out_buffer: Option<&mut [u8]>,
+    ) -> io::Result<u32> {
+        // IOCTL_XENIFACE_STORE_{READ,WRITE,DIRECTORY,REMOVE,SET_PERMISSIONS,ADD_WATCH}
+        let function = (control_code >> 2) & 0xfff;
+        assert!(
+            !(0x800..=0x805).contains(&function) || (function == 0x805 && self.1),
+            "blocking store ioctl {control_code:#x}"
+        );
+
+        unsafe { self.0.ioctl(control_code, in_buffer, out_buffer) }
+    }
+
+    unsafe fn submit(
+        &self,
+        control_code: u32,
+        in_buffer: Vec<u8>,
+        out_buffer: Vec<u8>,
+    ) -> io::Result<Completed> {
+        unsafe { self.0.submit(control_code, in_buffer, out_buffer) }
+    }
+
+    fn create_event(&self) -> io::Result<EmulatedEvent> {
+        self.0.create_event()
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(self.clone())
+    }
+}
@@ -0,0 +1,162 @@
+use std::{io, sync::Mutex};
+
+use xenstore_rs::{AsyncXs, Xs};
+use xenstore_win::{DomainXs, XsWindows, domain_path, emulator::Emulator};
+
+/// Xenstore recording the paths it is given, every node reads as `5`.
+#[derive(Default)]
+struct Recorder(Mutex<Vec<String>>);
+
+impl Recorder {
+    fn take(&self) -> Vec<String> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+
+    fn record(&self, path: &str) {
+        self.0.lock().unwrap().push(path.to_string());
+    }
+}
+
+impl Xs for Recorder {
+    fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        self.record(path);
+        Ok(vec![])
+    }
+
+    fn read(&self, path: &str) -> io::Result<Box<str>> {
+        self.record(path);
+        Ok("5".into())
+    }
+
+    fn write(&self, path: &str, _: &str) -> io::Result<()> {
+        self.record(path);
+        Ok(())
+    }
+
+    fn rm(&self, path: &str) -> io::Result<()> {
+        self.record(path);
+        Ok(())
+    }
+}
+
+impl AsyncXs for Recorder {
+    async fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        Xs::directory(self, path)
+    }
+
+    async fn read(&self, path: &str) -> io::Result<Box<str>> {
+        Xs::read(self, path)
+    }
+
+    async fn write(&self, path: &str, data: &str) -> io::Result<()> {
+        Xs::write(self, path, data)
+    }
+
+    async fn rm(&self, path: &str) -> io::Result<()> {
+        Xs::rm(self, path)
+    }
+}
+
+#[test]
+fn resolve() {
+    let xs = DomainXs::new(Recorder::default()).unwrap();
+    assert_eq!(xs.inner().take(), ["domid"]);
+    assert_eq!(xs.domid(), 5);
+    assert_eq!(xs.home(), "/local/domain/5");
+    assert_eq!(xs.domain_path(0), "/local/domain/0");
+    assert_eq!(domain_path(7), "/local/domain/7");
+
+    Xs::read(&xs, "data/key").unwrap();
+    Xs::write(&xs, "/local/domain/0/name", "value").unwrap();
+    Xs::directory(&xs, "").unwrap();
+    Xs::rm(&xs, "/").unwrap();
+    assert_eq!(
+        xs.inner().take(),
+        [
+            "/local/domain/5/data/key",
+            "/local/domain/0/name",
+            "/local/domain/5",
+            "/"
+        ]
+    );
+
+    assert_eq!(&*xs.vm_path().unwrap(), "5");
+    assert_eq!(xs.inner().take(), ["/local/domain/5/vm"]);
+}
+
+#[test]
+fn invalid() {
+    let xs = DomainXs::with_domid(Recorder::default(), 5);
+
+    for path in [
+        "..",
+        "data/../key",
+        "/local/./domain",
+        "data//key",
+        "data/",
+        "//",
+        "/local/domain/",
+        "data key",
+        "data/k\u{e9}y",
+        "data\0",
+        "data/@key",
+        "/@releaseDomain",
+        "@",
+        "@@releaseDomain",
+    ] {
+        let e = Xs::read(&xs, path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{path:?}");
+    }
+
+    assert!(xs.inner().take().is_empty());
+    assert!(xs.resolve("data-1/key_2").is_ok());
+
+    // Special paths are kept as is.
+    assert_eq!(xs.resolve("@releaseDomain").unwrap(), "@releaseDomain");
+    assert_eq!(xs.resolve("@introduceDomain").unwrap(), "@introduceDomain");
+}
+
+#[test]
+fn invalid_domid() {
+    let emulator = Emulator::new();
+    emulator.set("/local/domain/0/domid", "dom0");
+
+    let e = DomainXs::new(XsWindows::with_transport(emulator))
+        .err()
+        .unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn emulator() {
+    let emulator = Emulator::with_domid(5);
+    emulator.set("/local/domain/5/domid", "5");
+    emulator.set("/local/domain/5/vm", "/vm/0123");
+
+    let xs = DomainXs::new(XsWindows::with_transport(emulator.clone())).unwrap();
+    assert_eq!(&*xs.vm_path().unwrap(), "/vm/0123");
+
+    xs.write("data/key", "value").unwrap();
+    assert_eq!(emulator.get("/local/domain/5/data/key").unwrap(), b"value");
+
+    let watch = xs.watch("data").unwrap();
+    assert_eq!(watch.path(), "/local/domain/5/data");
+    assert!(xs.watch("data/").is_err());
+}
+
+#[test]
+fn async_xs() {
+    smol::block_on(async {
+        let xs = DomainXs::new_async(Recorder::default()).await.unwrap();
+        assert_eq!(xs.domid(), 5);
+
+        AsyncXs::write(&xs, "data/key", "value").await.unwrap();
+        assert_eq!(&*xs.vm_path_async().await.unwrap(), "5");
+        assert!(AsyncXs::rm(&xs, "data//key").await.is_err());
+
+        assert_eq!(
+            xs.inner().take(),
+            ["domid", "/local/domain/5/data/key", "/local/domain/5/vm"]
+        );
+    });
+}
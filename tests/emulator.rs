@@ -0,0 +1,194 @@
+mod common;
+
+use std::io;
+
+use xenstore_rs::Xs;
+use xenstore_win::{Transport, XsError, XsWindows, emulator::Emulator};
+
+use common::{Recorder, xs};
+
+#[test]
+fn read_write() {
+    let (emulator, xs) = xs();
+
+    xs.write("/local/domain/1/name", "guest").unwrap();
+    assert_eq!(&*xs.read("/local/domain/1/name").unwrap(), "guest");
+    assert_eq!(emulator.get("/local/domain/1/name").unwrap(), b"guest");
+
+    xs.write("/local/domain/1/name", "").unwrap();
+    assert_eq!(&*xs.read("/local/domain/1/name").unwrap(), "");
+}
+
+#[test]
+fn implicit_parents() {
+    let (emulator, xs) = xs();
+
+    xs.write("/a/b/c", "value").unwrap();
+
+    assert_eq!(emulator.get("/a").unwrap(), b"");
+    assert_eq!(emulator.get("/a/b").unwrap(), b"");
+    assert_eq!(&*xs.directory("/").unwrap(), ["a".into()]);
+    assert_eq!(&*xs.directory("/a").unwrap(), ["b".into()]);
+}
+
+#[test]
+fn directory() {
+    let (_, xs) = xs();
+
+    xs.write("/dir/x", "1").unwrap();
+    xs.write("/dir/y/z", "2").unwrap();
+    xs.write("/dir2", "3").unwrap();
+
+    assert_eq!(&*xs.directory("/dir").unwrap(), ["x".into(), "y".into()]);
+    assert!(xs.directory("/dir/x").unwrap().is_empty());
+}
+
+#[test]
+fn relative_paths() {
+    let emulator = Emulator::with_domid(7);
+    let xs = XsWindows::with_transport(emulator.clone());
+
+    xs.write("data/key", "value").unwrap();
+    assert_eq!(emulator.get("/local/domain/7/data/key").unwrap(), b"value");
+    assert_eq!(&*xs.read("data/key").unwrap(), "value");
+}
+
+#[test]
+fn rm() {
+    let (emulator, xs) = xs();
+
+    xs.write("/a/b/c", "value").unwrap();
+    xs.write("/a/d", "value").unwrap();
+    xs.rm("/a/b").unwrap();
+
+    assert!(emulator.get("/a/b").is_none());
+    assert!(emulator.get("/a/b/c").is_none());
+    assert_eq!(&*xs.directory("/a").unwrap(), ["d".into()]);
+}
+
+#[test]
+fn missing_node() {
+    let (_, xs) = xs();
+
+    for e in [
+        xs.read("/missing").unwrap_err(),
+        xs.directory("/missing").unwrap_err(),
+        xs.rm("/missing").unwrap_err(),
+    ] {
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(matches!(XsError::from(e), XsError::NotFound));
+    }
+}
+
+#[test]
+fn invalid_path() {
+    let (_, xs) = xs();
+
+    for path in ["", "/a//b", "/a/", "/a b"] {
+        let e = xs.write(path, "value").unwrap_err();
+        assert!(
+            matches!(XsError::from(e), XsError::InvalidArgument),
+            "{path:?}"
+        );
+    }
+}
+
+#[test]
+fn large_value() {
+    let (emulator, xs) = xs();
+
+    for len in [255, 256, 1000, 4095, 4096] {
+        let value = "v".repeat(len);
+        emulator.set("/large", &value);
+        assert_eq!(*xs.read("/large").unwrap(), value);
+    }
+
+    emulator.set("/large", "v".repeat(4097));
+    let e = xs.read("/large").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::FileTooLarge);
+    assert!(matches!(XsError::from(e), XsError::TooBig));
+}
+
+#[test]
+fn large_value_ioctls() {
+    let emulator = Emulator::new();
+    let forwarded = emulator.clone();
+    let recorder = Recorder::new(move |control_code, in_buffer, out_buffer| unsafe {
+        forwarded.ioctl(control_code, in_buffer, out_buffer)
+    });
+    let xs = XsWindows::with_transport(recorder.clone());
+
+    let value = "v".repeat(4000);
+    emulator.set("/large", &value);
+    assert_eq!(*xs.read("/large").unwrap(), value);
+    assert_eq!(recorder.take().len(), 1);
+
+    // Too large, probed once more to tell it from an invalid parameter.
+    emulator.set("/large", "v".repeat(4097));
+    assert!(xs.read("/large").is_err());
+    assert_eq!(recorder.take().len(), 2);
+}
+
+#[test]
+fn large_directory() {
+    let (emulator, xs) = xs();
+
+    // 8 bytes per entry, plus the final NUL.
+    for i in 0..512 {
+        emulator.set(&format!("/large/e{i:06}"), "");
+    }
+    assert_eq!(xs.directory("/large").unwrap().len(), 512);
+
+    emulator.set("/large/overflow", "");
+    let e = xs.directory("/large").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::FileTooLarge);
+}
+
+#[test]
+fn invalid_read_is_not_too_large() {
+    let (_, xs) = xs();
+
+    let e = xs.read("/a//b").unwrap_err();
+    assert!(matches!(XsError::from(e), XsError::InvalidArgument));
+}
+
+#[test]
+fn bytes() {
+    let (emulator, xs) = xs();
+
+    let value = b"\xff\xfe\x80value\n";
+    xs.write_bytes("/bytes", value).unwrap();
+    assert_eq!(emulator.get("/bytes").unwrap(), value);
+    assert_eq!(xs.read_bytes("/bytes").unwrap(), value);
+    assert_eq!(
+        xs.read_lossy("/bytes").unwrap(),
+        "\u{fffd}\u{fffd}\u{fffd}value\n"
+    );
+
+    // Not UTF-8, so not readable as a string.
+    assert!(xs.read("/bytes").is_err());
+
+    xs.write_bytes("/bytes", b"").unwrap();
+    assert!(xs.read_bytes("/bytes").unwrap().is_empty());
+}
+
+#[test]
+fn bytes_nul() {
+    let (emulator, xs) = xs();
+
+    // Rejected before reaching the driver.
+    for e in [
+        xs.write_bytes("/bytes", b"a\0b").unwrap_err(),
+        xs.write_bytes("/bytes", b"\0").unwrap_err(),
+        xs.write("/bytes", "a\0").unwrap_err(),
+        // Would write the value to /a.
+        xs.write_bytes("/a\0b", b"data").unwrap_err(),
+        xs.write("/a\0b", "data").unwrap_err(),
+    ] {
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(e.raw_os_error().is_none());
+        assert!(matches!(XsError::from(e), XsError::InvalidArgument));
+    }
+    assert!(emulator.get("/bytes").is_none());
+    assert!(emulator.get("/a").is_none());
+}
@@ -0,0 +1,160 @@
+use std::{error::Error, ffi::OsStr, io};
+
+use xenstore_win::{OpenError, OpenFailure, XsError, XsWindows, emulator::Emulator, win32_error};
+
+#[test]
+fn win32_mapping() {
+    for (code, errno) in [
+        (2, "ENOENT"),
+        (3, "ENOENT"),
+        (5, "EACCES"),
+        (80, "EEXIST"),
+        (183, "EEXIST"),
+        (1816, "EQUOTA"),
+        (1237, "EAGAIN"),
+        (87, "EINVAL"),
+    ] {
+        assert_eq!(
+            XsError::from_win32(code).errno_name(),
+            Some(errno),
+            "{code}"
+        );
+    }
+
+    for code in [55, 433, 1167, 1617] {
+        assert!(matches!(XsError::from_win32(code), XsError::DeviceGone));
+    }
+
+    assert!(matches!(XsError::from_win32(31), XsError::Os(31)));
+    // ERROR_BUFFER_OVERFLOW is "file name too long", not a xenstore E2BIG.
+    assert!(matches!(XsError::from_win32(111), XsError::Os(111)));
+}
+
+#[test]
+fn io_error_kind() {
+    for (error, kind) in [
+        (XsError::NotFound, io::ErrorKind::NotFound),
+        (XsError::PermissionDenied, io::ErrorKind::PermissionDenied),
+        (XsError::AlreadyExists, io::ErrorKind::AlreadyExists),
+        (XsError::TooBig, io::ErrorKind::FileTooLarge),
+        (XsError::QuotaExceeded, io::ErrorKind::QuotaExceeded),
+        (XsError::Again, io::ErrorKind::WouldBlock),
+        (XsError::InvalidArgument, io::ErrorKind::InvalidInput),
+        (XsError::DeviceGone, io::ErrorKind::NotConnected),
+        (XsError::NoDevice, io::ErrorKind::NotFound),
+    ] {
+        assert_eq!(io::Error::from(error).kind(), kind);
+    }
+}
+
+#[test]
+fn io_error_roundtrip() {
+    let e = io::Error::from(XsError::QuotaExceeded);
+    assert_eq!(e.to_string(), "quota exceeded (EQUOTA)");
+    assert!(matches!(XsError::from(e), XsError::QuotaExceeded));
+
+    let e = io::Error::from(XsError::Os(31));
+    #[cfg(windows)]
+    assert_eq!(e.raw_os_error(), Some(31));
+    assert!(matches!(XsError::from(e), XsError::Os(31)));
+
+    let e = io::Error::from(XsError::Io(io::Error::other("custom")));
+    assert_eq!(e.to_string(), "custom");
+    assert!(matches!(XsError::from(e), XsError::Io(_)));
+
+    // Win32 errors from a transport.
+    let e = win32_error(2);
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    assert!(matches!(XsError::from(e), XsError::NotFound));
+
+    // Errnos aren't Win32 codes (ENOTCONN would be ERROR_DEVICE_REMOVED otherwise).
+    #[cfg(not(windows))]
+    {
+        let e = XsError::from(io::Error::from_raw_os_error(107));
+        assert!(matches!(e, XsError::Io(_)));
+        assert_eq!(e.kind(), io::ErrorKind::NotConnected);
+    }
+}
+
+#[test]
+fn source() {
+    let e = XsError::Io(io::Error::other("custom"));
+    assert_eq!(e.source().unwrap().to_string(), "custom");
+
+    assert!(XsError::NotFound.source().is_none());
+}
+
+#[test]
+fn open_failure() {
+    for code in [2, 3, 55, 433, 1167, 1617] {
+        let failure = OpenFailure::from(win32_error(code));
+        assert!(matches!(failure, OpenFailure::NotPresent), "{code}");
+    }
+
+    let failure = OpenFailure::from(win32_error(5));
+    assert!(matches!(failure, OpenFailure::AccessDenied));
+    let failure = OpenFailure::from(win32_error(32));
+    assert!(matches!(failure, OpenFailure::SharingViolation));
+    let failure = OpenFailure::from(io::Error::other("custom"));
+    assert_eq!(failure.to_string(), "custom");
+}
+
+#[test]
+fn open_first() {
+    let mut tried = vec![];
+    let xs = XsWindows::open_first(["a", "b", "c"], |path| {
+        tried.push(path.to_owned());
+
+        match path.to_str() {
+            Some("a") => Err(win32_error(5)),
+            _ => Ok(Emulator::new()),
+        }
+    });
+    assert!(xs.is_ok());
+    assert_eq!(tried, ["a", "b"]);
+
+    let e = XsWindows::<Emulator>::open_first(["a", "b", "c"], |path| {
+        Err(match path.to_str() {
+            Some("a") => win32_error(5),
+            Some("b") => win32_error(32),
+            _ => win32_error(2),
+        })
+    })
+    .err()
+    .unwrap();
+
+    assert_eq!(
+        e.to_string(),
+        "no usable xeniface device (a: access denied; b: sharing violation; c: not present)"
+    );
+    let attempts: Vec<_> = e
+        .attempts()
+        .iter()
+        .map(|(path, _)| path.as_os_str())
+        .collect();
+    assert_eq!(
+        attempts,
+        [OsStr::new("a"), OsStr::new("b"), OsStr::new("c")]
+    );
+
+    let e = io::Error::from(e);
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    assert!(matches!(XsError::from(e), XsError::Io(_)));
+}
+
+#[test]
+fn open_no_device() {
+    let e = XsWindows::<Emulator>::open_first(Vec::<&str>::new(), |_| unreachable!())
+        .err()
+        .unwrap();
+    assert_eq!(e.to_string(), "no xeniface device found");
+    assert!(e.attempts().is_empty());
+    assert!(matches!(XsError::from(e), XsError::NoDevice));
+
+    let e = OpenError::Enumeration(io::ErrorKind::PermissionDenied.into());
+    assert!(
+        e.to_string()
+            .starts_with("unable to enumerate xeniface devices (")
+    );
+    assert_eq!(io::Error::from(e).kind(), io::ErrorKind::PermissionDenied);
+}
@@ -0,0 +1,171 @@
+mod common;
+
+use std::time::Duration;
+
+use xenstore_win::{XsWindows, emulator::Emulator, evtchn::EventChannel};
+
+use common::{FAKE_EVENT_HANDLE, Recorder, ctl_code};
+
+const TIMEOUT: Duration = Duration::from_millis(20);
+const HANDLE: usize = size_of::<usize>();
+const PORT: u32 = 42;
+
+/// Transport recording the ioctls, binding always gives `PORT`.
+fn recorder() -> Recorder {
+    Recorder::new(|_, _, out_buffer| match out_buffer {
+        Some(out_buffer) => {
+            out_buffer.copy_from_slice(&PORT.to_ne_bytes());
+            Ok(4)
+        }
+        None => Ok(0),
+    })
+}
+
+#[test]
+fn bind_unbound_encoding() {
+    let xs = XsWindows::with_transport(recorder());
+    let channel = EventChannel::bind_unbound(&xs, 7, true).unwrap();
+    assert_eq!(channel.port(), PORT);
+
+    let [(control_code, in_buffer)] = &xs.transport().take()[..] else {
+        panic!("expected a single ioctl");
+    };
+    assert_eq!(*control_code, ctl_code(0x810));
+
+    // { USHORT RemoteDomain; HANDLE Event; BOOLEAN Mask; } with natural alignment.
+    let mut expected = vec![0; 3 * HANDLE];
+    expected[..2].copy_from_slice(&7u16.to_ne_bytes());
+    expected[HANDLE..2 * HANDLE].copy_from_slice(&FAKE_EVENT_HANDLE.to_ne_bytes());
+    expected[2 * HANDLE] = 1;
+    assert_eq!(*in_buffer, expected);
+}
+
+#[test]
+fn bind_interdomain_encoding() {
+    let xs = XsWindows::with_transport(recorder());
+    let channel = EventChannel::bind_interdomain(&xs, 3, 0x01020304, false).unwrap();
+    assert_eq!(channel.port(), PORT);
+
+    let [(control_code, in_buffer)] = &xs.transport().take()[..] else {
+        panic!("expected a single ioctl");
+    };
+    assert_eq!(*control_code, ctl_code(0x811));
+
+    // { USHORT RemoteDomain; ULONG RemotePort; HANDLE Event; BOOLEAN Mask; }
+    let mut expected = vec![0; 8 + 2 * HANDLE];
+    expected[..2].copy_from_slice(&3u16.to_ne_bytes());
+    expected[4..8].copy_from_slice(&0x01020304u32.to_ne_bytes());
+    expected[8..8 + HANDLE].copy_from_slice(&FAKE_EVENT_HANDLE.to_ne_bytes());
+    assert_eq!(*in_buffer, expected);
+}
+
+#[test]
+fn port_encoding() {
+    let xs = XsWindows::with_transport(recorder());
+    let channel = EventChannel::bind_unbound(&xs, 0, false).unwrap();
+    xs.transport().take();
+
+    channel.notify().unwrap();
+    channel.unmask().unwrap();
+    drop(channel);
+
+    // { ULONG LocalPort; }
+    let port = PORT.to_ne_bytes().to_vec();
+    assert_eq!(
+        xs.transport().take(),
+        [
+            (ctl_code(0x813), port.clone()),
+            (ctl_code(0x814), port.clone()),
+            (ctl_code(0x812), port),
+        ]
+    );
+}
+
+fn loopback(mask: bool) -> (Emulator, EventChannel<Emulator>, EventChannel<Emulator>) {
+    let emulator = Emulator::with_domid(5);
+    let xs = XsWindows::with_transport(emulator.clone());
+
+    let server = EventChannel::bind_unbound(&xs, 5, mask).unwrap();
+    let client = EventChannel::bind_interdomain(&xs, 5, server.port(), mask).unwrap();
+
+    (emulator, server, client)
+}
+
+#[test]
+fn notify() {
+    let (emulator, server, client) = loopback(false);
+    assert_eq!(emulator.port_count(), 2);
+    assert_ne!(server.port(), client.port());
+
+    assert!(!server.wait_timeout(TIMEOUT).unwrap());
+
+    client.notify().unwrap();
+    client.notify().unwrap();
+    // Coalesced.
+    assert!(server.wait_timeout(TIMEOUT).unwrap());
+    assert!(!server.wait_timeout(TIMEOUT).unwrap());
+    assert!(!client.wait_timeout(TIMEOUT).unwrap());
+
+    server.notify().unwrap();
+    client.wait().unwrap();
+}
+
+#[test]
+fn mask() {
+    let (_emulator, server, client) = loopback(true);
+
+    client.notify().unwrap();
+    assert!(server.wait_timeout(TIMEOUT).unwrap());
+
+    // Held until unmasked.
+    client.notify().unwrap();
+    assert!(!server.wait_timeout(TIMEOUT).unwrap());
+    server.unmask().unwrap();
+    assert!(server.wait_timeout(TIMEOUT).unwrap());
+}
+
+#[test]
+fn close() {
+    let (emulator, server, client) = loopback(false);
+    let xs = XsWindows::with_transport(emulator.clone());
+
+    // Already bound.
+    assert!(EventChannel::bind_interdomain(&xs, 5, server.port(), false).is_err());
+    // Only loopback channels can be emulated.
+    let other = EventChannel::bind_unbound(&xs, 1, false).unwrap();
+    assert!(EventChannel::bind_interdomain(&xs, 1, other.port(), false).is_err());
+    drop(other);
+
+    drop(client);
+    assert_eq!(emulator.port_count(), 1);
+
+    // The server port is unbound again.
+    server.notify().unwrap();
+    let client = EventChannel::bind_interdomain(&xs, 5, server.port(), false).unwrap();
+    client.notify().unwrap();
+    assert!(server.wait_timeout(TIMEOUT).unwrap());
+
+    drop((server, client));
+    assert_eq!(emulator.port_count(), 0);
+}
+
+#[cfg(feature = "smol")]
+#[test]
+fn stream() {
+    use futures::{FutureExt, StreamExt};
+
+    let (_emulator, mut server, client) = loopback(false);
+
+    smol::block_on(async {
+        assert!(server.next().now_or_never().is_none());
+
+        let notifier = std::thread::spawn(move || {
+            client.notify().unwrap();
+            client
+        });
+        server.next().await.unwrap();
+        notifier.join().unwrap();
+
+        assert!(server.next().now_or_never().is_none());
+    });
+}
@@ -0,0 +1,232 @@
+mod common;
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use xenstore_win::{
+    XsWindows,
+    emulator::Emulator,
+    evtchn::EventChannel,
+    gnttab::{GrantedPages, MappedForeignPages, Notify, PAGE_SIZE},
+};
+
+use common::{Ioctl, Recorder, ctl_code};
+
+const TIMEOUT: Duration = Duration::from_millis(20);
+
+/// Transport recording the ioctls, results point to the returned pages with references
+/// 100, 101...
+fn recorder() -> (Recorder, Arc<Mutex<Vec<u8>>>) {
+    let pages = Arc::new(Mutex::new(vec![0u8; 2 * PAGE_SIZE]));
+    let memory = pages.clone();
+
+    let recorder = Recorder::new(move |_, _, out_buffer| {
+        let Some(out_buffer) = out_buffer else {
+            return Ok(0);
+        };
+
+        let address = memory.lock().unwrap().as_mut_ptr().expose_provenance();
+        let mut output = address.to_ne_bytes().to_vec();
+        (100u32..)
+            .take((out_buffer.len() - output.len()) / 4)
+            .for_each(|reference| output.extend(reference.to_ne_bytes()));
+
+        out_buffer.copy_from_slice(&output);
+        Ok(output.len() as u32)
+    });
+
+    (recorder, pages)
+}
+
+fn u32s(values: &[u32]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|value| value.to_ne_bytes())
+        .collect()
+}
+
+/// `XENIFACE_GNTTAB_*_IN` header, with the padding after `RemoteDomain`.
+fn pages_in(request_id: u32, remote_domid: u16, fields: [u32; 4]) -> Vec<u8> {
+    let mut bytes = u32s(&[request_id, remote_domid as u32]);
+    bytes.extend(u32s(&fields));
+    bytes
+}
+
+fn request_id(ioctl: &Ioctl) -> u32 {
+    u32::from_ne_bytes(ioctl.1[..4].try_into().unwrap())
+}
+
+#[test]
+fn grant_encoding() {
+    let (recorder, shared) = recorder();
+    let xs = XsWindows::with_transport(recorder);
+    let notify = Notify {
+        offset: Some(8),
+        port: None,
+    };
+
+    let mut pages = GrantedPages::new(&xs, 3, 2, true, notify).unwrap();
+    assert_eq!(pages.references(), [100, 101]);
+    assert_eq!(pages.as_slice().len(), 2 * PAGE_SIZE);
+    pages.as_mut_slice()[1] = 42;
+    assert_eq!(shared.lock().unwrap()[1], 42);
+
+    let ioctls = xs.transport().take();
+    let id = request_id(&ioctls[0]);
+    assert_eq!(
+        ioctls,
+        [
+            // Flags = READONLY | USE_NOTIFY_OFFSET
+            (ctl_code(0x820), pages_in(id, 3, [2, 0b011, 8, 0])),
+            (ctl_code(0x821), u32s(&[id])),
+        ]
+    );
+
+    drop(pages);
+    assert_eq!(xs.transport().take(), [(ctl_code(0x822), u32s(&[id]))]);
+}
+
+#[test]
+fn map_encoding() {
+    let xs = XsWindows::with_transport(recorder().0);
+    let notify = Notify {
+        offset: None,
+        port: Some(7),
+    };
+
+    let pages = MappedForeignPages::new(&xs, 3, &[10, 11], false, notify).unwrap();
+    assert_eq!(pages.as_slice().len(), 2 * PAGE_SIZE);
+
+    let ioctls = xs.transport().take();
+    let id = request_id(&ioctls[0]);
+    let mut map_in = pages_in(id, 3, [2, 0b100, 0, 7]);
+    map_in.extend(u32s(&[10, 11]));
+    assert_eq!(
+        ioctls,
+        [(ctl_code(0x823), map_in), (ctl_code(0x824), u32s(&[id])),]
+    );
+
+    drop(pages);
+    assert_eq!(xs.transport().take(), [(ctl_code(0x825), u32s(&[id]))]);
+}
+
+#[test]
+fn request_ids() {
+    let xs = XsWindows::with_transport(recorder().0);
+
+    let a = GrantedPages::new(&xs, 0, 1, false, Notify::default()).unwrap();
+    let b = GrantedPages::new(&xs, 0, 1, false, Notify::default()).unwrap();
+
+    let ioctls = xs.transport().take();
+    assert_ne!(request_id(&ioctls[0]), request_id(&ioctls[2]));
+    drop((a, b));
+}
+
+#[test]
+fn shared() {
+    let emulator = Emulator::with_domid(5);
+    let xs = XsWindows::with_transport(emulator.clone());
+
+    let mut granted = GrantedPages::new(&xs, 5, 3, false, Notify::default()).unwrap();
+    assert_eq!(emulator.grant_count(), 3);
+    assert!(granted.as_slice().iter().all(|&b| b == 0));
+
+    let mut mapped =
+        MappedForeignPages::new(&xs, 5, &granted.references()[1..], false, Notify::default())
+            .unwrap();
+    assert_eq!(mapped.as_slice().len(), 2 * PAGE_SIZE);
+    assert_eq!(emulator.request_count(), 2);
+
+    granted.as_mut_slice()[PAGE_SIZE] = 1;
+    mapped.as_mut_slice().unwrap()[PAGE_SIZE] = 2;
+    assert_eq!(mapped.as_slice()[0], 1);
+    assert_eq!(granted.as_slice()[2 * PAGE_SIZE], 2);
+
+    drop(mapped);
+    assert_eq!(emulator.request_count(), 1);
+    drop(granted);
+    assert_eq!(emulator.request_count(), 0);
+    assert_eq!(emulator.grant_count(), 0);
+}
+
+#[test]
+fn readonly() {
+    let emulator = Emulator::with_domid(5);
+    let xs = XsWindows::with_transport(emulator.clone());
+
+    let mut granted = GrantedPages::new(&xs, 5, 1, true, Notify::default()).unwrap();
+    granted.as_mut_slice()[0] = 1;
+
+    let mut mapped =
+        MappedForeignPages::new(&xs, 5, granted.references(), true, Notify::default()).unwrap();
+    assert!(mapped.is_readonly());
+    assert_eq!(mapped.as_slice()[0], 1);
+    assert!(mapped.as_mut_slice().is_none());
+}
+
+#[test]
+fn invalid_map() {
+    let emulator = Emulator::with_domid(5);
+    let xs = XsWindows::with_transport(emulator.clone());
+
+    let readonly = GrantedPages::new(&xs, 5, 1, true, Notify::default()).unwrap();
+    let other = GrantedPages::new(&xs, 5, 2, false, Notify::default()).unwrap();
+    let foreign = GrantedPages::new(&xs, 1, 1, false, Notify::default()).unwrap();
+
+    let map = |domid, references: &[u32], readonly| {
+        MappedForeignPages::new(&xs, domid, references, readonly, Notify::default()).map(drop)
+    };
+
+    // Read-only grant mapped writable.
+    assert_eq!(
+        map(5, readonly.references(), false).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    map(5, readonly.references(), true).unwrap();
+
+    // Not granted to us, or not from the same domain.
+    assert!(map(5, foreign.references(), false).is_err());
+    assert!(map(1, other.references(), false).is_err());
+    // Not contiguous.
+    let references = [other.references()[1], other.references()[0]];
+    assert!(map(5, &references, false).is_err());
+    assert!(map(5, &[readonly.references()[0], other.references()[0]], true).is_err());
+    assert!(map(5, &[], false).is_err());
+    assert!(map(5, &[u32::MAX], false).is_err());
+
+    // Failed requests are not left pending.
+    assert_eq!(emulator.request_count(), 3);
+}
+
+#[test]
+fn notify() {
+    let emulator = Emulator::with_domid(5);
+    let xs = XsWindows::with_transport(emulator.clone());
+
+    let server = EventChannel::bind_unbound(&xs, 5, false).unwrap();
+    let client = EventChannel::bind_interdomain(&xs, 5, server.port(), false).unwrap();
+
+    let mut granted = GrantedPages::new(&xs, 5, 1, false, Notify::default()).unwrap();
+    granted.as_mut_slice()[..2].copy_from_slice(&[1, 1]);
+
+    let notify = Notify {
+        offset: Some(1),
+        port: Some(client.port()),
+    };
+    let mapped = MappedForeignPages::new(&xs, 5, granted.references(), false, notify).unwrap();
+    assert!(!server.wait_timeout(TIMEOUT).unwrap());
+
+    drop(mapped);
+    assert_eq!(granted.as_slice()[..2], [1, 0]);
+    assert!(server.wait_timeout(TIMEOUT).unwrap());
+
+    // Out of the pages.
+    let notify = Notify {
+        offset: Some(PAGE_SIZE as u32),
+        port: None,
+    };
+    assert!(MappedForeignPages::new(&xs, 5, granted.references(), false, notify).is_err());
+}
@@ -0,0 +1,148 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use xenstore_win::{
+    XsWindows,
+    emulator::Emulator,
+    logger::{LOG_MAX_LENGTH, XenLogger},
+};
+
+/// Logger collecting `target: message` lines.
+#[derive(Clone, Default)]
+struct Collector(Arc<Mutex<Vec<String>>>);
+
+impl Collector {
+    fn take(&self) -> Vec<String> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+impl Log for Collector {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        self.0
+            .lock()
+            .unwrap()
+            .push(format!("{}: {}", record.target(), record.args()));
+    }
+
+    fn flush(&self) {}
+}
+
+fn log(logger: &impl Log, level: Level, message: &str) {
+    logger.log(
+        &Record::builder()
+            .level(level)
+            .target("agent")
+            .args(format_args!("{message}"))
+            .build(),
+    );
+}
+
+fn logger() -> (Emulator, XenLogger<Emulator>) {
+    let emulator = Emulator::new();
+    let xs = XsWindows::with_transport(emulator.clone());
+
+    (emulator, XenLogger::with_device(Some(xs)))
+}
+
+#[test]
+fn sanitize() {
+    let emulator = Emulator::new();
+    let xs = XsWindows::with_transport(emulator.clone());
+
+    xs.log("tab\there\nbell\x07 caf\u{e9}").unwrap();
+    xs.log("").unwrap();
+    xs.log(&"x".repeat(1000)).unwrap();
+
+    let logs = emulator.logs();
+    assert_eq!(logs.len(), 2);
+    assert_eq!(logs[0], "tab here\nbell? caf?");
+    assert_eq!(logs[1], "x".repeat(LOG_MAX_LENGTH));
+}
+
+#[test]
+fn level() {
+    let (emulator, logger) = logger();
+    let logger = logger.with_level(LevelFilter::Warn);
+    assert!(logger.has_device());
+
+    log(&logger, Level::Error, "error");
+    log(&logger, Level::Warn, "warn");
+    log(&logger, Level::Info, "info");
+    assert!(!logger.enabled(&Metadata::builder().level(Level::Info).build()));
+
+    assert_eq!(emulator.logs(), ["ERROR agent: error", "WARN agent: warn"]);
+}
+
+#[test]
+fn rate_limit() {
+    let (emulator, logger) = logger();
+    let logger = logger.with_rate_limit(2, Duration::from_millis(100));
+
+    for i in 0..5 {
+        log(&logger, Level::Info, &i.to_string());
+    }
+    assert_eq!(emulator.logs(), ["INFO agent: 0", "INFO agent: 1"]);
+
+    thread::sleep(Duration::from_millis(150));
+    log(&logger, Level::Info, "5");
+    assert_eq!(
+        emulator.logs()[2..],
+        [
+            "WARN xenstore_win::logger: 3 records suppressed",
+            "INFO agent: 5"
+        ]
+    );
+}
+
+#[test]
+fn fallback() {
+    let collector = Collector::default();
+    let absent = XenLogger::<Emulator>::with_device(None)
+        .with_fallback(collector.clone())
+        .with_rate_limit(0, Duration::from_secs(1));
+    assert!(!absent.has_device());
+
+    log(&absent, Level::Info, "info");
+    log(&absent, Level::Debug, "debug");
+    assert_eq!(collector.take(), ["agent: info"]);
+
+    // Only used without device.
+    let (emulator, logger) = logger();
+    let logger = logger.with_fallback(collector.clone());
+    log(&logger, Level::Info, "info");
+    assert_eq!(emulator.logs(), ["INFO agent: info"]);
+    assert!(collector.take().is_empty());
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing() {
+    use tracing_subscriber::{Registry, layer::SubscriberExt};
+
+    let (emulator, logger) = logger();
+    let collector = Collector::default();
+    let subscriber = Registry::default().with(logger);
+
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!(target: "agent", answer = 42, name = "xen", "hello {}", "world");
+        tracing::debug!(target: "agent", "debug");
+    });
+    assert_eq!(
+        emulator.logs(),
+        ["INFO agent: hello world answer=42 name=xen"]
+    );
+
+    let subscriber = Registry::default()
+        .with(XenLogger::<Emulator>::with_device(None).with_fallback(collector.clone()));
+    tracing::subscriber::with_default(subscriber, || tracing::warn!(target: "agent", "warn"));
+    assert_eq!(collector.take(), ["agent: warn"]);
+}
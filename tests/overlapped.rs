@@ -0,0 +1,244 @@
+#![cfg(feature = "smol")]
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    pin::{Pin, pin},
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use futures::task::noop_waker_ref;
+use xenstore_rs::AsyncXs;
+use xenstore_win::{
+    Overlapped, Transport, XENSTORE_PAYLOAD_MAX, XsWindows,
+    emulator::{EmulatedEvent, Emulator},
+    smol::XsSmolWindows,
+};
+
+/// Ioctl submitted to a [`Deferred`] transport.
+struct Request {
+    control_code: u32,
+    in_buffer: Vec<u8>,
+    out_buffer: Vec<u8>,
+    slot: Arc<Mutex<Slot>>,
+}
+
+#[derive(Default)]
+struct Slot {
+    result: Option<io::Result<Vec<u8>>>,
+    waker: Option<Waker>,
+    cancelled: bool,
+}
+
+/// Transport whose ioctls only complete when the test says so.
+#[derive(Clone, Default)]
+struct Deferred {
+    emulator: Emulator,
+    queue: Arc<Mutex<VecDeque<Request>>>,
+    cancelled: Arc<Mutex<usize>>,
+}
+
+impl Deferred {
+    fn pending(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    /// Complete all pending ioctls on the emulator, returns how many completed.
+    fn complete_all(&self) -> usize {
+        let requests: Vec<_> = self.queue.lock().unwrap().drain(..).collect();
+        let count = requests.len();
+
+        for mut request in requests {
+            let result = unsafe {
+                self.emulator.ioctl(
+                    request.control_code,
+                    &request.in_buffer,
+                    Some(&mut request.out_buffer),
+                )
+            };
+            let mut slot = request.slot.lock().unwrap();
+
+            slot.result = Some(result.map(|len| {
+                request.out_buffer.truncate(len as usize);
+                request.out_buffer
+            }));
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        }
+
+        count
+    }
+}
+
+struct DeferredIoctl {
+    slot: Arc<Mutex<Slot>>,
+    cancelled: Arc<Mutex<usize>>,
+}
+
+impl Overlapped for DeferredIoctl {
+    fn poll_complete(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Vec<u8>>> {
+        let mut slot = self.slot.lock().unwrap();
+
+        match slot.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for DeferredIoctl {
+    fn drop(&mut self) {
+        let mut slot = self.slot.lock().unwrap();
+
+        if slot.result.is_none() && !slot.cancelled {
+            slot.cancelled = true;
+            *self.cancelled.lock().unwrap() += 1;
+        }
+    }
+}
+
+impl Transport for Deferred {
+    type Event = EmulatedEvent;
+    type Overlapped<'a> = DeferredIoctl;
+
+    unsafe fn ioctl(
+        &self,
+        control_code: u32,
+        in_buffer: &[u8],
+        out_buffer: Option<&mut [u8]>,
+    ) -> io::Result<u32> {
+        unsafe { self.emulator.ioctl(control_code, in_buffer, out_buffer) }
+    }
+
+    unsafe fn submit(
+        &self,
+        control_code: u32,
+        in_buffer: Vec<u8>,
+        out_buffer: Vec<u8>,
+    ) -> io::Result<DeferredIoctl> {
+        let slot = Arc::default();
+
+        self.queue.lock().unwrap().push_back(Request {
+            control_code,
+            in_buffer,
+            out_buffer,
+            slot: Arc::clone(&slot),
+        });
+
+        Ok(DeferredIoctl {
+            slot,
+            cancelled: self.cancelled.clone(),
+        })
+    }
+
+    fn create_event(&self) -> io::Result<EmulatedEvent> {
+        self.emulator.create_event()
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(self.clone())
+    }
+}
+
+fn poll<F: Future>(future: Pin<&mut F>) -> Poll<F::Output> {
+    future.poll(&mut Context::from_waker(noop_waker_ref()))
+}
+
+#[test]
+fn concurrent_requests() {
+    let transport = Deferred::default();
+    let xs = XsSmolWindows::from(XsWindows::with_transport(transport.clone()));
+    transport.emulator.set("/a", "1");
+    transport.emulator.set("/b", "2");
+
+    let mut a = pin!(xs.read("/a"));
+    let mut b = pin!(xs.read("/b"));
+    let mut write = pin!(xs.write("/c", "3"));
+
+    // All requests are in flight at once.
+    assert!(poll(a.as_mut()).is_pending());
+    assert!(poll(b.as_mut()).is_pending());
+    assert!(poll(write.as_mut()).is_pending());
+    assert_eq!(transport.pending(), 3);
+
+    assert_eq!(transport.complete_all(), 3);
+    assert!(matches!(poll(b.as_mut()), Poll::Ready(Ok(v)) if &*v == "2"));
+    assert!(matches!(poll(a.as_mut()), Poll::Ready(Ok(v)) if &*v == "1"));
+    assert!(matches!(poll(write.as_mut()), Poll::Ready(Ok(()))));
+    assert_eq!(transport.emulator.get("/c").unwrap(), b"3");
+}
+
+#[test]
+fn large_output() {
+    let transport = Deferred::default();
+    let xs = XsSmolWindows::from(XsWindows::with_transport(transport.clone()));
+    let value = "x".repeat(XENSTORE_PAYLOAD_MAX);
+    transport.emulator.set("/large", &value);
+
+    let mut read = pin!(xs.read("/large"));
+
+    // The output buffer fits any payload.
+    let mut submitted = 0;
+    let result = loop {
+        match poll(read.as_mut()) {
+            Poll::Ready(result) => break result,
+            Poll::Pending => submitted += transport.complete_all(),
+        }
+    };
+
+    assert_eq!(*result.unwrap(), value);
+    assert_eq!(submitted, 1);
+}
+
+#[test]
+fn errors() {
+    let transport = Deferred::default();
+    let xs = XsSmolWindows::from(XsWindows::with_transport(transport.clone()));
+
+    let mut read = pin!(xs.read("/missing"));
+    assert!(poll(read.as_mut()).is_pending());
+    transport.complete_all();
+
+    let Poll::Ready(Err(e)) = poll(read.as_mut()) else {
+        panic!("read should fail");
+    };
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+}
+
+#[test]
+fn cancel() {
+    let transport = Deferred::default();
+    let xs = XsSmolWindows::from(XsWindows::with_transport(transport.clone()));
+
+    {
+        let mut read = pin!(xs.read("/a"));
+        assert!(poll(read.as_mut()).is_pending());
+    }
+
+    assert_eq!(*transport.cancelled.lock().unwrap(), 1);
+}
+
+#[test]
+fn emulator() {
+    let xs = XsSmolWindows::from(XsWindows::with_transport(Emulator::new()));
+
+    smol::block_on(async {
+        xs.write("/data", "value").await.unwrap();
+        assert_eq!(&*xs.read("/data").await.unwrap(), "value");
+        assert_eq!(xs.directory("/").await.unwrap(), vec!["data".into()]);
+
+        // Leave room for the path in the payload.
+        let value = "x".repeat(XENSTORE_PAYLOAD_MAX - 64);
+        xs.write("/data", &value).await.unwrap();
+        assert_eq!(*xs.read("/data").await.unwrap(), value);
+
+        xs.rm("/data").await.unwrap();
+        assert!(xs.read("/data").await.is_err());
+    });
+}
@@ -0,0 +1,127 @@
+mod common;
+
+use std::{
+    ffi::{CStr, c_char},
+    io,
+    sync::{Arc, Mutex},
+};
+
+use xenstore_rs::Xs;
+use xenstore_win::{Access, Permission, PermissionSet, XsWindows, emulator::Emulator};
+
+use common::Recorder;
+
+#[test]
+fn parse_permission() {
+    assert_eq!("n0".parse(), Ok(Permission::new(0, Access::None)));
+    assert_eq!("r5".parse(), Ok(Permission::new(5, Access::Read)));
+    assert_eq!("w12".parse(), Ok(Permission::new(12, Access::Write)));
+    assert_eq!("b65535".parse(), Ok(Permission::new(65535, Access::Both)));
+
+    for invalid in ["", "n", "x0", "r+5", "r-1", "r65536", "5", " r5"] {
+        assert!(invalid.parse::<Permission>().is_err(), "{invalid:?}");
+    }
+}
+
+#[test]
+fn parse_permission_set() {
+    let set: PermissionSet = "n0 r5,b7".parse().unwrap();
+
+    assert_eq!(set.owner(), 0);
+    assert_eq!(set.default_access(), Access::None);
+    assert_eq!(set.access(0), Access::Both);
+    assert_eq!(set.access(5), Access::Read);
+    assert_eq!(set.access(7), Access::Both);
+    assert_eq!(set.access(9), Access::None);
+    assert_eq!(set.to_string(), "n0,r5,b7");
+
+    assert_eq!(
+        set,
+        PermissionSet::new(0, Access::None)
+            .with(5, Access::Read)
+            .with(7, Access::Both)
+    );
+    assert!("".parse::<PermissionSet>().is_err());
+}
+
+/// Decoded XENIFACE_STORE_SET_PERMISSIONS_IN (with control code).
+type SetPermissionsIn = (u32, String, Vec<(u16, u32)>);
+
+/// Transport recording the last set permissions ioctl, decoded while the path is alive.
+fn recorder() -> (Recorder, Arc<Mutex<Option<SetPermissionsIn>>>) {
+    let last = Arc::new(Mutex::new(None));
+    let recorded = last.clone();
+
+    let recorder = Recorder::new(move |control_code, in_buffer, _| {
+        const PTR: usize = size_of::<usize>();
+
+        let ptr = usize::from_ne_bytes(in_buffer[..PTR].try_into().unwrap());
+        let path_len = u32::from_ne_bytes(in_buffer[PTR..PTR + 4].try_into().unwrap());
+        let count = u32::from_ne_bytes(in_buffer[PTR + 4..PTR + 8].try_into().unwrap());
+        let path = unsafe { CStr::from_ptr(std::ptr::with_exposed_provenance::<c_char>(ptr)) };
+
+        // Each entry is { USHORT Domain; <2 bytes padding>; ULONG Mask; }
+        let entries = &in_buffer[PTR + 8..];
+        assert_eq!(entries.len(), count as usize * 8);
+        assert_eq!(path.to_bytes_with_nul().len(), path_len as usize);
+
+        let permissions = entries
+            .chunks(8)
+            .map(|e| {
+                (
+                    u16::from_ne_bytes(e[..2].try_into().unwrap()),
+                    u32::from_ne_bytes(e[4..].try_into().unwrap()),
+                )
+            })
+            .collect();
+
+        *recorded.lock().unwrap() = Some((
+            control_code,
+            path.to_str().unwrap().to_string(),
+            permissions,
+        ));
+        Ok(0)
+    });
+
+    (recorder, last)
+}
+
+#[test]
+fn set_permissions_encoding() {
+    let (recorder, last) = recorder();
+    let xs = XsWindows::with_transport(recorder);
+    let set: PermissionSet = "n1 r0 w2 b3".parse().unwrap();
+
+    xs.set_permissions("/local/domain/1/data", set.as_slice())
+        .unwrap();
+
+    let (control_code, path, permissions) = last.lock().unwrap().take().unwrap();
+    assert_eq!(control_code, 0x222010); // IOCTL_XENIFACE_STORE_SET_PERMISSIONS
+    assert_eq!(path, "/local/domain/1/data");
+    assert_eq!(permissions, [(1, 0), (0, 1), (2, 2), (3, 3)]);
+
+    assert!(xs.set_permissions("/data", &[]).is_err());
+    assert!(last.lock().unwrap().is_none());
+}
+
+#[test]
+fn emulated_permissions() {
+    let emulator = Emulator::with_domid(3);
+    let xs = XsWindows::with_transport(emulator.clone());
+
+    assert_eq!(emulator.permissions("/").unwrap().to_string(), "n0");
+
+    xs.write("data/key", "value").unwrap();
+    assert_eq!(emulator.permissions("data").unwrap().to_string(), "n3");
+
+    let set: PermissionSet = "n3 r0".parse().unwrap();
+    xs.set_permissions("data", set.as_slice()).unwrap();
+    assert_eq!(emulator.permissions("data").unwrap(), set);
+
+    // Inherited by new children.
+    xs.write("data/other", "value").unwrap();
+    assert_eq!(emulator.permissions("data/other").unwrap(), set);
+
+    let e = xs.set_permissions("missing", set.as_slice()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+}
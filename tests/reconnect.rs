@@ -0,0 +1,148 @@
+use std::{io, sync::mpsc::TryRecvError, time::Duration};
+
+use xenstore_rs::Xs;
+use xenstore_win::{ConnectionStatus, ReconnectingXs, emulator::Emulator};
+
+const TIMEOUT: Duration = Duration::from_millis(20);
+
+fn xs() -> (Emulator, ReconnectingXs<Emulator>) {
+    let emulator = Emulator::new();
+    let device = emulator.clone();
+
+    (
+        emulator,
+        ReconnectingXs::with_opener(move || device.reopen()),
+    )
+}
+
+#[test]
+fn retry() {
+    let (emulator, xs) = xs();
+    let status = xs.subscribe();
+    assert_eq!(xs.status(), ConnectionStatus::Connected);
+
+    xs.write("/data", "value").unwrap();
+
+    // Removed and back before the next operation, which is retried transparently.
+    emulator.unplug();
+    emulator.plug();
+    assert_eq!(&*xs.read("/data").unwrap(), "value");
+
+    assert_eq!(status.try_recv(), Ok(ConnectionStatus::Disconnected));
+    assert_eq!(status.try_recv(), Ok(ConnectionStatus::Connected));
+    assert_eq!(status.try_recv(), Err(TryRecvError::Empty));
+}
+
+#[test]
+fn retry_rm() {
+    let (emulator, xs) = xs();
+
+    let e = xs.rm("/data").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+
+    // The failed attempt may have removed it.
+    emulator.unplug();
+    emulator.plug();
+    xs.rm("/data").unwrap();
+    assert_eq!(xs.status(), ConnectionStatus::Connected);
+}
+
+#[test]
+fn disconnected() {
+    let (emulator, xs) = xs();
+    let status = xs.subscribe();
+
+    emulator.unplug();
+    // Not mistaken for a missing node.
+    let e = xs.read("/data").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotConnected);
+    assert_eq!(xs.status(), ConnectionStatus::Disconnected);
+    assert!(xs.check().is_err());
+    assert_eq!(status.try_recv(), Ok(ConnectionStatus::Disconnected));
+    assert_eq!(status.try_recv(), Err(TryRecvError::Empty));
+
+    emulator.plug();
+    emulator.set("/data", "value");
+    assert_eq!(&*xs.read("/data").unwrap(), "value");
+    assert_eq!(xs.status(), ConnectionStatus::Connected);
+    assert_eq!(status.try_recv(), Ok(ConnectionStatus::Connected));
+}
+
+#[test]
+fn initially_absent() {
+    let emulator = Emulator::new();
+    emulator.unplug();
+
+    let device = emulator.clone();
+    let xs = ReconnectingXs::with_opener(move || device.reopen());
+    assert_eq!(xs.status(), ConnectionStatus::Disconnected);
+    let e = xs.directory("/").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotConnected);
+
+    emulator.plug();
+    xs.check().unwrap();
+    assert_eq!(xs.status(), ConnectionStatus::Connected);
+    xs.write("/data", "value").unwrap();
+}
+
+#[test]
+fn rewatch() {
+    let (emulator, xs) = xs();
+    let watch = xs.watch("/data").unwrap();
+    watch.recv().unwrap();
+    assert_eq!(emulator.watch_count(), 1);
+
+    // The driver drops the watches along with the device.
+    emulator.unplug();
+    assert_eq!(emulator.watch_count(), 0);
+    emulator.plug();
+    emulator.set("/data", "value");
+    assert!(watch.recv_timeout(TIMEOUT).unwrap().is_none());
+
+    // Registered again (and fired) on reconnection.
+    xs.check().unwrap();
+    assert_eq!(emulator.watch_count(), 1);
+    assert!(watch.recv_timeout(TIMEOUT).unwrap().is_some());
+
+    xs.write("/data/key", "value").unwrap();
+    assert!(watch.recv_timeout(TIMEOUT).unwrap().is_some());
+
+    // Destroyed through the new device.
+    drop(watch);
+    assert_eq!(emulator.watch_count(), 0);
+}
+
+#[test]
+fn stale_device() {
+    let (emulator, xs) = xs();
+    let device = xs.device().unwrap();
+
+    emulator.unplug();
+    emulator.plug();
+    xs.check().unwrap();
+
+    // Clones aren't reconnected.
+    assert!(device.read("/").is_err());
+    assert!(xs.device().unwrap().read("/").is_ok());
+}
+
+#[cfg(feature = "futures")]
+#[test]
+fn stream() {
+    use futures::{StreamExt, executor::block_on};
+
+    let (emulator, xs) = xs();
+    let mut status = xs.subscribe_stream();
+
+    emulator.unplug();
+    assert!(xs.check().is_err());
+    emulator.plug();
+    xs.check().unwrap();
+
+    drop(xs);
+    let status: Vec<_> = block_on(status.by_ref().collect());
+    assert_eq!(
+        status,
+        [ConnectionStatus::Disconnected, ConnectionStatus::Connected]
+    );
+}
@@ -0,0 +1,358 @@
+#![cfg(feature = "serde")]
+
+mod common;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+};
+
+use serde::{Deserialize, Serialize};
+use xenstore_rs::{AsyncXs, Xs};
+use xenstore_win::{
+    XsWindows,
+    emulator::Emulator,
+    serde::{from_xs, from_xs_async, to_xs, to_xs_async},
+};
+
+use common::xs;
+
+/// Async xenstore over the emulator.
+struct AsyncEmulator(XsWindows<Emulator>);
+
+impl AsyncXs for AsyncEmulator {
+    async fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        self.0.directory(path)
+    }
+
+    async fn read(&self, path: &str) -> io::Result<Box<str>> {
+        self.0.read(path)
+    }
+
+    async fn write(&self, path: &str, data: &str) -> io::Result<()> {
+        self.0.write(path, data)
+    }
+
+    async fn rm(&self, path: &str) -> io::Result<()> {
+        self.0.rm(path)
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Vif {
+    backend: String,
+    backend_id: u16,
+    mac: String,
+    feature_rx_copy: Option<bool>,
+    mtu: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum Mode {
+    Off,
+    Limit(u32),
+    Range { low: i8, high: i8 },
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Everything {
+    flag: bool,
+    ratio: f64,
+    letter: char,
+    signed: i64,
+    unit: (),
+    pair: (u8, String),
+    names: Vec<String>,
+    nested: Vec<Vec<u8>>,
+    by_name: HashMap<String, u32>,
+    by_index: BTreeMap<u16, String>,
+    modes: Vec<Mode>,
+}
+
+fn everything() -> Everything {
+    Everything {
+        flag: true,
+        ratio: 0.5,
+        letter: 'x',
+        signed: -42,
+        unit: (),
+        pair: (7, "seven".into()),
+        names: vec!["a".into(), "b".into()],
+        nested: vec![vec![1], vec![], vec![2, 3]],
+        by_name: HashMap::from([("one".into(), 1), ("two".into(), 2)]),
+        by_index: BTreeMap::from([(1, "one".into()), (10, "ten".into())]),
+        modes: vec![Mode::Off, Mode::Limit(5), Mode::Range { low: -1, high: 1 }],
+    }
+}
+
+#[test]
+fn vif() {
+    let (emulator, xs) = xs();
+    emulator.set(
+        "/local/domain/1/device/vif/0/backend",
+        "/local/domain/0/backend/vif/1/0",
+    );
+    emulator.set("/local/domain/1/device/vif/0/backend-id", "0");
+    emulator.set("/local/domain/1/device/vif/0/mac", "00:16:3e:00:00:01");
+    emulator.set("/local/domain/1/device/vif/0/feature-rx-copy", "1");
+    emulator.set("/local/domain/1/device/vif/0/state", "4");
+
+    // Unknown keys are ignored, missing options are None.
+    let vif: Vif = from_xs(&xs, "/local/domain/1/device/vif/0").unwrap();
+    assert_eq!(
+        vif,
+        Vif {
+            backend: "/local/domain/0/backend/vif/1/0".into(),
+            backend_id: 0,
+            mac: "00:16:3e:00:00:01".into(),
+            feature_rx_copy: Some(true),
+            mtu: None,
+        }
+    );
+
+    let vif = Vif {
+        backend_id: 3,
+        feature_rx_copy: None,
+        mtu: Some(1500),
+        ..vif
+    };
+    to_xs(&xs, "/local/domain/1/device/vif/0", &vif).unwrap();
+    assert_eq!(
+        emulator
+            .get("/local/domain/1/device/vif/0/backend-id")
+            .unwrap(),
+        b"3"
+    );
+    assert_eq!(
+        emulator.get("/local/domain/1/device/vif/0/mtu").unwrap(),
+        b"1500"
+    );
+    assert!(
+        emulator
+            .get("/local/domain/1/device/vif/0/feature-rx-copy")
+            .is_none()
+    );
+    assert_eq!(
+        emulator.get("/local/domain/1/device/vif/0/state").unwrap(),
+        b"4"
+    );
+
+    assert_eq!(
+        from_xs::<Vif>(&xs, "/local/domain/1/device/vif/0").unwrap(),
+        vif
+    );
+}
+
+#[test]
+fn layout() {
+    let (emulator, xs) = xs();
+
+    to_xs(&xs, "/data", &everything()).unwrap();
+
+    for (path, value) in [
+        ("/data", ""),
+        ("/data/flag", "1"),
+        ("/data/ratio", "0.5"),
+        ("/data/letter", "x"),
+        ("/data/signed", "-42"),
+        ("/data/unit", ""),
+        ("/data/pair/0", "7"),
+        ("/data/pair/1", "seven"),
+        ("/data/names/0", "a"),
+        ("/data/names/1", "b"),
+        ("/data/nested/1", ""),
+        ("/data/nested/2/1", "3"),
+        ("/data/by_name/two", "2"),
+        ("/data/by_index/10", "ten"),
+        ("/data/modes/0", "Off"),
+        ("/data/modes/1/Limit", "5"),
+        ("/data/modes/2/Range/low", "-1"),
+    ] {
+        assert_eq!(emulator.get(path).unwrap(), value.as_bytes(), "{path}");
+    }
+}
+
+#[test]
+fn round_trip() {
+    let (_, xs) = xs();
+
+    to_xs(&xs, "/data", &everything()).unwrap();
+    assert_eq!(from_xs::<Everything>(&xs, "/data").unwrap(), everything());
+
+    // Plain values.
+    to_xs(&xs, "/value", &12u8).unwrap();
+    assert_eq!(from_xs::<u8>(&xs, "/value").unwrap(), 12);
+    assert_eq!(from_xs::<String>(&xs, "/value").unwrap(), "12");
+    to_xs(&xs, "/value", &false).unwrap();
+    assert!(!from_xs::<bool>(&xs, "/value").unwrap());
+}
+
+#[test]
+fn variants() {
+    let (emulator, xs) = xs();
+
+    // Each variant replaces the previous one.
+    for mode in [
+        Mode::Limit(5),
+        Mode::Off,
+        Mode::Range { low: -1, high: 1 },
+        Mode::Limit(6),
+        Mode::Range { low: 2, high: 3 },
+        Mode::Off,
+    ] {
+        to_xs(&xs, "/mode", &mode).unwrap();
+        assert_eq!(from_xs::<Mode>(&xs, "/mode").unwrap(), mode);
+    }
+    assert!(emulator.get("/mode/Limit").is_none());
+    assert!(emulator.get("/mode/Range").is_none());
+}
+
+#[test]
+fn bools() {
+    let (emulator, xs) = xs();
+
+    for (value, expected) in [("1", true), ("0", false), ("true", true), ("false", false)] {
+        emulator.set("/bool", value);
+        assert_eq!(from_xs::<bool>(&xs, "/bool").unwrap(), expected);
+    }
+
+    emulator.set("/bool", "yes");
+    let e = from_xs::<bool>(&xs, "/bool").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn option() {
+    let (emulator, xs) = xs();
+
+    assert_eq!(from_xs::<Option<u32>>(&xs, "/missing").unwrap(), None);
+    let e = from_xs::<u32>(&xs, "/missing").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+
+    to_xs(&xs, "/option", &Some(5)).unwrap();
+    assert_eq!(from_xs::<Option<u32>>(&xs, "/option").unwrap(), Some(5));
+
+    // Removed along with its subtree.
+    emulator.set("/option/child", "value");
+    to_xs(&xs, "/option", &None::<u32>).unwrap();
+    assert!(emulator.get("/option").is_none());
+    to_xs(&xs, "/option", &None::<u32>).unwrap();
+}
+
+#[test]
+fn truncate() {
+    let (emulator, xs) = xs();
+    emulator.set("/list/other", "kept");
+
+    to_xs(&xs, "/list", &["a", "b", "c"]).unwrap();
+    to_xs(&xs, "/list", &["z"]).unwrap();
+
+    assert_eq!(from_xs::<Vec<String>>(&xs, "/list").unwrap(), ["z"]);
+    assert!(emulator.get("/list/1").is_none());
+    assert!(emulator.get("/list/2").is_none());
+    assert_eq!(emulator.get("/list/other").unwrap(), b"kept");
+
+    // Elements past a gap aren't dropped silently.
+    emulator.set("/list/2", "orphan");
+    let e = from_xs::<Vec<String>>(&xs, "/list").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn missing_elements() {
+    let (emulator, xs) = xs();
+
+    let e = to_xs(&xs, "/list", &[Some(1), None, Some(3)]).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    assert!(emulator.get("/list").is_none());
+
+    // Options nested in elements are still fine.
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Element {
+        value: Option<u32>,
+    }
+
+    let list = vec![Element { value: Some(1) }, Element { value: None }];
+    to_xs(&xs, "/list", &list).unwrap();
+    assert_eq!(from_xs::<Vec<Element>>(&xs, "/list").unwrap(), list);
+
+    to_xs(&xs, "/list", &[Some(1), Some(2), Some(3)]).unwrap();
+    assert_eq!(
+        from_xs::<Vec<Option<u32>>>(&xs, "/list").unwrap(),
+        [Some(1), Some(2), Some(3)]
+    );
+}
+
+#[test]
+fn invalid() {
+    let (emulator, xs) = xs();
+    emulator.set("/vif/backend", "backend");
+    emulator.set("/vif/backend-id", "dom0");
+    emulator.set("/vif/mac", "00:16:3e:00:00:01");
+
+    let e = from_xs::<Vif>(&xs, "/vif").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert!(e.to_string().contains("/vif/backend-id"), "{e}");
+
+    emulator.remove("/vif/mac");
+    emulator.set("/vif/backend-id", "0");
+    let e = from_xs::<Vif>(&xs, "/vif").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert!(e.to_string().contains("mac"), "{e}");
+
+    emulator.set("/mode/Limit", "1");
+    emulator.set("/mode/Off", "");
+    let e = from_xs::<Mode>(&xs, "/mode").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+    emulator.set("/number", "-1");
+    assert!(from_xs::<u32>(&xs, "/number").is_err());
+    assert!(from_xs::<BTreeMap<u32, String>>(&xs, "/vif").is_err());
+}
+
+#[test]
+fn invalid_keys() {
+    let (emulator, xs) = xs();
+
+    let map = HashMap::from([((1, 2), "value")]);
+    let e = to_xs(&xs, "/map", &map).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+    // Nothing is written on error.
+    assert!(emulator.get("/map").is_none());
+
+    // Keys are single node names.
+    for key in ["a/b", "", "..", "a b"] {
+        let map = BTreeMap::from([("ok", 1), (key, 2)]);
+        let e = to_xs(&xs, "/map", &map).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{key:?}");
+        assert!(emulator.get("/map").is_none());
+    }
+}
+
+#[test]
+fn async_xs() {
+    let (_, xs) = xs();
+    let xs = AsyncEmulator(xs);
+
+    smol::block_on(async {
+        to_xs_async(&xs, "/data", &everything()).await.unwrap();
+        assert_eq!(
+            from_xs_async::<Everything>(&xs, "/data").await.unwrap(),
+            everything()
+        );
+
+        to_xs_async(&xs, "/data/names", &["c"]).await.unwrap();
+        assert_eq!(
+            from_xs_async::<Vec<String>>(&xs, "/data/names")
+                .await
+                .unwrap(),
+            ["c"]
+        );
+        assert_eq!(
+            from_xs_async::<Option<u8>>(&xs, "/missing").await.unwrap(),
+            None
+        );
+    });
+}
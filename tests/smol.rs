@@ -0,0 +1,105 @@
+#![cfg(feature = "smol")]
+
+mod common;
+
+use std::io;
+
+use futures::{FutureExt, StreamExt};
+use xenstore_rs::{AsyncWatch, AsyncXs};
+use xenstore_win::{XsWindows, emulator::Emulator, smol::XsSmolWindows};
+
+use common::OverlappedStore;
+
+#[test]
+fn watch() {
+    let emulator = Emulator::new();
+    let xs = XsSmolWindows::from(XsWindows::with_transport(emulator.clone()));
+
+    smol::block_on(async {
+        let mut watch = xs.watch("/data").await.unwrap();
+        assert_eq!(emulator.watch_count(), 1);
+
+        // Initial event.
+        assert_eq!(&*watch.next().await.unwrap(), "/data");
+        assert!(watch.next().now_or_never().is_none());
+
+        // Subtree change.
+        xs.write("/data/a/b", "value").await.unwrap();
+        assert_eq!(&*watch.next().await.unwrap(), "/data");
+
+        // Unrelated change.
+        xs.write("/other", "value").await.unwrap();
+        assert!(watch.next().now_or_never().is_none());
+
+        // Parent removal.
+        emulator.set("/data", "");
+        watch.next().await.unwrap();
+        emulator.remove("/data");
+        assert_eq!(&*watch.next().await.unwrap(), "/data");
+
+        drop(watch);
+        assert_eq!(emulator.watch_count(), 0);
+    });
+}
+
+#[test]
+fn overlapped_watch() {
+    let transport = OverlappedStore::default();
+    let xs = XsSmolWindows::from(XsWindows::with_transport(transport.clone()));
+
+    // Registered without blocking the executor.
+    smol::block_on(async {
+        let mut watch = xs.watch("/data").await.unwrap();
+        assert_eq!(&*watch.next().await.unwrap(), "/data");
+
+        transport.0.set("/data", "value");
+        assert_eq!(&*watch.next().await.unwrap(), "/data");
+    });
+}
+
+#[test]
+fn rapid_firing() {
+    const WRITES: u32 = 1000;
+
+    let emulator = Emulator::new();
+    let xs = XsSmolWindows::from(XsWindows::with_transport(emulator.clone()));
+
+    smol::block_on(async {
+        let mut watch = xs.watch("/data").await.unwrap();
+        let writer = std::thread::spawn(move || {
+            for i in 1..=WRITES {
+                emulator.set("/data", i.to_string());
+            }
+        });
+
+        // Events are coalesced, but none is lost: the last write is always seen.
+        loop {
+            watch.next().await.unwrap();
+
+            if xs
+                .read("/data")
+                .await
+                .is_ok_and(|v| *v == *WRITES.to_string())
+            {
+                break;
+            }
+        }
+        writer.join().unwrap();
+    });
+}
+
+#[test]
+fn bytes() {
+    let emulator = Emulator::new();
+    let xs = XsSmolWindows::from(XsWindows::with_transport(emulator.clone()));
+
+    smol::block_on(async {
+        xs.write_bytes("/bytes", b"\xffvalue").await.unwrap();
+        assert_eq!(emulator.get("/bytes").unwrap(), b"\xffvalue");
+        assert_eq!(xs.read_bytes("/bytes").await.unwrap(), b"\xffvalue");
+        assert_eq!(xs.read_lossy("/bytes").await.unwrap(), "\u{fffd}value");
+
+        let e = xs.write_bytes("/bytes", b"a\0b").await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    });
+}
@@ -0,0 +1,225 @@
+mod common;
+
+use std::io;
+
+use xenstore_rs::Xs;
+use xenstore_win::{
+    XsWindows,
+    emulator::Emulator,
+    snapshot::{ApplyMode, Snapshot},
+};
+
+use common::xs;
+
+/// Xenstore failing to list `path`.
+struct Failing<'a> {
+    xs: &'a XsWindows<Emulator>,
+    path: &'a str,
+}
+
+impl Xs for Failing<'_> {
+    fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        match path == self.path {
+            true => Err(io::ErrorKind::PermissionDenied.into()),
+            false => self.xs.directory(path),
+        }
+    }
+
+    fn read(&self, path: &str) -> io::Result<Box<str>> {
+        self.xs.read(path)
+    }
+
+    fn write(&self, path: &str, data: &str) -> io::Result<()> {
+        self.xs.write(path, data)
+    }
+
+    fn rm(&self, path: &str) -> io::Result<()> {
+        self.xs.rm(path)
+    }
+}
+
+fn guest() -> (Emulator, XsWindows<Emulator>) {
+    let (emulator, xs) = xs();
+    emulator.set("/local/domain/1", "");
+    emulator.set("/local/domain/1/name", "guest");
+    emulator.set("/local/domain/1/data/key", "value");
+    emulator.set(
+        "/local/domain/1/data/escaped",
+        "tab\tline\n\"quoted\" \\ \u{1} caf\u{e9} \u{1f980}",
+    );
+    emulator.set("/local/domain/2/name", "other");
+
+    (emulator, xs)
+}
+
+#[test]
+fn capture() {
+    let (_, xs) = guest();
+
+    let snapshot = Snapshot::capture(&xs, "/local/domain/1").unwrap();
+    assert_eq!(snapshot.root(), "/local/domain/1");
+    assert_eq!(snapshot.len(), 4);
+    assert_eq!(snapshot.get("data"), Some(""));
+    assert_eq!(snapshot.get("data/key"), Some("value"));
+    assert_eq!(snapshot.get("name"), Some("guest"));
+    assert!(
+        snapshot.iter().map(|(path, _)| path).eq([
+            "/local/domain/1/data",
+            "/local/domain/1/data/escaped",
+            "/local/domain/1/data/key",
+            "/local/domain/1/name"
+        ]
+        .map(Box::from))
+    );
+
+    let e = Snapshot::capture(&xs, "/missing").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn json() {
+    let (_, xs) = guest();
+    let snapshot = Snapshot::capture(&xs, "/local/domain/1").unwrap();
+
+    let json = snapshot.to_json();
+    assert!(json.contains(r#""data/escaped": "tab\tline\n\"quoted\" \\ \u0001 café 🦀""#));
+    assert_eq!(Snapshot::from_json(&json).unwrap(), snapshot);
+
+    let empty = Snapshot::new("/empty");
+    assert_eq!(Snapshot::from_json(&empty.to_json()).unwrap(), empty);
+
+    let compact = r#"{"nodes":{"a":"\u00e9\ud83e\udd80\/"},"root":"/r"}"#;
+    let snapshot = Snapshot::from_json(compact).unwrap();
+    assert_eq!(snapshot.root(), "/r");
+    assert_eq!(snapshot.get("a"), Some("\u{e9}\u{1f980}/"));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn json_invalid() {
+    for json in [
+        "",
+        "[]",
+        r#"{"root": "/r"}"#,
+        r#"{"root": "/r", "nodes": {}, "other": 1}"#,
+        r#"{"root": "/r", "nodes": {"a": 1}}"#,
+        r#"{"root": "/r", "nodes": {"a": "b"}} {}"#,
+        r#"{"root": "/r", "nodes": {"a": "b",}}"#,
+        r#"{"root": "/r", "nodes": {"a": "\ud83e"}}"#,
+        r#"{"root": "/r", "nodes": {"a": "\q"}}"#,
+        "{\"root\": \"/r\", \"nodes\": {\"a\": \"\n\"}}",
+        r#"{"root": "/r", "nodes": {"a": "b"#,
+    ] {
+        let e = Snapshot::from_json(json).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{json}");
+    }
+}
+
+#[test]
+fn xenstore_ls() {
+    let (_, xs) = guest();
+    let snapshot = Snapshot::capture(&xs, "/local/domain/1").unwrap();
+
+    let text = snapshot.to_xenstore_ls();
+    assert_eq!(
+        text,
+        concat!(
+            "/local/domain/1/data = \"\"\n",
+            "/local/domain/1/data/escaped = \"tab\\tline\\n\"quoted\" \\\\ \\001 caf\\303\\251 \\360\\237\\246\\200\"\n",
+            "/local/domain/1/data/key = \"value\"\n",
+            "/local/domain/1/name = \"guest\"\n",
+        )
+    );
+    assert_eq!(
+        Snapshot::from_xenstore_ls("/local/domain/1", &text).unwrap(),
+        snapshot
+    );
+
+    // Short octal and hex escapes, from other versions of the tool.
+    let snapshot =
+        Snapshot::from_xenstore_ls("/", "/a = \"\\1\\x41\\1011\"\n\n/b = \"\"\n").unwrap();
+    assert_eq!(snapshot.get("a"), Some("\u{1}AA1"));
+    assert_eq!(snapshot.get("b"), Some(""));
+}
+
+#[test]
+fn xenstore_ls_invalid() {
+    for text in [
+        "/local/domain/1/name\n",
+        "/local/domain/1/name = guest\n",
+        "/local/domain/2/name = \"guest\"\n",
+        "/local/domain/1 = \"\"\n",
+        "/local/domain/1/name = \"\\q\"\n",
+        "/local/domain/1/name = \"\\377\"\n",
+    ] {
+        let e = Snapshot::from_xenstore_ls("/local/domain/1", text).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{text}");
+    }
+}
+
+#[test]
+fn apply() {
+    let (_, source) = guest();
+    let snapshot = Snapshot::capture(&source, "/local/domain/1").unwrap();
+
+    let (emulator, xs) = xs();
+    emulator.set("/local/domain/1/name", "old");
+    emulator.set("/local/domain/1/extra/key", "extra");
+
+    snapshot.apply(&xs, ApplyMode::Merge).unwrap();
+    assert_eq!(emulator.get("/local/domain/1/name").unwrap(), b"guest");
+    assert_eq!(emulator.get("/local/domain/1/extra/key").unwrap(), b"extra");
+
+    snapshot.apply(&xs, ApplyMode::Mirror).unwrap();
+    assert!(emulator.get("/local/domain/1/extra").is_none());
+    assert_eq!(Snapshot::capture(&xs, "/local/domain/1").unwrap(), snapshot);
+}
+
+#[test]
+fn mirror_parents() {
+    let (emulator, xs) = xs();
+    emulator.set("/r/a/extra", "extra");
+
+    // Parents only created by writing the nodes are kept.
+    let mut snapshot = Snapshot::new("/r");
+    snapshot.insert("a/b", "x");
+    snapshot.apply(&xs, ApplyMode::Mirror).unwrap();
+    assert_eq!(emulator.get("/r/a/b").unwrap(), b"x");
+    assert!(emulator.get("/r/a/extra").is_none());
+}
+
+#[test]
+fn mirror_errors() {
+    let (emulator, xs) = xs();
+    emulator.set("/r/extra", "extra");
+
+    let mut snapshot = Snapshot::new("/r");
+    snapshot.insert("a", "x");
+
+    // Listing errors aren't mistaken for an exact mirror.
+    let failing = Failing {
+        xs: &xs,
+        path: "/r",
+    };
+    let e = snapshot.apply(&failing, ApplyMode::Mirror).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    assert_eq!(emulator.get("/r/extra").unwrap(), b"extra");
+}
+
+#[test]
+fn relocate() {
+    let (_, xs) = guest();
+    let snapshot = Snapshot::capture(&xs, "/local/domain/1")
+        .unwrap()
+        .with_root("/fixtures/guest");
+
+    snapshot.apply(&xs, ApplyMode::Mirror).unwrap();
+    assert_eq!(&*xs.read("/fixtures/guest/data/key").unwrap(), "value");
+    assert_eq!(Snapshot::capture(&xs, "/fixtures/guest").unwrap(), snapshot);
+
+    let mut fixture = Snapshot::new("/");
+    fixture.insert("fixture", "value");
+    fixture.apply(&xs, ApplyMode::Merge).unwrap();
+    assert_eq!(&*xs.read("/fixture").unwrap(), "value");
+}
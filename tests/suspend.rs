@@ -0,0 +1,139 @@
+mod common;
+
+use std::{thread, time::Duration};
+
+use xenstore_rs::Xs;
+use xenstore_win::{SuspendWatcher, WatchSet, XsError};
+
+use common::xs;
+
+const TIMEOUT: Duration = Duration::from_millis(20);
+
+#[test]
+fn recv() {
+    let (emulator, xs) = xs();
+    emulator.resume();
+    assert_eq!(xs.suspend_count().unwrap(), 1);
+
+    let watcher = SuspendWatcher::new(&xs).unwrap();
+    assert_eq!(emulator.suspend_event_count(), 1);
+    assert_eq!(watcher.suspend_count(), 1);
+    assert!(watcher.recv_timeout(TIMEOUT).unwrap().is_none());
+
+    emulator.resume();
+    assert_eq!(watcher.recv_timeout(TIMEOUT).unwrap(), Some(2));
+    assert_eq!(watcher.suspend_count(), 2);
+
+    // Coalesced.
+    emulator.resume();
+    emulator.resume();
+    assert_eq!(watcher.recv().unwrap(), 4);
+    assert!(watcher.recv_timeout(TIMEOUT).unwrap().is_none());
+
+    drop(watcher);
+    assert_eq!(emulator.suspend_event_count(), 0);
+}
+
+#[test]
+fn iter() {
+    let (emulator, xs) = xs();
+    let watcher = SuspendWatcher::new(&xs).unwrap();
+
+    let resumer = thread::spawn(move || {
+        for _ in 0..3 {
+            thread::sleep(TIMEOUT);
+            emulator.resume();
+        }
+    });
+
+    // Each resume is received, as they are spaced out.
+    assert_eq!(watcher.take(3).collect::<Vec<_>>(), [1, 2, 3]);
+    resumer.join().unwrap();
+}
+
+#[test]
+fn rewatch() {
+    let (emulator, xs) = xs();
+    let watch = xs.watch("/a").unwrap();
+    watch.recv().unwrap();
+
+    // Watches of clones are registered again too.
+    let mut set = WatchSet::new(&xs).unwrap();
+    set.add("b", "/b").unwrap();
+    assert_eq!(set.recv().unwrap(), ("b", "/b".into()));
+    drop(xs.watch("/c").unwrap());
+    assert_eq!(emulator.watch_count(), 2);
+
+    let watcher = SuspendWatcher::new(&xs).unwrap().with_rewatch();
+    emulator.resume();
+    watcher.recv().unwrap();
+
+    // Fired, so that changes missed meanwhile are handled.
+    assert_eq!(emulator.watch_count(), 2);
+    assert_eq!(watch.try_recv().unwrap().as_deref(), Some("/a"));
+    assert_eq!(
+        set.recv_timeout(Some(TIMEOUT)).unwrap(),
+        Some(("b", "/b".into()))
+    );
+
+    // New registrations are the ones in use.
+    xs.write("/a/key", "value").unwrap();
+    assert_eq!(watch.try_recv().unwrap().as_deref(), Some("/a"));
+
+    drop((watch, set));
+    assert_eq!(emulator.watch_count(), 0);
+}
+
+#[test]
+fn rewatch_failure() {
+    let (emulator, xs) = xs();
+    let watch = xs.watch("/a").unwrap();
+    watch.recv().unwrap();
+
+    let watcher = SuspendWatcher::new(&xs).unwrap().with_rewatch();
+    emulator.fail_watches(1);
+    emulator.resume();
+    let e = watcher.recv_timeout(TIMEOUT).unwrap_err();
+    assert!(matches!(XsError::from(e), XsError::QuotaExceeded));
+    assert_eq!(watcher.suspend_count(), 0);
+
+    // The resume isn't consumed, the next receive registers the watch again.
+    assert_eq!(watcher.recv_timeout(TIMEOUT).unwrap(), Some(1));
+    assert_eq!(watch.try_recv().unwrap().as_deref(), Some("/a"));
+    assert_eq!(emulator.watch_count(), 1);
+}
+
+#[test]
+fn manual_rewatch() {
+    let (emulator, xs) = xs();
+    let watch = xs.watch("/a").unwrap();
+    watch.recv().unwrap();
+
+    // Not registered again without asking.
+    let watcher = SuspendWatcher::new(&xs).unwrap();
+    emulator.resume();
+    watcher.recv().unwrap();
+    assert!(watch.try_recv().unwrap().is_none());
+
+    xs.rewatch().unwrap();
+    assert_eq!(watch.try_recv().unwrap().as_deref(), Some("/a"));
+    assert_eq!(emulator.watch_count(), 1);
+}
+
+#[cfg(feature = "futures")]
+#[test]
+fn stream() {
+    use futures::{FutureExt, StreamExt};
+
+    let (emulator, xs) = xs();
+    let mut stream = SuspendWatcher::new(&xs).unwrap().into_stream();
+
+    smol::block_on(async {
+        assert!(stream.next().now_or_never().is_none());
+
+        let resumer = thread::spawn(move || emulator.resume());
+        assert_eq!(stream.next().await, Some(1));
+        assert_eq!(stream.suspend_count(), 1);
+        resumer.join().unwrap();
+    });
+}
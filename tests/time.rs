@@ -0,0 +1,152 @@
+mod common;
+
+use std::{
+    io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use xenstore_win::{
+    XsWindows,
+    emulator::Emulator,
+    time::{DriftMonitor, Skew, XenTime},
+};
+
+use common::{Recorder, ctl_code};
+
+/// FILETIME of the Unix epoch.
+const FILETIME_UNIX_EPOCH: u64 = 116_444_736_000_000_000;
+
+/// Transport answering `IOCTL_XENIFACE_SHAREDINFO_GET_TIME` with a raw output.
+fn output(bytes: Vec<u8>) -> Recorder {
+    Recorder::new(move |control_code, in_buffer, out_buffer| {
+        assert_eq!(control_code, ctl_code(0x840));
+        assert!(in_buffer.is_empty());
+
+        out_buffer.unwrap().copy_from_slice(&bytes);
+        Ok(bytes.len() as u32)
+    })
+}
+
+/// `XENIFACE_SHAREDINFO_GET_TIME_OUT`, with the padding after `Local`.
+fn get_time_out(low: u32, high: u32, local: u8) -> Vec<u8> {
+    let mut bytes = low.to_ne_bytes().to_vec();
+    bytes.extend(high.to_ne_bytes());
+    bytes.extend([local, 0, 0, 0]);
+    bytes
+}
+
+#[test]
+fn decode() {
+    let xs = XsWindows::with_transport(output(get_time_out(0x89ab_cdef, 0x0123_4567, 0)));
+    let time = xs.xen_time().unwrap();
+    assert_eq!(time.filetime(), 0x0123_4567_89ab_cdef);
+    assert!(!time.is_local());
+
+    let xs = XsWindows::with_transport(output(get_time_out(1, 0, 1)));
+    assert_eq!(xs.xen_time().unwrap(), XenTime::from_filetime(1, true));
+}
+
+#[test]
+fn system_time() {
+    let time = XenTime::from_filetime(FILETIME_UNIX_EPOCH, false);
+    assert_eq!(time.to_system_time(), UNIX_EPOCH);
+
+    // 100ns resolution.
+    let time = XenTime::from_filetime(FILETIME_UNIX_EPOCH + 15_000_001, false);
+    let expected = UNIX_EPOCH + Duration::new(1, 500_000_100);
+    assert_eq!(SystemTime::from(time), expected);
+    assert_eq!(XenTime::from(expected), time);
+    assert_eq!(
+        XenTime::from(expected + Duration::from_nanos(99)).filetime(),
+        time.filetime()
+    );
+
+    // Before the Unix epoch.
+    let time = XenTime::from_filetime(FILETIME_UNIX_EPOCH - 10_000_000, false);
+    assert_eq!(time.to_system_time(), UNIX_EPOCH - Duration::from_secs(1));
+    assert_eq!(XenTime::from(UNIX_EPOCH - Duration::from_secs(1)), time);
+
+    let time = XenTime::from_filetime(0, false);
+    assert_eq!(
+        time.to_system_time(),
+        UNIX_EPOCH - Duration::from_secs(11_644_473_600)
+    );
+}
+
+#[test]
+fn skew() {
+    let xen = UNIX_EPOCH + Duration::from_secs(1000);
+
+    let skew = Skew::between(xen, xen + Duration::from_millis(1500));
+    assert_eq!(skew.duration, Duration::from_millis(1500));
+    assert!(skew.ahead);
+    assert_eq!(skew.as_millis(), 1500);
+    assert_eq!(skew.to_string(), "1500");
+
+    let skew = Skew::between(xen, xen - Duration::from_micros(2500));
+    assert_eq!(skew.duration, Duration::from_micros(2500));
+    assert!(!skew.ahead);
+    assert_eq!(skew.as_millis(), -2);
+    assert_eq!(skew.to_string(), "-2");
+
+    assert_eq!(Skew::between(xen, xen).as_millis(), 0);
+
+    let skew = Skew {
+        duration: Duration::MAX,
+        ahead: false,
+    };
+    assert_eq!(skew.as_millis(), -i64::MAX);
+}
+
+#[test]
+fn emulator() {
+    let emulator = Emulator::new();
+    let xs = XsWindows::with_transport(emulator.clone());
+
+    let before = SystemTime::now();
+    let time = xs.xen_time().unwrap();
+    assert!(!time.is_local());
+    assert!(time.to_system_time() >= XenTime::from(before).to_system_time());
+    assert!(time.to_system_time() <= SystemTime::now());
+
+    let time = XenTime::from_filetime(FILETIME_UNIX_EPOCH, true);
+    emulator.set_time(Some(time));
+    assert_eq!(xs.xen_time().unwrap(), time);
+}
+
+#[test]
+fn drift_monitor() {
+    let emulator = Emulator::new();
+    let xs = XsWindows::with_transport(emulator.clone());
+    let monitor = DriftMonitor::new(&xs, "data/clock-skew").unwrap();
+
+    emulator.set_time(Some(XenTime::from(
+        SystemTime::now() - Duration::from_secs(60),
+    )));
+    let skew = monitor.sample().unwrap();
+    assert!(skew.ahead);
+    assert!(skew.duration >= Duration::from_secs(60));
+    assert!(skew.duration < Duration::from_secs(70));
+    assert_eq!(
+        emulator.get("data/clock-skew").unwrap(),
+        skew.to_string().as_bytes()
+    );
+
+    emulator.set_time(Some(XenTime::from(
+        SystemTime::now() + Duration::from_secs(60),
+    )));
+    let skew = monitor.sample().unwrap();
+    assert!(!skew.ahead);
+    assert!(skew.as_millis() <= -50_000);
+    assert_eq!(
+        emulator.get("data/clock-skew").unwrap(),
+        skew.to_string().as_bytes()
+    );
+    // Not a bogus skew of the timezone offset.
+    emulator.set_time(Some(XenTime::from_filetime(
+        XenTime::from(SystemTime::now()).filetime(),
+        true,
+    )));
+    let e = monitor.sample().unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+}
@@ -0,0 +1,86 @@
+#![cfg(feature = "tokio")]
+
+mod common;
+
+use std::io;
+
+use futures::{FutureExt, StreamExt};
+use xenstore_rs::{AsyncWatch, AsyncXs};
+use xenstore_win::{Access, Permission, XsWindows, tokio::XsTokioWindows};
+
+use common::{OverlappedStore, xs};
+
+#[tokio::test]
+async fn read_write() {
+    let (emulator, xs) = xs();
+    let xs = XsTokioWindows::from(xs);
+
+    xs.write("/data/a", "value").await.unwrap();
+    assert_eq!(&*xs.read("/data/a").await.unwrap(), "value");
+    assert_eq!(xs.directory("/data").await.unwrap(), vec!["a".into()]);
+
+    xs.set_permissions("/data/a", &[Permission::new(0, Access::Read)])
+        .await
+        .unwrap();
+    assert_eq!(
+        emulator.permissions("/data/a").unwrap().default_access(),
+        Access::Read
+    );
+
+    xs.rm("/data/a").await.unwrap();
+    assert!(xs.read("/data/a").await.is_err());
+}
+
+#[tokio::test]
+async fn watch() {
+    let (emulator, xs) = xs();
+    let xs = XsTokioWindows::from(xs);
+
+    let mut watch = xs.watch("/data").await.unwrap();
+    assert_eq!(emulator.watch_count(), 1);
+
+    // Initial event.
+    assert_eq!(&*watch.next().await.unwrap(), "/data");
+    assert!(watch.next().now_or_never().is_none());
+
+    // Woken by a change from another thread.
+    let writer = std::thread::spawn(move || emulator.set("/data/a", "value"));
+    assert_eq!(&*watch.next().await.unwrap(), "/data");
+    writer.join().unwrap();
+}
+
+#[tokio::test]
+async fn bytes() {
+    let (emulator, xs) = xs();
+    let xs = XsTokioWindows::from(xs);
+
+    xs.write_bytes("/bytes", b"\xffvalue").await.unwrap();
+    assert_eq!(emulator.get("/bytes").unwrap(), b"\xffvalue");
+    assert_eq!(xs.read_bytes("/bytes").await.unwrap(), b"\xffvalue");
+    assert_eq!(xs.read_lossy("/bytes").await.unwrap(), "\u{fffd}value");
+
+    let e = xs.write_bytes("/bytes", b"a\0b").await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn overlapped() {
+    let transport = OverlappedStore::default();
+    let xs = XsTokioWindows::from(XsWindows::with_transport(transport.clone()));
+
+    // Store operations don't go through blocking ioctls.
+    xs.write("/data/a", "value").await.unwrap();
+    assert_eq!(&*xs.read("/data/a").await.unwrap(), "value");
+    assert_eq!(xs.directory("/data").await.unwrap(), vec!["a".into()]);
+    xs.write_bytes("/data/b", b"\xff").await.unwrap();
+    assert_eq!(xs.read_bytes("/data/b").await.unwrap(), b"\xff");
+    xs.set_permissions("/data/a", &[Permission::new(0, Access::Read)])
+        .await
+        .unwrap();
+    xs.rm("/data/a").await.unwrap();
+    let mut watch = xs.watch("/data").await.unwrap();
+    assert_eq!(&*watch.next().await.unwrap(), "/data");
+
+    assert!(transport.0.get("/data/a").is_none());
+    assert_eq!(transport.0.get("/data/b").unwrap(), b"\xff");
+}
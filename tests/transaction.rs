@@ -0,0 +1,158 @@
+mod common;
+
+use std::io;
+
+use xenstore_rs::{Xs, XsTransaction, XsTransactionSpan};
+use xenstore_win::{TRANSACTION_ATTEMPTS, XsError};
+
+use common::xs;
+
+#[test]
+fn buffered() {
+    let (emulator, xs) = xs();
+    emulator.set("/data/a", "a");
+    emulator.set("/data/b", "b");
+
+    let tx = xs.transaction().unwrap();
+    tx.write("/data/c/d", "d").unwrap();
+    tx.rm("/data/a").unwrap();
+    tx.write("/data/b", "value").unwrap();
+
+    // Seen by the transaction only.
+    assert_eq!(&*tx.read("/data/b").unwrap(), "value");
+    assert_eq!(&*tx.read("/data/c").unwrap(), "");
+    assert!(tx.read("/data/a").is_err());
+    assert_eq!(tx.directory("/data").unwrap(), ["b".into(), "c".into()]);
+    assert_eq!(emulator.get("/data/b").unwrap(), b"b");
+    assert!(emulator.get("/data/c").is_none());
+
+    tx.rm("/data").unwrap();
+    assert!(tx.directory("/data").is_err());
+    tx.write_bytes("/data/e", b"\xff").unwrap();
+    assert_eq!(tx.read_bytes("/data/e").unwrap(), b"\xff");
+    assert_eq!(tx.directory("/data").unwrap(), ["e".into()]);
+
+    tx.commit().unwrap();
+    assert_eq!(xs.directory("/data").unwrap(), ["e".into()]);
+    assert_eq!(emulator.get("/data/e").unwrap(), b"\xff");
+}
+
+#[test]
+fn dropped() {
+    let (emulator, xs) = xs();
+
+    let tx = xs.transaction().unwrap();
+    tx.write("/data", "value").unwrap();
+    drop(tx);
+
+    assert!(emulator.get("/data").is_none());
+}
+
+#[test]
+fn conflict() {
+    let (emulator, xs) = xs();
+    emulator.set("/counter", "1");
+
+    let tx = xs.transaction().unwrap();
+    assert_eq!(&*tx.read("/counter").unwrap(), "1");
+    tx.write("/counter", "2").unwrap();
+
+    emulator.set("/counter", "5");
+    let e = tx.commit().unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+    assert!(matches!(XsError::from(e), XsError::Again));
+    assert_eq!(emulator.get("/counter").unwrap(), b"5");
+}
+
+#[test]
+fn conflict_created() {
+    let (emulator, xs) = xs();
+    emulator.set("/device", "");
+
+    let tx = xs.transaction().unwrap();
+    assert!(tx.read("/device/0").is_err());
+    assert!(tx.directory("/device").unwrap().is_empty());
+    tx.write("/device/0", "mine").unwrap();
+
+    // Concurrent allocation of the same index.
+    emulator.set("/device/0", "theirs");
+    assert!(tx.commit().is_err());
+    assert_eq!(emulator.get("/device/0").unwrap(), b"theirs");
+}
+
+#[test]
+fn unrelated_change() {
+    let (emulator, xs) = xs();
+    emulator.set("/counter", "1");
+
+    let tx = xs.transaction().unwrap();
+    tx.read("/counter").unwrap();
+    tx.write("/counter", "2").unwrap();
+
+    emulator.set("/other", "value");
+    tx.commit().unwrap();
+    assert_eq!(emulator.get("/counter").unwrap(), b"2");
+}
+
+#[test]
+fn with_transaction() {
+    let (emulator, xs) = xs();
+    emulator.set("/device", "");
+
+    // Allocate the first free index, racing with another writer on the first attempt.
+    let mut attempts = 0;
+    let index = xs
+        .with_transaction(|tx| {
+            attempts += 1;
+            let index = tx.directory("/device")?.len();
+
+            if attempts == 1 {
+                emulator.set("/device/0", "theirs");
+            }
+
+            tx.write(&format!("/device/{index}"), "mine")?;
+            Ok(index)
+        })
+        .unwrap();
+
+    assert_eq!((attempts, index), (2, 1));
+    assert_eq!(emulator.get("/device/0").unwrap(), b"theirs");
+    assert_eq!(emulator.get("/device/1").unwrap(), b"mine");
+}
+
+#[test]
+fn with_transaction_gives_up() {
+    let (emulator, xs) = xs();
+
+    let mut attempts = 0;
+    let e = xs
+        .with_transaction(|tx| {
+            attempts += 1;
+            tx.read("/counter").ok();
+            emulator.set("/counter", attempts.to_string());
+            tx.write("/counter", "mine")
+        })
+        .unwrap_err();
+
+    assert_eq!(attempts, TRANSACTION_ATTEMPTS);
+    assert!(matches!(XsError::from(e), XsError::Again));
+    assert_eq!(
+        emulator.get("/counter").unwrap(),
+        attempts.to_string().as_bytes()
+    );
+}
+
+#[test]
+fn with_transaction_error() {
+    let (emulator, xs) = xs();
+
+    let e = xs
+        .with_transaction(|tx| {
+            tx.write("/data", "value")?;
+            tx.read("/missing")
+        })
+        .unwrap_err();
+
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    assert!(emulator.get("/data").is_none());
+}
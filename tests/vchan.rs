@@ -0,0 +1,267 @@
+use std::{
+    io::{self, Read, Write},
+    sync::mpsc,
+    thread,
+};
+
+use xenstore_rs::Xs;
+use xenstore_win::{
+    XsWindows,
+    emulator::Emulator,
+    vchan::{self, Vchan},
+};
+
+const DOMID: u16 = 5;
+
+fn emulator() -> Emulator {
+    let emulator = Emulator::with_domid(DOMID);
+    emulator.set("domid", DOMID.to_string());
+    emulator
+}
+
+fn path() -> String {
+    vchan::path(DOMID, DOMID, "test")
+}
+
+#[test]
+fn rendezvous() {
+    let emulator = emulator();
+    let xs = XsWindows::with_transport(emulator.clone());
+
+    let server = Vchan::server(&xs, DOMID, &path(), 0, 0).unwrap();
+    assert_eq!(path(), "/local/domain/5/data/vchan/5/test");
+
+    let ring_ref = xs.read(&format!("{}/ring-ref", path())).unwrap();
+    let port = xs.read(&format!("{}/event-channel", path())).unwrap();
+    assert!(ring_ref.parse::<u32>().is_ok());
+    assert!(port.parse::<u32>().is_ok());
+    assert_eq!(
+        emulator
+            .permissions(&format!("{}/ring-ref", path()))
+            .unwrap()
+            .to_string(),
+        "n5,r5"
+    );
+
+    let client = Vchan::client(&xs, DOMID, &path()).unwrap();
+    assert!(server.is_open() && client.is_open());
+
+    // Only one client.
+    assert!(Vchan::client(&xs, DOMID, &path()).is_err());
+
+    drop((client, server));
+    assert!(emulator.get(&path()).is_none());
+    assert_eq!(emulator.grant_count(), 0);
+    assert_eq!(emulator.request_count(), 0);
+    assert_eq!(emulator.port_count(), 0);
+}
+
+#[test]
+fn missing() {
+    let xs = XsWindows::with_transport(emulator());
+
+    assert_eq!(
+        Vchan::client(&xs, DOMID, &path()).err().unwrap().kind(),
+        io::ErrorKind::NotFound
+    );
+    // Rings are up to 1 MiB.
+    assert_eq!(
+        Vchan::server(&xs, DOMID, &path(), 1 << 21, 0)
+            .err()
+            .unwrap()
+            .kind(),
+        io::ErrorKind::InvalidInput
+    );
+}
+
+#[test]
+fn read_write() {
+    let emulator = emulator();
+    let xs = XsWindows::with_transport(emulator.clone());
+
+    let mut server = Vchan::server(&xs, DOMID, &path(), 0, 0).unwrap();
+    // Buffered until the client connects.
+    server.write_all(b"hello").unwrap();
+
+    let mut client = Vchan::client(&xs, DOMID, &path()).unwrap();
+    assert_eq!(client.data_ready().unwrap(), 5);
+    let mut buf = [0; 5];
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    client.write_all(b"world").unwrap();
+    let mut buf = [0; 16];
+    assert_eq!(server.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"world");
+
+    // Server rings are 1024 bytes to read, 2048 to write.
+    assert_eq!(server.buffer_space().unwrap(), 2048);
+    assert_eq!(client.buffer_space().unwrap(), 1024);
+    assert_eq!(server.write(&[1; 4096]).unwrap(), 2048);
+    assert_eq!(server.buffer_space().unwrap(), 0);
+}
+
+#[test]
+fn close() {
+    let emulator = emulator();
+    let xs = XsWindows::with_transport(emulator.clone());
+
+    let mut server = Vchan::server(&xs, DOMID, &path(), 0, 0).unwrap();
+    let mut client = Vchan::client(&xs, DOMID, &path()).unwrap();
+
+    client.write_all(b"bye").unwrap();
+    drop(client);
+    assert!(!server.is_open());
+
+    // Data written before closing is still read.
+    let mut buf = Vec::new();
+    server.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, b"bye");
+    assert_eq!(
+        server.write(b"hello").unwrap_err().kind(),
+        io::ErrorKind::BrokenPipe
+    );
+
+    // Clients can't reconnect.
+    assert_eq!(
+        Vchan::client(&xs, DOMID, &path()).err().unwrap().kind(),
+        io::ErrorKind::ConnectionRefused
+    );
+}
+
+/// Send `len` bytes from a client thread, through rings of `read_min`/`write_min` bytes.
+fn transfer(read_min: usize, write_min: usize, len: usize) {
+    let emulator = emulator();
+    let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+    let (ready_tx, ready_rx) = mpsc::channel();
+
+    let client = thread::spawn({
+        let emulator = emulator.clone();
+        let data = data.clone();
+
+        move || {
+            let xs = XsWindows::with_transport(emulator);
+            ready_rx.recv().unwrap();
+            let mut client = Vchan::client(&xs, DOMID, &path()).unwrap();
+
+            client.write_all(&data).unwrap();
+            // Echoed back.
+            let mut echo = vec![0; data.len()];
+            client.read_exact(&mut echo).unwrap();
+            assert!(echo == data);
+        }
+    });
+
+    let xs = XsWindows::with_transport(emulator.clone());
+    let mut server = Vchan::server(&xs, DOMID, &path(), read_min, write_min).unwrap();
+    ready_tx.send(()).unwrap();
+
+    let mut buf = vec![0; 1000];
+    let mut received = Vec::new();
+    while received.len() < len {
+        let n = server.read(&mut buf).unwrap();
+        received.extend_from_slice(&buf[..n]);
+    }
+    assert!(received == data);
+    server.write_all(&received).unwrap();
+
+    client.join().unwrap();
+    // The client is gone.
+    assert_eq!(server.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn transfer_small() {
+    transfer(0, 0, 100_000);
+}
+
+#[test]
+fn transfer_large() {
+    // Rings in separate pages, on both sides.
+    transfer(16 << 10, 64 << 10, 1 << 20);
+}
+
+#[test]
+fn transfer_mixed() {
+    transfer(2048, 8192, 100_000);
+    transfer(1 << 20, 1024, 100_000);
+}
+
+#[cfg(feature = "smol")]
+#[test]
+fn async_read_write() {
+    use futures::{AsyncReadExt, AsyncWriteExt, FutureExt};
+
+    let xs = XsWindows::with_transport(emulator());
+    let mut server = Vchan::server(&xs, DOMID, &path(), 0, 0).unwrap();
+    let mut client = Vchan::client(&xs, DOMID, &path()).unwrap();
+    let data: Vec<u8> = (0..50_000).map(|i| i as u8).collect();
+
+    smol::block_on(async {
+        let mut buf = [0; 16];
+        assert!(
+            AsyncReadExt::read(&mut server, &mut buf)
+                .now_or_never()
+                .is_none()
+        );
+
+        // Larger than the rings, so both sides wait for each other.
+        let write = async {
+            AsyncWriteExt::write_all(&mut client, &data).await.unwrap();
+            client.close().await.unwrap();
+            drop(client);
+        };
+        let read = async {
+            let mut received = Vec::new();
+            AsyncReadExt::read_to_end(&mut server, &mut received)
+                .await
+                .unwrap();
+            received
+        };
+
+        let ((), received) = futures::join!(write, read);
+        assert!(received == data);
+    });
+}
+
+#[cfg(feature = "smol")]
+#[test]
+fn async_concurrent() {
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    let xs = XsWindows::with_transport(emulator());
+    let server = Vchan::server(&xs, DOMID, &path(), 0, 0).unwrap();
+    let mut client = Vchan::client(&xs, DOMID, &path()).unwrap();
+    let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+
+    // The server reads and writes from separate tasks, waiting on the same event channel.
+    let (mut reader, mut writer) = server.split();
+    let executor = smol::LocalExecutor::new();
+
+    let write = executor.spawn(async {
+        writer.write_all(&data).await.unwrap();
+    });
+    let read = executor.spawn(async {
+        let mut received = vec![0; data.len()];
+        reader.read_exact(&mut received).await.unwrap();
+        received
+    });
+    let echo = executor.spawn(async {
+        let mut buf = [0; 1000];
+        let mut echoed = 0;
+        while echoed < data.len() {
+            let n = AsyncReadExt::read(&mut client, &mut buf).await.unwrap();
+            AsyncWriteExt::write_all(&mut client, &buf[..n])
+                .await
+                .unwrap();
+            echoed += n;
+        }
+    });
+
+    let received = smol::block_on(executor.run(async {
+        write.await;
+        echo.await;
+        read.await
+    }));
+    assert!(received == data);
+}
@@ -0,0 +1,199 @@
+mod common;
+
+use std::io;
+
+use xenstore_rs::Xs;
+use xenstore_win::{
+    XsWindows,
+    emulator::Emulator,
+    walk::{OnError, Order, Walk},
+};
+
+fn xs() -> (Emulator, XsWindows<Emulator>) {
+    let (emulator, xs) = common::xs();
+    emulator.set("/local/domain/1/name", "one");
+    emulator.set("/local/domain/1/data/key", "value");
+    emulator.set("/local/domain/2/name", "two");
+
+    (emulator, xs)
+}
+
+fn paths(
+    walk: impl Iterator<Item = Result<(Box<str>, Box<str>), impl std::fmt::Debug>>,
+) -> Vec<Box<str>> {
+    walk.map(|entry| entry.unwrap().0).collect()
+}
+
+/// Xenstore where a node is removed right after its parent is listed.
+struct Racy<'a> {
+    xs: &'a XsWindows<Emulator>,
+    emulator: &'a Emulator,
+    removed: &'a str,
+}
+
+impl Xs for Racy<'_> {
+    fn directory(&self, path: &str) -> io::Result<Vec<Box<str>>> {
+        let entries = self.xs.directory(path)?;
+        self.emulator.remove(self.removed);
+        Ok(entries)
+    }
+
+    fn read(&self, path: &str) -> io::Result<Box<str>> {
+        self.xs.read(path)
+    }
+
+    fn write(&self, path: &str, data: &str) -> io::Result<()> {
+        self.xs.write(path, data)
+    }
+
+    fn rm(&self, path: &str) -> io::Result<()> {
+        self.xs.rm(path)
+    }
+}
+
+#[test]
+fn depth_first() {
+    let (_, xs) = xs();
+
+    let walk: Vec<_> = xs.walk("/local/domain").map(Result::unwrap).collect();
+    assert_eq!(
+        walk,
+        [
+            ("/local/domain".into(), "".into()),
+            ("/local/domain/1".into(), "".into()),
+            ("/local/domain/1/data".into(), "".into()),
+            ("/local/domain/1/data/key".into(), "value".into()),
+            ("/local/domain/1/name".into(), "one".into()),
+            ("/local/domain/2".into(), "".into()),
+            ("/local/domain/2/name".into(), "two".into()),
+        ]
+    );
+}
+
+#[test]
+fn breadth_first() {
+    let (_, xs) = xs();
+
+    assert_eq!(
+        paths(xs.walk("/local/domain").with_order(Order::BreadthFirst)),
+        [
+            "/local/domain".into(),
+            "/local/domain/1".into(),
+            "/local/domain/2".into(),
+            "/local/domain/1/data".into(),
+            "/local/domain/1/name".into(),
+            "/local/domain/2/name".into(),
+            "/local/domain/1/data/key".into(),
+        ]
+    );
+}
+
+#[test]
+fn root() {
+    let (_, xs) = xs();
+
+    assert_eq!(
+        paths(xs.walk("/").with_max_depth(2)),
+        ["/".into(), "/local".into(), "/local/domain".into()]
+    );
+}
+
+#[test]
+fn max_depth() {
+    let (_, xs) = xs();
+
+    assert_eq!(
+        paths(xs.walk("/local/domain/1").with_max_depth(0)),
+        ["/local/domain/1".into()]
+    );
+    assert_eq!(
+        paths(xs.walk("/local/domain/1").with_max_depth(1)),
+        [
+            "/local/domain/1".into(),
+            "/local/domain/1/data".into(),
+            "/local/domain/1/name".into(),
+        ]
+    );
+}
+
+#[test]
+fn glob() {
+    let (_, xs) = xs();
+
+    assert_eq!(
+        paths(xs.walk("/").with_glob("/local/domain/*/name")),
+        ["/local/domain/1/name".into(), "/local/domain/2/name".into()]
+    );
+    assert_eq!(
+        paths(xs.walk("/").with_glob("/local/domain/?")),
+        ["/local/domain/1".into(), "/local/domain/2".into()]
+    );
+    assert_eq!(
+        paths(xs.walk("/").with_glob("/local/**/key")),
+        ["/local/domain/1/data/key".into()]
+    );
+    assert!(paths(xs.walk("/").with_glob("/local/*/key")).is_empty());
+}
+
+#[test]
+fn missing() {
+    let (_, xs) = xs();
+
+    let errors: Vec<_> = xs.walk("/missing").collect();
+    assert_eq!(errors.len(), 1);
+    let e = errors.into_iter().next().unwrap().unwrap_err();
+    assert_eq!(e.path(), "/missing");
+    assert_eq!(e.error().kind(), io::ErrorKind::NotFound);
+    assert_eq!(io::Error::from(e).kind(), io::ErrorKind::NotFound);
+}
+
+#[test]
+fn removed_while_walking() {
+    let (emulator, xs) = xs();
+    let racy = Racy {
+        xs: &xs,
+        emulator: &emulator,
+        removed: "/local/domain/1/data",
+    };
+
+    let walk: Vec<_> = Walk::new(&racy, "/local/domain/1").collect();
+    assert_eq!(walk.len(), 3);
+    assert_eq!(walk[0].as_ref().unwrap().0.as_ref(), "/local/domain/1");
+    let e = walk[1].as_ref().unwrap_err();
+    assert_eq!(e.path(), "/local/domain/1/data");
+    assert_eq!(e.error().kind(), io::ErrorKind::NotFound);
+    assert_eq!(walk[2].as_ref().unwrap().0.as_ref(), "/local/domain/1/name");
+
+    emulator.set("/local/domain/1/data/key", "value");
+    assert_eq!(
+        paths(Walk::new(&racy, "/local/domain/1").with_errors(OnError::Skip)),
+        ["/local/domain/1".into(), "/local/domain/1/name".into()]
+    );
+}
+
+#[cfg(feature = "smol")]
+#[test]
+fn stream() {
+    use futures::TryStreamExt;
+    use xenstore_win::smol::XsSmolWindows;
+
+    let (_, xs) = xs();
+    let xs = XsSmolWindows::from(xs);
+
+    let walk: Vec<_> = smol::block_on(
+        xs.walk("/local/domain")
+            .with_order(Order::BreadthFirst)
+            .with_max_depth(1)
+            .stream()
+            .try_collect(),
+    )
+    .unwrap();
+    assert_eq!(
+        walk,
+        [
+            ("/local/domain".into(), "".into()),
+            ("/local/domain/1".into(), "".into()),
+            ("/local/domain/2".into(), "".into()),
+        ]
+    );
+}
@@ -0,0 +1,159 @@
+mod common;
+
+use std::{thread, time::Duration};
+
+use xenstore_rs::Xs;
+use xenstore_win::Watch;
+
+use common::xs;
+
+#[test]
+fn recv() {
+    let (emulator, xs) = xs();
+    let watch = xs.watch("/data").unwrap();
+
+    // Initial event.
+    assert_eq!(&*watch.recv().unwrap(), "/data");
+    assert!(watch.try_recv().unwrap().is_none());
+
+    xs.write("/data/key", "value").unwrap();
+    assert_eq!(watch.try_recv().unwrap().as_deref(), Some("/data"));
+
+    emulator.set("/other", "value");
+    assert!(watch.try_recv().unwrap().is_none());
+}
+
+#[test]
+fn descendants() {
+    let (emulator, xs) = xs();
+    xs.write("/data/key", "value").unwrap();
+    let watch = xs.watch("/data/key").unwrap();
+    watch.recv().unwrap();
+
+    // Writing an ancestor doesn't fire watches below it, removing it does.
+    xs.write("/data", "value").unwrap();
+    emulator.set("/", "");
+    assert!(watch.try_recv().unwrap().is_none());
+
+    xs.rm("/data").unwrap();
+    assert_eq!(watch.try_recv().unwrap().as_deref(), Some("/data/key"));
+}
+
+#[test]
+fn recv_timeout() {
+    let (emulator, xs) = xs();
+    let watch = xs.watch("/data").unwrap();
+    watch.recv().unwrap();
+
+    assert!(
+        watch
+            .recv_timeout(Duration::from_millis(20))
+            .unwrap()
+            .is_none()
+    );
+
+    let writer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(20));
+        emulator.set("/data", "value");
+    });
+
+    assert_eq!(
+        watch
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .as_deref(),
+        Some("/data")
+    );
+    writer.join().unwrap();
+}
+
+#[test]
+fn iterator() {
+    let (emulator, xs) = xs();
+    xs.write("/data", "").unwrap();
+    let watch = xs.watch("/data").unwrap();
+
+    let writer = thread::spawn(move || {
+        for i in 0..3 {
+            thread::sleep(Duration::from_millis(10));
+            emulator.set("/data", i.to_string());
+        }
+    });
+
+    // Events may be coalesced, but the last write is always seen.
+    for path in watch {
+        assert_eq!(&*path, "/data");
+        if &*xs.read("/data").unwrap() == "2" {
+            break;
+        }
+    }
+    writer.join().unwrap();
+}
+
+#[test]
+fn recv_any() {
+    let (emulator, xs) = xs();
+    let a = xs.watch("/a").unwrap();
+    let b = xs.watch("/b").unwrap();
+    a.recv().unwrap();
+    b.recv().unwrap();
+
+    let timeout = Some(Duration::from_millis(20));
+    assert!(Watch::recv_any(&[&a, &b], timeout).unwrap().is_none());
+
+    emulator.set("/b/key", "value");
+    let (index, path) = Watch::recv_any(&[&a, &b], None).unwrap().unwrap();
+    assert_eq!((index, &*path), (1, "/b"));
+    assert!(Watch::recv_any(&[&a, &b], timeout).unwrap().is_none());
+}
+
+#[test]
+fn drop_removes_watch() {
+    let (emulator, xs) = xs();
+
+    let watch = xs.watch("/data").unwrap();
+    assert_eq!(emulator.watch_count(), 1);
+
+    drop(watch);
+    assert_eq!(emulator.watch_count(), 0);
+}
+
+#[test]
+fn coalesced() {
+    let (emulator, xs) = xs();
+    let watch = xs.watch("/data").unwrap();
+    watch.recv().unwrap();
+
+    for i in 0..3 {
+        emulator.set("/data", i.to_string());
+    }
+    watch.recv().unwrap();
+    assert!(watch.try_recv().unwrap().is_none());
+}
+
+#[test]
+fn rapid_firing() {
+    const WRITES: u32 = 1000;
+
+    let (emulator, xs) = xs();
+    let watch = xs.watch("/data").unwrap();
+
+    let writer = thread::spawn(move || {
+        for i in 1..=WRITES {
+            emulator.set("/data", i.to_string());
+        }
+    });
+
+    // Events are coalesced, but none is lost: the last write is always seen.
+    loop {
+        watch
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .expect("watch event lost");
+
+        if xs.read("/data").is_ok_and(|v| *v == *WRITES.to_string()) {
+            break;
+        }
+    }
+    writer.join().unwrap();
+}
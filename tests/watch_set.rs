@@ -0,0 +1,181 @@
+mod common;
+
+use std::time::Duration;
+
+use xenstore_rs::Xs;
+use xenstore_win::{WATCH_SET_CAPACITY, WatchSet, XsError};
+
+use common::xs;
+
+const TIMEOUT: Option<Duration> = Some(Duration::from_millis(20));
+
+#[test]
+fn tokens() {
+    let (emulator, xs) = xs();
+    let mut set = WatchSet::new(&xs).unwrap();
+
+    set.add(1, "/a").unwrap();
+    set.add(2, "/b").unwrap();
+    assert_eq!(set.len(), 2);
+
+    // Initial events.
+    let mut initial = [set.recv().unwrap(), set.recv().unwrap()];
+    initial.sort();
+    assert_eq!(initial, [(1, "/a".into()), (2, "/b".into())]);
+    assert!(set.recv_timeout(TIMEOUT).unwrap().is_none());
+
+    xs.write("/b/key", "value").unwrap();
+    assert_eq!(set.recv().unwrap(), (2, "/b".into()));
+
+    emulator.set("/a", "value");
+    assert_eq!(set.recv().unwrap(), (1, "/a".into()));
+    assert!(set.recv_timeout(TIMEOUT).unwrap().is_none());
+}
+
+#[test]
+fn remove() {
+    let (emulator, xs) = xs();
+    let mut set = WatchSet::new(&xs).unwrap();
+
+    set.add("a", "/a").unwrap();
+    set.add("b", "/b").unwrap();
+    assert_eq!(emulator.watch_count(), 2);
+
+    assert!(set.remove(&"a"));
+    assert!(!set.remove(&"a"));
+    assert_eq!(emulator.watch_count(), 1);
+
+    // Only the initial event of the remaining watch is left.
+    assert_eq!(set.recv().unwrap(), ("b", "/b".into()));
+    assert!(set.recv_timeout(TIMEOUT).unwrap().is_none());
+
+    set.remove(&"b");
+    assert!(set.is_empty());
+    assert!(set.recv().is_err());
+}
+
+#[test]
+fn capacity() {
+    let (emulator, xs) = xs();
+    let mut set = WatchSet::new(&xs).unwrap();
+
+    for i in 0..WATCH_SET_CAPACITY {
+        set.add(i, &format!("/{i}")).unwrap();
+    }
+
+    let e = set.add(WATCH_SET_CAPACITY, "/full").unwrap_err();
+    assert!(matches!(XsError::from(e), XsError::QuotaExceeded));
+    let e = set.add_diffed(WATCH_SET_CAPACITY, "/full").unwrap_err();
+    assert!(matches!(XsError::from(e), XsError::QuotaExceeded));
+    assert_eq!(emulator.watch_count(), WATCH_SET_CAPACITY);
+
+    set.remove(&0);
+    set.add(WATCH_SET_CAPACITY, "/full").unwrap();
+}
+
+#[test]
+fn diffed() {
+    let (emulator, xs) = xs();
+    let mut set = WatchSet::new(&xs).unwrap();
+    emulator.set("/config/a", "1");
+    emulator.set("/config/b", "2");
+
+    set.add_diffed((), "/config").unwrap();
+    assert_eq!(set.recv().unwrap(), ((), "/config".into()));
+
+    // Modification.
+    xs.write("/config/a", "3").unwrap();
+    assert_eq!(set.recv().unwrap(), ((), "/config/a".into()));
+
+    // Creation, with implicit parents.
+    xs.write("/config/c/d", "4").unwrap();
+    assert_eq!(set.recv().unwrap(), ((), "/config/c".into()));
+    assert_eq!(set.recv().unwrap(), ((), "/config/c/d".into()));
+
+    // Removal of a subtree.
+    xs.rm("/config/c").unwrap();
+    assert_eq!(set.recv().unwrap(), ((), "/config/c".into()));
+    assert_eq!(set.recv().unwrap(), ((), "/config/c/d".into()));
+
+    // Writing the same value doesn't change anything.
+    xs.write("/config/b", "2").unwrap();
+    assert!(set.recv_timeout(TIMEOUT).unwrap().is_none());
+}
+
+#[test]
+fn diffed_coalesced() {
+    let (emulator, xs) = xs();
+    let mut set = WatchSet::new(&xs).unwrap();
+
+    set.add_diffed(0, "/config").unwrap();
+    set.recv().unwrap();
+
+    // Both changes are seen, even if the watch fired once.
+    xs.write("/config/a", "1").unwrap();
+    xs.write("/config/b", "2").unwrap();
+    assert_eq!(set.recv().unwrap(), (0, "/config".into()));
+    assert_eq!(set.recv().unwrap(), (0, "/config/a".into()));
+    assert_eq!(set.recv().unwrap(), (0, "/config/b".into()));
+    assert!(set.recv_timeout(TIMEOUT).unwrap().is_none());
+
+    emulator.remove("/config");
+    let mut removed: Vec<_> = (0..3).map(|_| set.recv().unwrap().1).collect();
+    removed.sort();
+    assert_eq!(
+        removed,
+        ["/config".into(), "/config/a".into(), "/config/b".into()] as [Box<str>; 3]
+    );
+}
+
+#[cfg(feature = "smol")]
+#[test]
+fn stream() {
+    use futures::{FutureExt, StreamExt};
+
+    let (emulator, xs) = xs();
+    let mut set = WatchSet::new(&xs).unwrap();
+
+    emulator.set("/b", "");
+
+    smol::block_on(async {
+        set.add("a", "/a").unwrap();
+        set.add_diffed("b", "/b").unwrap();
+
+        let mut initial = [set.next().await.unwrap(), set.next().await.unwrap()];
+        initial.sort();
+        assert_eq!(initial, [("a", "/a".into()), ("b", "/b".into())]);
+        assert!(set.next().now_or_never().is_none());
+
+        emulator.set("/b/key", "value");
+        assert_eq!(set.next().await.unwrap(), ("b", "/b/key".into()));
+
+        emulator.set("/a/key", "value");
+        assert_eq!(set.next().await.unwrap(), ("a", "/a".into()));
+    });
+}
+
+#[cfg(feature = "smol")]
+#[test]
+fn stream_overlapped() {
+    use futures::{FutureExt, StreamExt};
+    use xenstore_win::{XsWindows, emulator::Emulator};
+
+    use common::OverlappedStore;
+
+    let transport = OverlappedStore(Emulator::new(), true);
+    let xs = XsWindows::with_transport(transport.clone());
+    let mut set = WatchSet::new(&xs).unwrap();
+    transport.0.set("/config/a", "1");
+
+    // Diffing doesn't issue blocking ioctls from the stream.
+    smol::block_on(async {
+        set.add_diffed((), "/config").unwrap();
+        assert_eq!(set.next().await.unwrap(), ((), "/config".into()));
+
+        transport.0.set("/config/b", "2");
+        transport.0.remove("/config/a");
+        assert_eq!(set.next().await.unwrap(), ((), "/config/a".into()));
+        assert_eq!(set.next().await.unwrap(), ((), "/config/b".into()));
+        assert!(set.next().now_or_never().is_none());
+    });
+}